@@ -0,0 +1,303 @@
+use crate::AtomicF32;
+use arc_swap::{ArcSwap, Guard};
+use rand::Rng;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+
+// -------------------------------------
+// SOURCE BANK
+// -------------------------------------
+/// Sources a synth can hold, the main one (slot 0) included.
+pub const MAX_SOURCES: usize = 8;
+/// Longest crossfade between two sources, in milliseconds.
+pub const MAX_CROSSFADE_MS: f32 = 600_000.0;
+
+/// A buffer swapped out of a slot, kept for the grains started on it.
+#[derive(Clone)]
+pub struct RetiredBuffer {
+    /// The slot's generation while this buffer was current
+    pub generation: u32,
+    pub samples: Arc<[f32]>,
+}
+
+/// The buffers of one slot, swapped as a whole so a reader always sees a
+/// current buffer, retired buffers and generation that belong together.
+pub struct SlotBuffers {
+    pub current: Arc<[f32]>,
+    /// What the slot held before its swaps, oldest first, until
+    /// `release_retired` finds no grain reading them
+    pub retired: Vec<RetiredBuffer>,
+    /// Bumped by every swap
+    pub generation: u32,
+}
+
+impl SlotBuffers {
+    fn empty() -> Self {
+        Self {
+            current: Arc::from(Vec::new()),
+            retired: Vec::new(),
+            generation: 0,
+        }
+    }
+}
+
+/// Loaded sources of a synth and how grains pick among them. Slot 0 is the
+/// synth's main source; the other slots are loaded into the bank. Every
+/// grain reads one source, drawn by weight, or between two sources by the
+/// crossfade position while crossfading, so the mix moves through the
+/// grain population rather than per sample.
+///
+/// Buffers are immutable snapshots swapped atomically: readers, the audio
+/// thread included, borrow them without locking or copying. Swapping a slot
+/// adds its previous buffer to the retired ones and bumps the slot's
+/// generation. Grains carry the generation they started on, so the ones
+/// already playing finish on their old buffer, however many swaps followed,
+/// while new grains read the new one.
+pub struct SourceBank {
+    slots: [ArcSwap<SlotBuffers>; MAX_SOURCES],
+    // Published by the audio thread: some grain still reads the retired
+    // buffer of the slot
+    retired_in_use: [AtomicBool; MAX_SOURCES],
+    weights: [AtomicF32; MAX_SOURCES],
+    crossfade_enabled: AtomicBool,
+    crossfade_from: AtomicU8,
+    crossfade_to: AtomicU8,
+    // Where the crossfade is (0 = all `from`, 1 = all `to`), where it is
+    // heading, and how long a full sweep takes
+    crossfade_position: AtomicF32,
+    crossfade_target: AtomicF32,
+    crossfade_time_ms: AtomicF32,
+}
+
+impl SourceBank {
+    pub fn new() -> Self {
+        Self {
+            slots: std::array::from_fn(|_| ArcSwap::from_pointee(SlotBuffers::empty())),
+            retired_in_use: std::array::from_fn(|_| AtomicBool::new(false)),
+            weights: std::array::from_fn(|_| AtomicF32::new(1.0)),
+            crossfade_enabled: AtomicBool::new(false),
+            crossfade_from: AtomicU8::new(0),
+            crossfade_to: AtomicU8::new(1),
+            crossfade_position: AtomicF32::new(0.0),
+            crossfade_target: AtomicF32::new(0.0),
+            crossfade_time_ms: AtomicF32::new(0.0),
+        }
+    }
+
+    /// The buffers of `slot` right now; hold the guard no longer than a
+    /// block.
+    pub fn slot(&self, slot: usize) -> Guard<Arc<SlotBuffers>> {
+        self.slots[slot].load()
+    }
+
+    /// The current buffer of `slot`, kept as long as the caller needs it.
+    pub fn current(&self, slot: usize) -> Arc<[f32]> {
+        Arc::clone(&self.slots[slot].load().current)
+    }
+
+    pub fn generation(&self, slot: usize) -> u32 {
+        self.slots[slot].load().generation
+    }
+
+    pub fn filesize(&self, slot: usize) -> usize {
+        self.slots[slot].load().current.len()
+    }
+
+    /// Swaps `samples` into `slot`, an empty buffer clearing it, and
+    /// returns the new generation. The replaced buffer joins the retired
+    /// ones until `release_retired`.
+    pub fn set_source(&self, slot: usize, samples: Vec<f32>) -> Result<u32, String> {
+        if slot >= MAX_SOURCES {
+            return Err(format!("Source slot {} out of range", slot));
+        }
+        let samples: Arc<[f32]> = Arc::from(samples);
+        let previous = self.slots[slot].rcu(|buffers| {
+            let mut retired = buffers.retired.clone();
+            if !buffers.current.is_empty() {
+                retired.push(RetiredBuffer {
+                    generation: buffers.generation,
+                    samples: Arc::clone(&buffers.current),
+                });
+            }
+            SlotBuffers {
+                current: Arc::clone(&samples),
+                retired,
+                generation: buffers.generation.wrapping_add(1),
+            }
+        });
+        let generation = previous.generation.wrapping_add(1);
+        crate::loader::retire(previous);
+        Ok(generation)
+    }
+
+    /// Set by the audio thread every block.
+    pub fn set_retired_in_use(&self, slot: usize, in_use: bool) {
+        self.retired_in_use[slot].store(in_use, Ordering::Relaxed);
+    }
+
+    pub fn is_retired_in_use(&self, slot: usize) -> bool {
+        self.retired_in_use[slot].load(Ordering::Relaxed)
+    }
+
+    /// Frees the buffers retired by the swaps up to the one that made
+    /// `generation`; buffers retired by later swaps stay. The snapshots
+    /// swapped out here are dropped off the audio thread, see
+    /// `loader::retire`.
+    pub fn release_retired(&self, slot: usize, generation: u32) {
+        // Retired before `generation`, counting across wrap-around
+        let released = |buffer: &RetiredBuffer| (generation.wrapping_sub(buffer.generation) as i32) > 0;
+        if !self.slots[slot].load().retired.iter().any(released) {
+            return;
+        }
+        let previous = self.slots[slot].rcu(|buffers| SlotBuffers {
+            current: Arc::clone(&buffers.current),
+            retired: buffers.retired.iter().filter(|b| !released(b)).cloned().collect(),
+            generation: buffers.generation,
+        });
+        crate::loader::retire(previous);
+    }
+
+    /// Relative chance (0..1) of `slot` being picked outside a crossfade.
+    pub fn set_weight(&self, slot: usize, weight: f32) -> Result<(), String> {
+        match self.weights.get(slot) {
+            Some(w) => {
+                w.store(weight.clamp(0.0, 1.0));
+                Ok(())
+            }
+            None => Err(format!("Source slot {} out of range", slot)),
+        }
+    }
+
+    pub fn set_crossfade_enabled(&self, enabled: bool) {
+        self.crossfade_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Crossfade from slot `from` to slot `to`, moving to `position` (0..1)
+    /// over `time_ms` for a full sweep; 0 jumps there.
+    pub fn set_crossfade(
+        &self,
+        from: usize,
+        to: usize,
+        position: f32,
+        time_ms: f32,
+    ) -> Result<(), String> {
+        if from >= MAX_SOURCES || to >= MAX_SOURCES {
+            return Err("Source slot out of range".to_string());
+        }
+        self.crossfade_from.store(from as u8, Ordering::Relaxed);
+        self.crossfade_to.store(to as u8, Ordering::Relaxed);
+        self.crossfade_time_ms.store(time_ms.clamp(0.0, MAX_CROSSFADE_MS));
+        self.crossfade_target.store(position.clamp(0.0, 1.0));
+        Ok(())
+    }
+
+    pub fn crossfade_position(&self) -> f32 {
+        self.crossfade_position.load()
+    }
+
+    /// Moves the crossfade towards its target; called by the scheduler with
+    /// the seconds since its last call.
+    pub fn advance_crossfade(&self, elapsed: f32) {
+        let (position, target) = (self.crossfade_position.load(), self.crossfade_target.load());
+        let time = self.crossfade_time_ms.load() / 1000.0;
+        let step = if time > 0.0 { elapsed / time } else { 1.0 };
+        let position = if target > position {
+            (position + step).min(target)
+        } else {
+            (position - step).max(target)
+        };
+        self.crossfade_position.store(position);
+    }
+
+    /// Source for the next grain: a loaded slot, or 0 when none is.
+    pub fn pick(&self) -> usize {
+        let mut rng = rand::thread_rng();
+        let loaded = |slot: usize| self.filesize(slot) > 0;
+        if self.crossfade_enabled.load(Ordering::Relaxed) {
+            let from = self.crossfade_from.load(Ordering::Relaxed) as usize;
+            let to = self.crossfade_to.load(Ordering::Relaxed) as usize;
+            let (picked, other) = if rng.gen_range(0.0..1.0) < self.crossfade_position() {
+                (to, from)
+            } else {
+                (from, to)
+            };
+            return [picked, other].into_iter().find(|&s| loaded(s)).unwrap_or(0);
+        }
+
+        let total: f32 = (0..MAX_SOURCES)
+            .filter(|&slot| loaded(slot))
+            .map(|slot| self.weights[slot].load())
+            .sum();
+        if total <= 0.0 {
+            return 0;
+        }
+        let mut draw = rng.gen_range(0.0..total);
+        let mut picked = 0;
+        for slot in (0..MAX_SOURCES).filter(|&slot| loaded(slot)) {
+            let weight = self.weights[slot].load();
+            if weight > 0.0 {
+                picked = slot;
+                if draw < weight {
+                    break;
+                }
+                draw -= weight;
+            }
+        }
+        picked
+    }
+}
+
+impl Default for SourceBank {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The bank as one audio block sees it: the current and retired buffers of
+/// every slot, with their generations, from one snapshot per slot.
+pub struct SourceView<'a> {
+    current: [&'a [f32]; MAX_SOURCES],
+    retired: [&'a [RetiredBuffer]; MAX_SOURCES],
+    generations: [u32; MAX_SOURCES],
+}
+
+impl<'a> SourceView<'a> {
+    pub fn new(
+        current: [&'a [f32]; MAX_SOURCES],
+        retired: [&'a [RetiredBuffer]; MAX_SOURCES],
+        generations: [u32; MAX_SOURCES],
+    ) -> Self {
+        Self {
+            current,
+            retired,
+            generations,
+        }
+    }
+
+    /// A single source in slot 0, for rendering outside a synth.
+    pub fn single(source: &'a [f32]) -> Self {
+        let mut current: [&[f32]; MAX_SOURCES] = [&[]; MAX_SOURCES];
+        current[0] = source;
+        Self::new(current, [&[]; MAX_SOURCES], [0; MAX_SOURCES])
+    }
+
+    /// Buffer of `slot` for a grain started at `generation`: the current
+    /// one, a retired one, or nothing once that buffer is gone.
+    pub fn source(&self, slot: usize, generation: u32) -> &'a [f32] {
+        match self.generations.get(slot) {
+            Some(&current) if current == generation => self.current[slot],
+            Some(_) => self.retired[slot]
+                .iter()
+                .find(|buffer| buffer.generation == generation)
+                .map_or(&[], |buffer| &buffer.samples[..]),
+            None => &[],
+        }
+    }
+
+    /// Whether a grain of `slot` at `generation` reads the retired buffer.
+    pub fn is_retired(&self, slot: usize, generation: u32) -> bool {
+        self.generations.get(slot).is_some_and(|&current| current != generation)
+    }
+}
@@ -0,0 +1,302 @@
+use crate::psola::{detect_periods, MAX_FREQUENCY, MIN_FREQUENCY};
+use crate::AtomicF32;
+use rand::Rng;
+use realfft::RealFftPlanner;
+use std::{
+    f32::consts::PI,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+// -------------------------------------
+// DESCRIPTORS
+// -------------------------------------
+/// Length of an analysed segment and the spacing of segment starts.
+pub const SEGMENT_SIZE: usize = 2048;
+pub const SEGMENT_HOP: usize = SEGMENT_SIZE / 2;
+pub const DESCRIPTOR_COUNT: usize = 4;
+/// Most candidates a grain can be drawn from.
+pub const MAX_CANDIDATES: usize = 64;
+// Segments quieter than this (-80 dB) are left out of the corpus
+const SILENCE_RMS: f32 = 1e-4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Descriptor {
+    /// RMS level, compared in dB
+    Loudness,
+    /// Spectral centroid, compared in octaves
+    Brightness,
+    /// Fundamental from YIN, compared in octaves; unvoiced segments count as
+    /// the lowest pitch
+    Pitch,
+    /// Spectral flatness, 0 for a pure tone and 1 for white noise
+    Noisiness,
+}
+
+impl Descriptor {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(Descriptor::Loudness),
+            1 => Some(Descriptor::Brightness),
+            2 => Some(Descriptor::Pitch),
+            3 => Some(Descriptor::Noisiness),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            Descriptor::Loudness => 0,
+            Descriptor::Brightness => 1,
+            Descriptor::Pitch => 2,
+            Descriptor::Noisiness => 3,
+        }
+    }
+}
+
+/// Raw descriptors of one segment of the source.
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    /// First sample of the segment
+    pub start: usize,
+    pub rms: f32,
+    /// In Hz
+    pub centroid: f32,
+    /// In Hz, 0 when unvoiced
+    pub pitch: f32,
+    pub noisiness: f32,
+}
+
+impl Segment {
+    // The perceptual scale each descriptor is compared on
+    fn scaled(&self) -> [f32; DESCRIPTOR_COUNT] {
+        [
+            20.0 * self.rms.max(SILENCE_RMS).log10(),
+            self.centroid.max(1.0).log2(),
+            if self.pitch > 0.0 {
+                self.pitch.log2()
+            } else {
+                MIN_FREQUENCY.log2()
+            },
+            self.noisiness,
+        ]
+    }
+}
+
+/// What grains are selected for: a 0..1 value per descriptor, relative to
+/// the range found in the corpus, and how much each one counts (0 ignores
+/// it).
+#[derive(Clone, Copy, Debug)]
+pub struct DescriptorTarget {
+    pub values: [f32; DESCRIPTOR_COUNT],
+    pub weights: [f32; DESCRIPTOR_COUNT],
+}
+
+/// The source cut into overlapping segments with their descriptors, for
+/// picking grains by sound rather than by position.
+pub struct Corpus {
+    segments: Vec<Segment>,
+    // Descriptors rescaled to 0..1 over the corpus
+    normalized: Vec<[f32; DESCRIPTOR_COUNT]>,
+}
+
+impl Corpus {
+    pub fn empty() -> Self {
+        Self {
+            segments: Vec::new(),
+            normalized: Vec::new(),
+        }
+    }
+
+    /// Cuts `source` into segments and describes them; `None` once `cancel`
+    /// is raised.
+    pub fn analyze(source: &[f32], sample_rate: u32, cancel: &AtomicBool) -> Option<Self> {
+        if source.is_empty() {
+            return Some(Self::empty());
+        }
+        let min_lag = (sample_rate as f32 / MAX_FREQUENCY) as usize;
+        let max_lag = (sample_rate as f32 / MIN_FREQUENCY) as usize;
+        let periods = detect_periods(source, min_lag, max_lag, SEGMENT_HOP, cancel)?;
+
+        let forward = RealFftPlanner::<f32>::new().plan_fft_forward(SEGMENT_SIZE);
+        let window: Vec<f32> = (0..SEGMENT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / SEGMENT_SIZE as f32).cos())
+            .collect();
+        let mut input = forward.make_input_vec();
+        let mut spectrum = forward.make_output_vec();
+        let bin_hz = sample_rate as f32 / SEGMENT_SIZE as f32;
+
+        let mut segments = Vec::new();
+        for (index, period) in periods.iter().enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+            let start = index * SEGMENT_HOP;
+            let end = (start + SEGMENT_SIZE).min(source.len());
+            let samples = &source[start..end];
+            let rms = (samples.iter().map(|s| s * s).sum::<f32>() / SEGMENT_SIZE as f32).sqrt();
+            if rms < SILENCE_RMS {
+                continue;
+            }
+            for (i, x) in input.iter_mut().enumerate() {
+                *x = samples.get(i).copied().unwrap_or(0.0) * window[i];
+            }
+            let _ = forward.process(&mut input, &mut spectrum);
+
+            // Centroid on magnitudes, flatness (geometric over arithmetic
+            // mean) on power, both without the DC bin
+            let (mut weighted, mut total, mut log_power) = (0.0f32, 0.0f32, 0.0f32);
+            let mut power_sum = 0.0f32;
+            for (k, bin) in spectrum.iter().enumerate().skip(1) {
+                let magnitude = bin.norm();
+                let power = magnitude * magnitude + 1e-12;
+                weighted += k as f32 * bin_hz * magnitude;
+                total += magnitude;
+                log_power += power.ln();
+                power_sum += power;
+            }
+            let bins = (spectrum.len() - 1) as f32;
+            segments.push(Segment {
+                start,
+                rms,
+                centroid: if total > 0.0 { weighted / total } else { 0.0 },
+                pitch: period.map_or(0.0, |p| sample_rate as f32 / p),
+                noisiness: ((log_power / bins).exp() / (power_sum / bins)).clamp(0.0, 1.0),
+            });
+        }
+
+        let scaled: Vec<[f32; DESCRIPTOR_COUNT]> = segments.iter().map(Segment::scaled).collect();
+        let mut low = [f32::MAX; DESCRIPTOR_COUNT];
+        let mut high = [f32::MIN; DESCRIPTOR_COUNT];
+        for values in &scaled {
+            for d in 0..DESCRIPTOR_COUNT {
+                low[d] = low[d].min(values[d]);
+                high[d] = high[d].max(values[d]);
+            }
+        }
+        let normalized = scaled
+            .iter()
+            .map(|values| {
+                std::array::from_fn(|d| {
+                    let range = high[d] - low[d];
+                    if range > 1e-6 {
+                        (values[d] - low[d]) / range
+                    } else {
+                        0.5
+                    }
+                })
+            })
+            .collect();
+        Some(Self {
+            segments,
+            normalized,
+        })
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Start of a segment drawn at random from the `candidates` closest to
+    /// `target` (weighted Euclidean distance); any segment when every weight
+    /// is 0. Only segments starting in `region` (source samples) count;
+    /// `None` when there are none.
+    pub fn select(
+        &self,
+        target: &DescriptorTarget,
+        candidates: usize,
+        region: Range<usize>,
+    ) -> Option<usize> {
+        // Segments are in source order
+        let first = self.segments.partition_point(|s| s.start < region.start);
+        let last = self.segments.partition_point(|s| s.start < region.end);
+        if first >= last {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        if target.weights.iter().all(|&w| w <= 0.0) {
+            return Some(self.segments[rng.gen_range(first..last)].start);
+        }
+
+        // Keep the closest segments in a small sorted array
+        let candidates = candidates.clamp(1, MAX_CANDIDATES);
+        let mut best = [(f32::MAX, 0usize); MAX_CANDIDATES];
+        let mut found = 0;
+        for (index, values) in self.normalized.iter().enumerate().take(last).skip(first) {
+            let distance: f32 = (0..DESCRIPTOR_COUNT)
+                .map(|d| target.weights[d] * (values[d] - target.values[d]).powi(2))
+                .sum();
+            if found < candidates {
+                found += 1;
+            } else if distance >= best[candidates - 1].0 {
+                continue;
+            }
+            let mut slot = found - 1;
+            while slot > 0 && best[slot - 1].0 > distance {
+                best[slot] = best[slot - 1];
+                slot -= 1;
+            }
+            best[slot] = (distance, index);
+        }
+        let (_, index) = best[rng.gen_range(0..found)];
+        Some(self.segments[index].start)
+    }
+}
+
+/// User settings of descriptor-driven selection. While enabled, grain
+/// starts come from the corpus instead of the grain start.
+pub struct DescriptorSettings {
+    enabled: AtomicBool,
+    values: [AtomicF32; DESCRIPTOR_COUNT],
+    weights: [AtomicF32; DESCRIPTOR_COUNT],
+    candidates: AtomicUsize,
+}
+
+impl DescriptorSettings {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            values: std::array::from_fn(|_| AtomicF32::new(0.5)),
+            weights: std::array::from_fn(|_| AtomicF32::new(0.0)),
+            candidates: AtomicUsize::new(8),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// `value` and `weight` are both 0..1.
+    pub fn set_target(&self, descriptor: Descriptor, value: f32, weight: f32) {
+        let d = descriptor.index() as usize;
+        self.values[d].store(value.clamp(0.0, 1.0));
+        self.weights[d].store(weight.clamp(0.0, 1.0));
+    }
+
+    pub fn target(&self) -> DescriptorTarget {
+        DescriptorTarget {
+            values: std::array::from_fn(|d| self.values[d].load()),
+            weights: std::array::from_fn(|d| self.weights[d].load()),
+        }
+    }
+
+    pub fn candidates(&self) -> usize {
+        self.candidates.load(Ordering::Relaxed)
+    }
+
+    /// 1 always plays the closest segment; more candidates add variety.
+    pub fn set_candidates(&self, candidates: usize) {
+        self.candidates
+            .store(candidates.clamp(1, MAX_CANDIDATES), Ordering::Relaxed);
+    }
+}
+
+impl Default for DescriptorSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,498 @@
+use crate::filter::{Biquad, FilterMode, Svf};
+use crate::smoothing::{SmoothedParam, SmoothingMode};
+use crate::AtomicF32;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// -------------------------------------
+// MASTER EFFECTS
+// -------------------------------------
+pub const FX_COUNT: usize = 4;
+/// Longest delay time; the delay lines are allocated for it up front.
+pub const MAX_DELAY_MS: f32 = 2000.0;
+// The filter and EQ coefficients follow their smoothed settings every this
+// many samples
+const COEFF_INTERVAL: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Effect {
+    Delay,
+    Reverb,
+    Filter,
+    Eq,
+}
+
+impl Effect {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(Effect::Delay),
+            1 => Some(Effect::Reverb),
+            2 => Some(Effect::Filter),
+            3 => Some(Effect::Eq),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            Effect::Delay => 0,
+            Effect::Reverb => 1,
+            Effect::Filter => 2,
+            Effect::Eq => 3,
+        }
+    }
+}
+
+/// Bypass and wet/dry of one effect.
+struct Slot {
+    bypass: AtomicBool,
+    mix: AtomicF32,
+}
+
+impl Slot {
+    fn new(mix: f32) -> Self {
+        Self {
+            // Everything starts bypassed so the output is unchanged
+            bypass: AtomicBool::new(true),
+            mix: AtomicF32::new(mix),
+        }
+    }
+}
+
+/// Effect settings shared between the control side and the audio callback.
+pub struct MasterEffects {
+    order: [AtomicU8; FX_COUNT],
+    slots: [Slot; FX_COUNT],
+    delay_time_ms: AtomicF32,
+    delay_feedback: AtomicF32,
+    delay_ping_pong: AtomicBool,
+    reverb_room_size: AtomicF32,
+    reverb_damping: AtomicF32,
+    filter_mode: AtomicU8,
+    filter_cutoff: AtomicF32,
+    filter_resonance: AtomicF32,
+    // Low shelf, mid peak, high shelf
+    eq_freqs: [AtomicF32; 3],
+    eq_gains_db: [AtomicF32; 3],
+    eq_mid_q: AtomicF32,
+}
+
+impl MasterEffects {
+    pub fn new() -> Self {
+        Self {
+            order: std::array::from_fn(|i| AtomicU8::new(i as u8)),
+            slots: [Slot::new(0.3), Slot::new(0.3), Slot::new(1.0), Slot::new(1.0)],
+            delay_time_ms: AtomicF32::new(375.0),
+            delay_feedback: AtomicF32::new(0.4),
+            delay_ping_pong: AtomicBool::new(false),
+            reverb_room_size: AtomicF32::new(0.7),
+            reverb_damping: AtomicF32::new(0.5),
+            filter_mode: AtomicU8::new(FilterMode::LowPass.index()),
+            filter_cutoff: AtomicF32::new(8000.0),
+            filter_resonance: AtomicF32::new(0.707),
+            eq_freqs: [
+                AtomicF32::new(200.0),
+                AtomicF32::new(1000.0),
+                AtomicF32::new(5000.0),
+            ],
+            eq_gains_db: std::array::from_fn(|_| AtomicF32::new(0.0)),
+            eq_mid_q: AtomicF32::new(1.0),
+        }
+    }
+
+    pub fn order(&self) -> [Effect; FX_COUNT] {
+        std::array::from_fn(|i| {
+            Effect::from_index(self.order[i].load(Ordering::Relaxed)).unwrap_or(Effect::Delay)
+        })
+    }
+
+    /// `order` must name every effect exactly once.
+    pub fn set_order(&self, order: [Effect; FX_COUNT]) -> Result<(), String> {
+        for effect in [Effect::Delay, Effect::Reverb, Effect::Filter, Effect::Eq] {
+            if order.iter().filter(|&&e| e == effect).count() != 1 {
+                return Err(format!("Effect order must contain {:?} once", effect));
+            }
+        }
+        for (slot, effect) in self.order.iter().zip(order.iter()) {
+            slot.store(effect.index(), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn set_bypass(&self, effect: Effect, bypass: bool) {
+        self.slots[effect.index() as usize].bypass.store(bypass, Ordering::Relaxed);
+    }
+
+    pub fn is_bypassed(&self, effect: Effect) -> bool {
+        self.slots[effect.index() as usize].bypass.load(Ordering::Relaxed)
+    }
+
+    /// 0 is fully dry, 1 fully wet.
+    pub fn set_mix(&self, effect: Effect, mix: f32) {
+        self.slots[effect.index() as usize].mix.store(mix.clamp(0.0, 1.0));
+    }
+
+    pub fn mix(&self, effect: Effect) -> f32 {
+        self.slots[effect.index() as usize].mix.load()
+    }
+
+    pub fn set_delay(&self, time_ms: f32, feedback: f32, ping_pong: bool) {
+        self.delay_time_ms.store(time_ms.clamp(1.0, MAX_DELAY_MS));
+        self.delay_feedback.store(feedback.clamp(0.0, 0.95));
+        self.delay_ping_pong.store(ping_pong, Ordering::Relaxed);
+    }
+
+    /// Both 0..1.
+    pub fn set_reverb(&self, room_size: f32, damping: f32) {
+        self.reverb_room_size.store(room_size.clamp(0.0, 1.0));
+        self.reverb_damping.store(damping.clamp(0.0, 1.0));
+    }
+
+    pub fn set_filter(&self, mode: FilterMode, cutoff: f32, resonance: f32) {
+        self.filter_mode.store(mode.index(), Ordering::Relaxed);
+        self.filter_cutoff.store(cutoff.max(10.0));
+        self.filter_resonance.store(resonance.clamp(0.1, 40.0));
+    }
+
+    /// `band` 0 is the low shelf, 1 the mid peak, 2 the high shelf.
+    pub fn set_eq_band(&self, band: usize, freq: f32, gain_db: f32) -> Result<(), String> {
+        if band >= 3 {
+            return Err(format!("Invalid EQ band: {}", band));
+        }
+        self.eq_freqs[band].store(freq.max(10.0));
+        self.eq_gains_db[band].store(gain_db.clamp(-24.0, 24.0));
+        Ok(())
+    }
+
+    pub fn set_eq_mid_q(&self, q: f32) {
+        self.eq_mid_q.store(q.clamp(0.1, 20.0));
+    }
+}
+
+impl Default for MasterEffects {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// -------------------------------------
+// EFFECT PROCESSORS
+// -------------------------------------
+struct StereoDelay {
+    lines: [Vec<f32>; 2],
+    write: usize,
+    // Smoothed delay in samples so time changes glide instead of clicking
+    delay: f32,
+    target: f32,
+    feedback: f32,
+    ping_pong: bool,
+    // The first time setting jumps straight to the target
+    primed: bool,
+}
+
+impl StereoDelay {
+    fn new(sample_rate: u32) -> Self {
+        let len = (MAX_DELAY_MS / 1000.0 * sample_rate as f32) as usize + 2;
+        Self {
+            lines: [vec![0.0; len], vec![0.0; len]],
+            write: 0,
+            delay: 1.0,
+            target: 1.0,
+            feedback: 0.0,
+            ping_pong: false,
+            primed: false,
+        }
+    }
+
+    fn read(line: &[f32], write: usize, delay: f32) -> f32 {
+        let len = line.len();
+        let pos = write as f32 + len as f32 - delay;
+        let i = pos.floor() as usize;
+        let frac = pos - pos.floor();
+        let a = line[i % len];
+        let b = line[(i + 1) % len];
+        a + (b - a) * frac
+    }
+
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        self.delay += 0.001 * (self.target - self.delay);
+        let len = self.lines[0].len();
+        let max_delay = (len - 2) as f32;
+        let delay = self.delay.clamp(1.0, max_delay);
+        let out_l = Self::read(&self.lines[0], self.write, delay);
+        let out_r = Self::read(&self.lines[1], self.write, delay);
+        let (fb_l, fb_r) = if self.ping_pong { (out_r, out_l) } else { (out_l, out_r) };
+        // Ping-pong feeds the mono sum into the left line only
+        let (in_l, in_r) = if self.ping_pong {
+            (0.5 * (left + right), 0.0)
+        } else {
+            (left, right)
+        };
+        self.lines[0][self.write] = in_l + fb_l * self.feedback;
+        self.lines[1][self.write] = in_r + fb_r * self.feedback;
+        self.write = (self.write + 1) % len;
+        (out_l, out_r)
+    }
+}
+
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(1)],
+            index: 0,
+            store: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.store = output * (1.0 - damp) + self.store * damp;
+        self.buffer[self.index] = input + self.store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(1)],
+            index: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// Freeverb: eight parallel damped combs into four allpasses per side, the
+/// right side slightly detuned for width.
+struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+    feedback: f32,
+    damp: f32,
+}
+
+impl Reverb {
+    const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+    const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+    const STEREO_SPREAD: usize = 23;
+    const INPUT_GAIN: f32 = 0.015;
+    const WET_GAIN: f32 = 3.0;
+
+    fn new(sample_rate: u32) -> Self {
+        // Tunings are for 44.1 kHz
+        let scale = |len: usize| (len as f32 * sample_rate as f32 / 44100.0) as usize;
+        let side = |spread: usize| {
+            (
+                Self::COMBS.iter().map(|&l| Comb::new(scale(l + spread))).collect(),
+                Self::ALLPASSES.iter().map(|&l| Allpass::new(scale(l + spread))).collect(),
+            )
+        };
+        let (combs_l, allpasses_l) = side(0);
+        let (combs_r, allpasses_r) = side(Self::STEREO_SPREAD);
+        Self {
+            combs: [combs_l, combs_r],
+            allpasses: [allpasses_l, allpasses_r],
+            feedback: 0.0,
+            damp: 0.0,
+        }
+    }
+
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let input = (left + right) * Self::INPUT_GAIN;
+        let mut out = [0.0; 2];
+        for (side, out) in out.iter_mut().enumerate() {
+            for comb in self.combs[side].iter_mut() {
+                *out += comb.process(input, self.feedback, self.damp);
+            }
+            for allpass in self.allpasses[side].iter_mut() {
+                *out = allpass.process(*out);
+            }
+        }
+        (out[0] * Self::WET_GAIN, out[1] * Self::WET_GAIN)
+    }
+}
+
+/// Processing state of the chain. Built (and allocated) when the stream
+/// starts and owned by the audio callback from then on.
+pub struct EffectChain {
+    sample_rate: u32,
+    order: [Effect; FX_COUNT],
+    // Wet share of every effect: its mix, or 0 while bypassed. Ticked per
+    // sample so bypass and mix crossfade; an effect runs until it reaches 0
+    wet: [SmoothedParam; FX_COUNT],
+    delay: StereoDelay,
+    reverb: Reverb,
+    filter: [Svf; 2],
+    eq: [[Biquad; 3]; 2],
+    filter_mode: FilterMode,
+    // Cutoff is smoothed in octaves so sweeps move evenly through the range
+    filter_cutoff: SmoothedParam,
+    filter_resonance: SmoothedParam,
+    eq_freqs: [f32; 3],
+    eq_mid_q: f32,
+    eq_gains_db: [SmoothedParam; 3],
+    // Samples since the coefficients were last updated, and whether the
+    // next sample must update them regardless
+    elapsed: usize,
+    refresh: bool,
+    // The first settings jump straight to their targets
+    primed: bool,
+}
+
+impl EffectChain {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            order: [Effect::Delay, Effect::Reverb, Effect::Filter, Effect::Eq],
+            wet: std::array::from_fn(|_| SmoothedParam::new(0.0)),
+            delay: StereoDelay::new(sample_rate),
+            reverb: Reverb::new(sample_rate),
+            filter: [Svf::new(FilterMode::LowPass, 8000.0, 0.707, sample_rate); 2],
+            eq: [[Biquad::default(); 3]; 2],
+            filter_mode: FilterMode::LowPass,
+            filter_cutoff: SmoothedParam::new(8000.0f32.log2()),
+            filter_resonance: SmoothedParam::new(0.707),
+            eq_freqs: [200.0, 1000.0, 5000.0],
+            eq_mid_q: 1.0,
+            eq_gains_db: std::array::from_fn(|_| SmoothedParam::new(0.0)),
+            elapsed: 0,
+            refresh: true,
+            primed: false,
+        }
+    }
+
+    /// Reads the settings; call once per block before `process`. Bypass,
+    /// mix, filter cutoff and resonance and the EQ gains glide to their new
+    /// values with the given smoothing.
+    pub fn prepare(&mut self, fx: &MasterEffects, mode: SmoothingMode, time_ms: f32) {
+        let sr = self.sample_rate;
+        self.order = fx.order();
+        for (i, effect) in [Effect::Delay, Effect::Reverb, Effect::Filter, Effect::Eq]
+            .into_iter()
+            .enumerate()
+        {
+            let wet = if fx.is_bypassed(effect) { 0.0 } else { fx.mix(effect) };
+            self.wet[i].configure(mode, time_ms, sr);
+            if self.primed {
+                self.wet[i].set_target(wet);
+            } else {
+                self.wet[i].reset(wet);
+            }
+        }
+
+        self.delay.target = fx.delay_time_ms.load() / 1000.0 * sr as f32;
+        if !self.delay.primed {
+            self.delay.delay = self.delay.target;
+            self.delay.primed = true;
+        }
+        self.delay.feedback = fx.delay_feedback.load();
+        self.delay.ping_pong = fx.delay_ping_pong.load(Ordering::Relaxed);
+
+        self.reverb.feedback = fx.reverb_room_size.load() * 0.28 + 0.7;
+        self.reverb.damp = fx.reverb_damping.load() * 0.4;
+
+        self.filter_mode = FilterMode::from_index(fx.filter_mode.load(Ordering::Relaxed))
+            .unwrap_or(FilterMode::LowPass);
+        self.eq_freqs = std::array::from_fn(|band| fx.eq_freqs[band].load());
+        self.eq_mid_q = fx.eq_mid_q.load();
+        let targets = [
+            (&mut self.filter_cutoff, fx.filter_cutoff.load().log2()),
+            (&mut self.filter_resonance, fx.filter_resonance.load()),
+        ];
+        let eq_targets = self
+            .eq_gains_db
+            .iter_mut()
+            .zip(fx.eq_gains_db.iter().map(|gain| gain.load()));
+        for (smoother, target) in targets.into_iter().chain(eq_targets) {
+            smoother.configure(mode, time_ms, sr);
+            if self.primed {
+                smoother.set_target(target);
+            } else {
+                smoother.reset(target);
+            }
+        }
+        self.primed = true;
+        // Mode, frequencies and Q are not smoothed: pick them up now
+        self.refresh = true;
+    }
+
+    fn smoothers(&mut self) -> [&mut SmoothedParam; 5] {
+        let [low, mid, high] = &mut self.eq_gains_db;
+        [&mut self.filter_cutoff, &mut self.filter_resonance, low, mid, high]
+    }
+
+    /// Moves the smoothed settings on by the samples processed since the
+    /// last update and recomputes the coefficients, if anything changed.
+    fn update_coefficients(&mut self) {
+        let elapsed = std::mem::take(&mut self.elapsed);
+        let mut smoothing = false;
+        for smoother in self.smoothers() {
+            smoothing |= smoother.is_smoothing();
+            smoother.advance(elapsed);
+        }
+        if !smoothing && !self.refresh {
+            return;
+        }
+        self.refresh = false;
+
+        let sr = self.sample_rate;
+        let cutoff = self.filter_cutoff.value().exp2();
+        let resonance = self.filter_resonance.value();
+        for filter in self.filter.iter_mut() {
+            filter.set(self.filter_mode, cutoff, resonance, sr);
+        }
+        let gains = self.eq_gains_db.each_ref().map(SmoothedParam::value);
+        for bands in self.eq.iter_mut() {
+            bands[0].set_shelf(false, self.eq_freqs[0], gains[0], sr);
+            bands[1].set_peaking(self.eq_freqs[1], self.eq_mid_q, gains[1], sr);
+            bands[2].set_shelf(true, self.eq_freqs[2], gains[2], sr);
+        }
+    }
+
+    pub fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        if self.refresh || self.elapsed >= COEFF_INTERVAL {
+            self.update_coefficients();
+        }
+        self.elapsed += 1;
+        let (mut left, mut right) = (left, right);
+        for effect in self.order {
+            let i = effect.index() as usize;
+            let mix = self.wet[i].tick();
+            if mix == 0.0 {
+                continue;
+            }
+            let (wet_l, wet_r) = match effect {
+                Effect::Delay => self.delay.process(left, right),
+                Effect::Reverb => self.reverb.process(left, right),
+                Effect::Filter => (self.filter[0].process(left), self.filter[1].process(right)),
+                Effect::Eq => {
+                    let [eq_l, eq_r] = &mut self.eq;
+                    (
+                        eq_l.iter_mut().fold(left, |x, band| band.process(x)),
+                        eq_r.iter_mut().fold(right, |x, band| band.process(x)),
+                    )
+                }
+            };
+            left += (wet_l - left) * mix;
+            right += (wet_r - right) * mix;
+        }
+        (left, right)
+    }
+}
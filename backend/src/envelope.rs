@@ -0,0 +1,245 @@
+use crate::AtomicF32;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+// -------------------------------------
+// CLOUD ENVELOPE
+// -------------------------------------
+pub const MAX_ENVELOPE_SEGMENTS: usize = 8;
+
+/// A multi-segment envelope: linear ramps to `levels[i]` over `times_ms[i]`,
+/// holding at the end of segment `sustain` while the gate is on, then a
+/// release to zero. Without a sustain point the envelope runs through all
+/// segments once and then releases by itself.
+#[derive(Clone, Copy)]
+pub struct EnvelopeShape {
+    pub levels: [f32; MAX_ENVELOPE_SEGMENTS],
+    pub times_ms: [f32; MAX_ENVELOPE_SEGMENTS],
+    pub count: usize,
+    pub sustain: Option<usize>,
+    pub release_ms: f32,
+}
+
+impl EnvelopeShape {
+    pub fn adsr(attack_ms: f32, decay_ms: f32, sustain: f32, release_ms: f32) -> Self {
+        let mut shape = Self {
+            levels: [0.0; MAX_ENVELOPE_SEGMENTS],
+            times_ms: [0.0; MAX_ENVELOPE_SEGMENTS],
+            count: 2,
+            sustain: Some(1),
+            release_ms: release_ms.max(0.0),
+        };
+        shape.levels[0] = 1.0;
+        shape.times_ms[0] = attack_ms.max(0.0);
+        shape.levels[1] = sustain.clamp(0.0, 1.0);
+        shape.times_ms[1] = decay_ms.max(0.0);
+        shape
+    }
+}
+
+impl Default for EnvelopeShape {
+    fn default() -> Self {
+        Self::adsr(10.0, 100.0, 1.0, 300.0)
+    }
+}
+
+/// Envelope settings and gate shared with the audio callback. Everything is
+/// atomic so the callback can read it once per block without locking.
+pub struct CloudEnvelope {
+    enabled: AtomicBool,
+    gate: AtomicBool,
+    // Bumped on every gate-on so the callback can retrigger
+    triggers: AtomicU32,
+    levels: [AtomicF32; MAX_ENVELOPE_SEGMENTS],
+    times_ms: [AtomicF32; MAX_ENVELOPE_SEGMENTS],
+    count: AtomicUsize,
+    // `usize::MAX` means no sustain point
+    sustain: AtomicUsize,
+    release_ms: AtomicF32,
+}
+
+impl CloudEnvelope {
+    pub fn new() -> Self {
+        let envelope = Self {
+            enabled: AtomicBool::new(false),
+            gate: AtomicBool::new(false),
+            triggers: AtomicU32::new(0),
+            levels: std::array::from_fn(|_| AtomicF32::new(0.0)),
+            times_ms: std::array::from_fn(|_| AtomicF32::new(0.0)),
+            count: AtomicUsize::new(0),
+            sustain: AtomicUsize::new(usize::MAX),
+            release_ms: AtomicF32::new(0.0),
+        };
+        envelope.set_shape(&EnvelopeShape::default());
+        envelope
+    }
+
+    pub fn set_shape(&self, shape: &EnvelopeShape) {
+        let count = shape.count.min(MAX_ENVELOPE_SEGMENTS);
+        for i in 0..count {
+            self.levels[i].store(shape.levels[i].max(0.0));
+            self.times_ms[i].store(shape.times_ms[i].max(0.0));
+        }
+        self.sustain.store(
+            shape.sustain.filter(|&s| s < count).unwrap_or(usize::MAX),
+            Ordering::Relaxed,
+        );
+        self.release_ms.store(shape.release_ms.max(0.0));
+        self.count.store(count, Ordering::Release);
+    }
+
+    pub fn shape(&self) -> EnvelopeShape {
+        let count = self.count.load(Ordering::Acquire);
+        let mut shape = EnvelopeShape {
+            levels: [0.0; MAX_ENVELOPE_SEGMENTS],
+            times_ms: [0.0; MAX_ENVELOPE_SEGMENTS],
+            count,
+            sustain: None,
+            release_ms: self.release_ms.load(),
+        };
+        for i in 0..count {
+            shape.levels[i] = self.levels[i].load();
+            shape.times_ms[i] = self.times_ms[i].load();
+        }
+        let sustain = self.sustain.load(Ordering::Relaxed);
+        shape.sustain = (sustain < count).then_some(sustain);
+        shape
+    }
+
+    /// Enabled without a gate the envelope stays idle, so the cloud is
+    /// silent until `gate_on`.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Starts (or restarts) the envelope. Enables it if it was off.
+    pub fn gate_on(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+        self.gate.store(true, Ordering::Relaxed);
+        self.triggers.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn gate_off(&self) {
+        self.gate.store(false, Ordering::Relaxed);
+    }
+
+    pub fn gate(&self) -> bool {
+        self.gate.load(Ordering::Relaxed)
+    }
+
+    pub fn triggers(&self) -> u32 {
+        self.triggers.load(Ordering::Acquire)
+    }
+}
+
+impl Default for CloudEnvelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Idle,
+    Segment(usize),
+    Sustain,
+    Release,
+}
+
+/// Running state of an envelope, ticked once per sample.
+#[derive(Clone, Copy)]
+pub struct EnvelopeRunner {
+    stage: Stage,
+    level: f32,
+    from_level: f32,
+    position: f32,
+    length: f32,
+}
+
+impl EnvelopeRunner {
+    pub fn new() -> Self {
+        Self {
+            stage: Stage::Idle,
+            level: 0.0,
+            from_level: 0.0,
+            position: 0.0,
+            length: 0.0,
+        }
+    }
+
+    /// Restarts from the current level so a retrigger doesn't click.
+    pub fn trigger(&mut self, shape: &EnvelopeShape, sample_rate: u32) {
+        self.enter_segment(0, shape, sample_rate);
+    }
+
+    pub fn release(&mut self, shape: &EnvelopeShape, sample_rate: u32) {
+        if self.stage == Stage::Idle || self.stage == Stage::Release {
+            return;
+        }
+        self.stage = Stage::Release;
+        self.start_ramp(shape.release_ms, sample_rate);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.stage == Stage::Idle
+    }
+
+    pub fn is_releasing(&self) -> bool {
+        self.stage == Stage::Release
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    pub fn tick(&mut self, shape: &EnvelopeShape, sample_rate: u32) -> f32 {
+        let target = match self.stage {
+            Stage::Idle | Stage::Sustain => return self.level,
+            Stage::Segment(i) => shape.levels[i],
+            Stage::Release => 0.0,
+        };
+        self.position += 1.0;
+        let t = if self.length > 0.0 { (self.position / self.length).min(1.0) } else { 1.0 };
+        self.level = self.from_level + (target - self.from_level) * t;
+        if t >= 1.0 {
+            self.level = target;
+            match self.stage {
+                Stage::Segment(i) if Some(i) == shape.sustain => self.stage = Stage::Sustain,
+                Stage::Segment(i) if i + 1 < shape.count => {
+                    self.enter_segment(i + 1, shape, sample_rate)
+                }
+                Stage::Segment(_) => {
+                    self.stage = Stage::Release;
+                    self.start_ramp(shape.release_ms, sample_rate);
+                }
+                _ => self.stage = Stage::Idle,
+            }
+        }
+        self.level
+    }
+
+    fn enter_segment(&mut self, index: usize, shape: &EnvelopeShape, sample_rate: u32) {
+        if index >= shape.count {
+            self.stage = Stage::Release;
+            self.start_ramp(shape.release_ms, sample_rate);
+            return;
+        }
+        self.stage = Stage::Segment(index);
+        self.start_ramp(shape.times_ms[index], sample_rate);
+    }
+
+    fn start_ramp(&mut self, time_ms: f32, sample_rate: u32) {
+        self.from_level = self.level;
+        self.position = 0.0;
+        self.length = time_ms / 1000.0 * sample_rate as f32;
+    }
+}
+
+impl Default for EnvelopeRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,395 @@
+use crate::AtomicF32;
+use rand::Rng;
+use std::{
+    f32::consts::PI,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+// -------------------------------------
+// FILTERS
+// -------------------------------------
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+impl FilterMode {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(FilterMode::LowPass),
+            1 => Some(FilterMode::HighPass),
+            2 => Some(FilterMode::BandPass),
+            3 => Some(FilterMode::Notch),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            FilterMode::LowPass => 0,
+            FilterMode::HighPass => 1,
+            FilterMode::BandPass => 2,
+            FilterMode::Notch => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterTopology {
+    Biquad,
+    Svf,
+}
+
+impl FilterTopology {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(FilterTopology::Biquad),
+            1 => Some(FilterTopology::Svf),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            FilterTopology::Biquad => 0,
+            FilterTopology::Svf => 1,
+        }
+    }
+}
+
+/// Keeps the cutoff in the range the filters stay stable in.
+fn clamp_cutoff(cutoff: f32, sample_rate: u32) -> f32 {
+    cutoff.clamp(10.0, 0.49 * sample_rate as f32)
+}
+
+/// RBJ cookbook biquad, transposed direct form II.
+#[derive(Clone, Copy, Default)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    pub fn new(mode: FilterMode, cutoff: f32, q: f32, sample_rate: u32) -> Self {
+        let mut filter = Self::default();
+        filter.set(mode, cutoff, q, sample_rate);
+        filter
+    }
+
+    /// Changes the coefficients and keeps the state.
+    pub fn set(&mut self, mode: FilterMode, cutoff: f32, q: f32, sample_rate: u32) {
+        let w0 = 2.0 * PI * clamp_cutoff(cutoff, sample_rate) / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q.max(0.1));
+        let (b0, b1, b2) = match mode {
+            FilterMode::LowPass => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0),
+            FilterMode::HighPass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0),
+            FilterMode::BandPass => (alpha, 0.0, -alpha),
+            FilterMode::Notch => (1.0, -2.0 * cos, 1.0),
+        };
+        self.set_normalized(b0, b1, b2, 1.0 + alpha, -2.0 * cos, 1.0 - alpha);
+    }
+
+    /// Peaking EQ band: `gain_db` around `freq` with bandwidth `q`.
+    pub fn set_peaking(&mut self, freq: f32, q: f32, gain_db: f32, sample_rate: u32) {
+        let a = 10.0_f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * clamp_cutoff(freq, sample_rate) / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q.max(0.1));
+        self.set_normalized(
+            1.0 + alpha * a,
+            -2.0 * cos,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos,
+            1.0 - alpha / a,
+        );
+    }
+
+    /// Shelf with slope 1; `high` selects a high shelf instead of a low one.
+    pub fn set_shelf(&mut self, high: bool, freq: f32, gain_db: f32, sample_rate: u32) {
+        let a = 10.0_f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * clamp_cutoff(freq, sample_rate) / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / 2.0 * 2.0_f32.sqrt();
+        let beta = 2.0 * a.sqrt() * alpha;
+        let sign = if high { -1.0 } else { 1.0 };
+        self.set_normalized(
+            a * ((a + 1.0) - sign * (a - 1.0) * cos + beta),
+            sign * 2.0 * a * ((a - 1.0) - sign * (a + 1.0) * cos),
+            a * ((a + 1.0) - sign * (a - 1.0) * cos - beta),
+            (a + 1.0) + sign * (a - 1.0) * cos + beta,
+            -sign * 2.0 * ((a - 1.0) + sign * (a + 1.0) * cos),
+            (a + 1.0) + sign * (a - 1.0) * cos - beta,
+        );
+    }
+
+    /// Raw coefficients; everything is divided by `a0`.
+    pub fn set_normalized(&mut self, b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) {
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = a1 / a0;
+        self.a2 = a2 / a0;
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Trapezoidal state-variable filter (Zavalishin / Simper). Stays well
+/// behaved when the cutoff moves, which suits modulated or swept filters.
+#[derive(Clone, Copy)]
+pub struct Svf {
+    mode: FilterMode,
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    pub fn new(mode: FilterMode, cutoff: f32, q: f32, sample_rate: u32) -> Self {
+        let mut filter = Self {
+            mode,
+            k: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        filter.set(mode, cutoff, q, sample_rate);
+        filter
+    }
+
+    pub fn set(&mut self, mode: FilterMode, cutoff: f32, q: f32, sample_rate: u32) {
+        let g = (PI * clamp_cutoff(cutoff, sample_rate) / sample_rate as f32).tan();
+        self.mode = mode;
+        self.k = 1.0 / q.max(0.1);
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let v3 = x - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+        match self.mode {
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => x - self.k * v1 - v2,
+            FilterMode::BandPass => v1,
+            FilterMode::Notch => x - self.k * v1,
+        }
+    }
+}
+
+// -------------------------------------
+// PER-GRAIN FILTER
+// -------------------------------------
+/// A filter with its own state, carried inside a `Grain`. Coefficients are
+/// computed by the scheduler so the audio thread only runs it.
+#[derive(Clone, Copy)]
+pub enum GrainFilter {
+    Biquad(Biquad),
+    Svf(Svf),
+}
+
+impl GrainFilter {
+    pub fn new(
+        topology: FilterTopology,
+        mode: FilterMode,
+        cutoff: f32,
+        q: f32,
+        sample_rate: u32,
+    ) -> Self {
+        match topology {
+            FilterTopology::Biquad => GrainFilter::Biquad(Biquad::new(mode, cutoff, q, sample_rate)),
+            FilterTopology::Svf => GrainFilter::Svf(Svf::new(mode, cutoff, q, sample_rate)),
+        }
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        match self {
+            GrainFilter::Biquad(filter) => filter.process(x),
+            GrainFilter::Svf(filter) => filter.process(x),
+        }
+    }
+}
+
+/// User ranges for the per-grain filter; each grain draws its own cutoff
+/// (evenly spread in octaves) and resonance (Q) from them.
+pub struct GrainFilterSettings {
+    enabled: AtomicBool,
+    topology: AtomicU8,
+    mode: AtomicU8,
+    cutoff_min: AtomicF32,
+    cutoff_max: AtomicF32,
+    resonance_min: AtomicF32,
+    resonance_max: AtomicF32,
+}
+
+impl GrainFilterSettings {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            topology: AtomicU8::new(FilterTopology::Svf.index()),
+            mode: AtomicU8::new(FilterMode::LowPass.index()),
+            cutoff_min: AtomicF32::new(500.0),
+            cutoff_max: AtomicF32::new(5000.0),
+            resonance_min: AtomicF32::new(0.707),
+            resonance_max: AtomicF32::new(0.707),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_type(&self, topology: FilterTopology, mode: FilterMode) {
+        self.topology.store(topology.index(), Ordering::Relaxed);
+        self.mode.store(mode.index(), Ordering::Relaxed);
+    }
+
+    /// In Hz. Equal bounds give every grain the same cutoff.
+    pub fn set_cutoff_range(&self, min: f32, max: f32) {
+        let (min, max) = (min.max(10.0), max.max(10.0));
+        self.cutoff_min.store(min.min(max));
+        self.cutoff_max.store(min.max(max));
+    }
+
+    /// As filter Q: 0.707 is flat, higher values ring.
+    pub fn set_resonance_range(&self, min: f32, max: f32) {
+        let (min, max) = (min.clamp(0.1, 40.0), max.clamp(0.1, 40.0));
+        self.resonance_min.store(min.min(max));
+        self.resonance_max.store(min.max(max));
+    }
+
+    /// Draws the filter for the next grain, or `None` when disabled.
+    pub fn random_filter(&self, sample_rate: u32) -> Option<GrainFilter> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let topology = FilterTopology::from_index(self.topology.load(Ordering::Relaxed))?;
+        let mode = FilterMode::from_index(self.mode.load(Ordering::Relaxed))?;
+        let mut rng = rand::thread_rng();
+        let (cutoff_min, cutoff_max) = (self.cutoff_min.load(), self.cutoff_max.load());
+        let cutoff = cutoff_min * (cutoff_max / cutoff_min).powf(rng.gen_range(0.0..=1.0));
+        let (q_min, q_max) = (self.resonance_min.load(), self.resonance_max.load());
+        let q = q_min + (q_max - q_min) * rng.gen_range(0.0..=1.0);
+        Some(GrainFilter::new(topology, mode, cutoff, q, sample_rate))
+    }
+}
+
+impl Default for GrainFilterSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    // Steady-state gain of a sine at `freq` through `process`, in dB
+    fn gain_db(mut process: impl FnMut(f32) -> f32, freq: f32) -> f32 {
+        let total = SAMPLE_RATE as usize;
+        let settle = total / 2;
+        let (mut input, mut output) = (0.0, 0.0);
+        for i in 0..total {
+            let x = (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin();
+            let y = process(x);
+            if i >= settle {
+                input += x * x;
+                output += y * y;
+            }
+        }
+        10.0 * (output / input).log10()
+    }
+
+    #[test]
+    fn magnitude_response() {
+        // (mode, q, test frequency, expected dB, tolerance) for a 1 kHz cutoff
+        let cases = [
+            (FilterMode::LowPass, 0.707, 1000.0, -3.01, 0.1),
+            (FilterMode::LowPass, 0.707, 100.0, 0.0, 0.1),
+            (FilterMode::LowPass, 0.707, 10000.0, -41.0, 3.0),
+            (FilterMode::HighPass, 0.707, 1000.0, -3.01, 0.1),
+            (FilterMode::HighPass, 0.707, 10000.0, 0.0, 0.1),
+            (FilterMode::HighPass, 0.707, 100.0, -40.0, 1.0),
+            (FilterMode::BandPass, 1.0, 1000.0, 0.0, 0.1),
+            (FilterMode::BandPass, 1.0, 10000.0, -20.0, 2.0),
+            (FilterMode::LowPass, 4.0, 1000.0, 12.04, 0.2),
+            (FilterMode::Notch, 0.707, 10000.0, 0.0, 0.2),
+        ];
+        for topology in [FilterTopology::Biquad, FilterTopology::Svf] {
+            for (mode, q, freq, expected, tolerance) in cases {
+                let mut filter = GrainFilter::new(topology, mode, 1000.0, q, SAMPLE_RATE);
+                let gain = gain_db(|x| filter.process(x), freq);
+                assert!(
+                    (gain - expected).abs() < tolerance,
+                    "{topology:?} {mode:?} q {q} at {freq} Hz: {gain} dB, expected {expected}"
+                );
+            }
+            let mut notch = GrainFilter::new(topology, FilterMode::Notch, 1000.0, 0.707, SAMPLE_RATE);
+            let gain = gain_db(|x| notch.process(x), 1000.0);
+            assert!(gain < -40.0, "{topology:?} notch at the cutoff: {gain} dB");
+        }
+    }
+
+    #[test]
+    fn eq_bands() {
+        let mut peak = Biquad::default();
+        peak.set_peaking(1000.0, 1.0, 6.0, SAMPLE_RATE);
+        let mut low = Biquad::default();
+        low.set_shelf(false, 200.0, 6.0, SAMPLE_RATE);
+        let mut high = Biquad::default();
+        high.set_shelf(true, 5000.0, -6.0, SAMPLE_RATE);
+        // (band, test frequency, expected dB)
+        let cases = [
+            (peak, 1000.0, 6.0),
+            (peak, 20000.0, 0.0),
+            (low, 20.0, 6.0),
+            (low, 10000.0, 0.0),
+            (high, 20000.0, -6.0),
+            (high, 50.0, 0.0),
+        ];
+        for (mut band, freq, expected) in cases {
+            let gain = gain_db(|x| band.process(x), freq);
+            assert!((gain - expected).abs() < 0.3, "at {freq} Hz: {gain} dB, expected {expected}");
+        }
+    }
+}
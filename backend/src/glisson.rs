@@ -0,0 +1,171 @@
+use crate::AtomicF32;
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// -------------------------------------
+// GLISSON
+// -------------------------------------
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GlideCurve {
+    /// Rate changes by the same amount every sample
+    Linear,
+    /// Rate changes by the same ratio every sample, an even sweep in pitch
+    Exponential,
+}
+
+impl GlideCurve {
+    pub fn from_index(index: u8) -> Self {
+        match index {
+            1 => GlideCurve::Exponential,
+            _ => GlideCurve::Linear,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            GlideCurve::Linear => 0,
+            GlideCurve::Exponential => 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GlideDirection {
+    Up,
+    Down,
+    /// Each grain flips a coin
+    Random,
+}
+
+impl GlideDirection {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(GlideDirection::Up),
+            1 => Some(GlideDirection::Down),
+            2 => Some(GlideDirection::Random),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            GlideDirection::Up => 0,
+            GlideDirection::Down => 1,
+            GlideDirection::Random => 2,
+        }
+    }
+}
+
+/// Pitch glide of one grain: the playback rate moves from the grain's own
+/// rate to `end_ratio` times it over the grain.
+#[derive(Clone, Copy)]
+pub struct Glide {
+    end_ratio: f32,
+    curve: GlideCurve,
+}
+
+impl Glide {
+    pub fn new(end_ratio: f32, curve: GlideCurve) -> Self {
+        Self {
+            end_ratio: end_ratio.max(1e-3),
+            curve,
+        }
+    }
+
+    /// Rate multiplier at `t` (0..1 across the grain).
+    pub fn rate_at(&self, t: f32) -> f32 {
+        match self.curve {
+            GlideCurve::Linear => 1.0 + (self.end_ratio - 1.0) * t,
+            GlideCurve::Exponential => self.end_ratio.powf(t),
+        }
+    }
+
+    /// Source distance covered after `i` of `length` samples at a base rate
+    /// of 1: the integral of `rate_at`, in closed form so a grain can be
+    /// rendered from any sample.
+    pub fn offset_at(&self, i: usize, length: usize) -> f32 {
+        let (i, length) = (i as f32, length.max(1) as f32);
+        match self.curve {
+            GlideCurve::Linear => i + (self.end_ratio - 1.0) * i * i / (2.0 * length),
+            GlideCurve::Exponential => {
+                let log_ratio = self.end_ratio.ln();
+                if log_ratio.abs() < 1e-6 {
+                    i
+                } else {
+                    length * (self.end_ratio.powf(i / length) - 1.0) / log_ratio
+                }
+            }
+        }
+    }
+}
+
+/// User settings for glisson grains; each grain draws its glide depth (in
+/// semitones) from the range and its direction from `direction`.
+pub struct GlissonSettings {
+    enabled: AtomicBool,
+    depth_min: AtomicF32,
+    depth_max: AtomicF32,
+    direction: AtomicU8,
+    curve: AtomicU8,
+}
+
+impl GlissonSettings {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            depth_min: AtomicF32::new(0.0),
+            depth_max: AtomicF32::new(12.0),
+            direction: AtomicU8::new(GlideDirection::Random.index()),
+            curve: AtomicU8::new(GlideCurve::Exponential.index()),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// In semitones, 0..48. Equal bounds give every grain the same depth.
+    pub fn set_depth_range(&self, min: f32, max: f32) {
+        let (min, max) = (min.clamp(0.0, 48.0), max.clamp(0.0, 48.0));
+        self.depth_min.store(min.min(max));
+        self.depth_max.store(min.max(max));
+    }
+
+    pub fn set_direction(&self, direction: GlideDirection) {
+        self.direction.store(direction.index(), Ordering::Relaxed);
+    }
+
+    pub fn set_curve(&self, curve: GlideCurve) {
+        self.curve.store(curve.index(), Ordering::Relaxed);
+    }
+
+    /// Draws the glide for the next grain, or `None` when disabled.
+    pub fn random_glide(&self) -> Option<Glide> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        let (min, max) = (self.depth_min.load(), self.depth_max.load());
+        let depth = min + (max - min) * rng.gen_range(0.0..=1.0);
+        let up = match GlideDirection::from_index(self.direction.load(Ordering::Relaxed)) {
+            Some(GlideDirection::Up) => true,
+            Some(GlideDirection::Down) => false,
+            _ => rng.gen_bool(0.5),
+        };
+        let semitones = if up { depth } else { -depth };
+        Some(Glide::new(
+            2.0_f32.powf(semitones / 12.0),
+            GlideCurve::from_index(self.curve.load(Ordering::Relaxed)),
+        ))
+    }
+}
+
+impl Default for GlissonSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
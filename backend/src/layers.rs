@@ -0,0 +1,502 @@
+use crate::bank::{SourceView, MAX_SOURCES};
+use crate::envelope::EnvelopeRunner;
+use crate::midi::MAX_POLYPHONY;
+use crate::modulation::FollowerRunner;
+use crate::smoothing::SmoothedParam;
+use crate::psola::{PsolaControl, PsolaVoice};
+use crate::spectral::{SpectralControl, SpectralVoice};
+use crate::{balance, GrainPool, GranularSynth, ProcessingMode, Recorder, MAX_ACTIVE_GRAINS};
+use crossbeam_channel::{Receiver, Sender};
+use rtrb::{Producer, RingBuffer};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+// -------------------------------------
+// LAYERS
+// -------------------------------------
+/// Most layers one engine mixes, the main synth included.
+pub const MAX_LAYERS: usize = 8;
+/// Frames a layer renders per pass; longer device buffers are split.
+pub const MAX_BLOCK_FRAMES: usize = 4096;
+/// Id of the layer playing the engine's own synth; it can't be removed.
+pub const MAIN_LAYER: usize = 0;
+// Renderers a stream may hold at once, live or on their way back; the
+// retired channel has room for all of them so the callback never drops one
+const MAX_RENDERERS: usize = 2 * MAX_LAYERS;
+
+/// Mute and solo of one layer, read by the audio callback every block.
+pub struct LayerControl {
+    muted: AtomicBool,
+    soloed: AtomicBool,
+}
+
+impl LayerControl {
+    pub fn new() -> Self {
+        Self {
+            muted: AtomicBool::new(false),
+            soloed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_soloed(&self, soloed: bool) {
+        self.soloed.store(soloed, Ordering::Relaxed);
+    }
+
+    pub fn is_soloed(&self) -> bool {
+        self.soloed.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for LayerControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Control-side handle of one layer: its synth (source, parameters, gain
+/// and pan), mute/solo and the recorder for its stem.
+pub struct Layer {
+    id: usize,
+    synth: Arc<GranularSynth>,
+    control: Arc<LayerControl>,
+    stem: Recorder,
+}
+
+impl Layer {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn synth(&self) -> &Arc<GranularSynth> {
+        &self.synth
+    }
+
+    pub fn control(&self) -> &LayerControl {
+        &self.control
+    }
+
+    pub fn stem(&self) -> &Recorder {
+        &self.stem
+    }
+}
+
+/// Channel ends the audio callback uses to follow layer changes: new
+/// renderers come in through `added`, ids to drop through `removed`, and
+/// dropped renderers go back through `retired` so they are freed on the
+/// control side, not in the callback. `retired` always has room for every
+/// renderer of the stream, so sending to it can't fail.
+pub(crate) struct LayerChanges {
+    pub added: Receiver<LayerRenderer>,
+    pub removed: Receiver<usize>,
+    pub retired: Sender<LayerRenderer>,
+}
+
+/// The layers of an engine plus the channels that keep the running stream
+/// in step with them.
+pub(crate) struct LayerSet {
+    layers: Vec<Layer>,
+    next_id: usize,
+    // Sample rate and channel count of the running stream
+    stream_format: Option<(u32, u16)>,
+    // Renderers handed to the running stream and not yet back on `retired`
+    renderers: usize,
+    added_sender: Sender<LayerRenderer>,
+    added_receiver: Receiver<LayerRenderer>,
+    removed_sender: Sender<usize>,
+    removed_receiver: Receiver<usize>,
+    retired_sender: Sender<LayerRenderer>,
+    retired_receiver: Receiver<LayerRenderer>,
+}
+
+impl LayerSet {
+    pub fn new(main: Arc<GranularSynth>) -> Self {
+        let (added_sender, added_receiver) = crossbeam_channel::bounded(MAX_LAYERS);
+        let (removed_sender, removed_receiver) = crossbeam_channel::bounded(2 * MAX_LAYERS);
+        let (retired_sender, retired_receiver) = crossbeam_channel::bounded(MAX_RENDERERS);
+        Self {
+            layers: vec![Layer {
+                id: MAIN_LAYER,
+                synth: main,
+                control: Arc::new(LayerControl::new()),
+                stem: Recorder::new(),
+            }],
+            next_id: MAIN_LAYER + 1,
+            stream_format: None,
+            renderers: 0,
+            added_sender,
+            added_receiver,
+            removed_sender,
+            removed_receiver,
+            retired_sender,
+            retired_receiver,
+        }
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn get(&self, id: usize) -> Result<&Layer, String> {
+        self.layers
+            .iter()
+            .find(|layer| layer.id == id)
+            .ok_or_else(|| format!("No layer with id {}", id))
+    }
+
+    /// Fails for a synth already playing in a layer: layers sharing a grain
+    /// channel would each get a random share of its grains.
+    pub fn add(&mut self, synth: Arc<GranularSynth>) -> Result<usize, String> {
+        self.free_retired();
+        if self.layers.len() >= MAX_LAYERS {
+            return Err(format!("At most {} layers", MAX_LAYERS));
+        }
+        if self
+            .layers
+            .iter()
+            .any(|layer| Arc::ptr_eq(&layer.synth.grain_receiver, &synth.grain_receiver))
+        {
+            return Err("The synth already plays in a layer".to_string());
+        }
+        if self.stream_format.is_some() && self.renderers >= MAX_RENDERERS {
+            return Err("Removed layers are still being freed".to_string());
+        }
+        let layer = Layer {
+            id: self.next_id,
+            synth,
+            control: Arc::new(LayerControl::new()),
+            stem: Recorder::new(),
+        };
+        if let Some((sample_rate, channels)) = self.stream_format {
+            let renderer = LayerRenderer::new(&layer, sample_rate, channels);
+            self.added_sender
+                .try_send(renderer)
+                .map_err(|_| "Layer queue is full".to_string())?;
+            self.renderers += 1;
+        }
+        self.next_id += 1;
+        let id = layer.id;
+        self.layers.push(layer);
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Result<(), String> {
+        self.free_retired();
+        if id == MAIN_LAYER {
+            return Err("The main layer can't be removed".to_string());
+        }
+        let index = self
+            .layers
+            .iter()
+            .position(|layer| layer.id == id)
+            .ok_or_else(|| format!("No layer with id {}", id))?;
+        if self.stream_format.is_some() {
+            self.removed_sender
+                .try_send(id)
+                .map_err(|_| "Layer queue is full".to_string())?;
+        }
+        let layer = self.layers.remove(index);
+        if layer.stem.is_recording() {
+            let _ = layer.stem.stop();
+        }
+        Ok(())
+    }
+
+    /// Builds the renderers for a new stream and hands out the channel ends
+    /// its callback uses.
+    pub fn start(
+        &mut self,
+        sample_rate: u32,
+        channels: u16,
+    ) -> (Vec<LayerRenderer>, LayerChanges) {
+        // Changes queued for a previous stream no longer apply
+        while self.added_receiver.try_recv().is_ok() {}
+        while self.removed_receiver.try_recv().is_ok() {}
+        self.free_retired();
+        self.stream_format = Some((sample_rate, channels));
+        let mut renderers = Vec::with_capacity(MAX_LAYERS);
+        for layer in &self.layers {
+            renderers.push(LayerRenderer::new(layer, sample_rate, channels));
+        }
+        self.renderers = renderers.len();
+        let changes = LayerChanges {
+            added: self.added_receiver.clone(),
+            removed: self.removed_receiver.clone(),
+            retired: self.retired_sender.clone(),
+        };
+        (renderers, changes)
+    }
+
+    /// Call once the stream is dropped; its renderers went with it.
+    pub fn stop(&mut self) {
+        self.stream_format = None;
+        self.free_retired();
+        self.renderers = 0;
+    }
+
+    fn free_retired(&mut self) {
+        while self.retired_receiver.try_recv().is_ok() {
+            self.renderers = self.renderers.saturating_sub(1);
+        }
+    }
+}
+
+/// Audio-thread side of one layer: the grains it is playing and the
+/// smoothing and envelope state that used to live in the engine callback.
+/// `render` fills `left`/`right` with one block of the layer after its gain,
+/// pan and mute/solo.
+pub(crate) struct LayerRenderer {
+    id: usize,
+    synth: Arc<GranularSynth>,
+    control: Arc<LayerControl>,
+    sample_rate: u32,
+    channels: usize,
+    pool: GrainPool,
+    // Used instead of the pool in the spectral and PSOLA modes
+    spectral: SpectralVoice,
+    psola: PsolaVoice,
+    gain: SmoothedParam,
+    pan: SmoothedParam,
+    // Fades the layer in and out on mute/solo instead of clicking
+    level: SmoothedParam,
+    followers: FollowerRunner,
+    cloud_env: EnvelopeRunner,
+    cloud_env_triggers: u32,
+    note_envs: [EnvelopeRunner; MAX_POLYPHONY],
+    note_env_triggers: [u32; MAX_POLYPHONY],
+    // Level of every cloud for the current frame, see `Grain::cloud`
+    cloud_levels: [f32; 1 + MAX_POLYPHONY],
+    left: Vec<f32>,
+    right: Vec<f32>,
+    peak_left: f32,
+    peak_right: f32,
+    // The stem recorder's writer thread drains this ring
+    stem_producer: Producer<f32>,
+    stem_recording: Arc<AtomicBool>,
+}
+
+impl LayerRenderer {
+    pub fn new(layer: &Layer, sample_rate: u32, channels: u16) -> Self {
+        let synth = Arc::clone(&layer.synth);
+        let (stem_producer, stem_consumer) =
+            RingBuffer::<f32>::new(sample_rate as usize * channels as usize);
+        *layer.stem.consumer.lock().unwrap() = Some(stem_consumer);
+        Self {
+            id: layer.id,
+            control: Arc::clone(&layer.control),
+            sample_rate,
+            channels: channels.max(1) as usize,
+            pool: GrainPool::new(MAX_ACTIVE_GRAINS),
+            spectral: SpectralVoice::new(),
+            psola: PsolaVoice::new(),
+            gain: SmoothedParam::new(synth.params.gain()),
+            pan: SmoothedParam::new(synth.params.pan()),
+            level: SmoothedParam::new(1.0),
+            followers: FollowerRunner::new(),
+            cloud_env: EnvelopeRunner::new(),
+            cloud_env_triggers: synth.cloud_envelope.triggers(),
+            note_envs: [EnvelopeRunner::new(); MAX_POLYPHONY],
+            note_env_triggers: std::array::from_fn(|i| synth.midi.voices()[i].triggers()),
+            cloud_levels: [1.0; 1 + MAX_POLYPHONY],
+            left: vec![0.0; MAX_BLOCK_FRAMES],
+            right: vec![0.0; MAX_BLOCK_FRAMES],
+            peak_left: 0.0,
+            peak_right: 0.0,
+            stem_producer,
+            stem_recording: Arc::clone(&layer.stem.is_recording),
+            synth,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn control(&self) -> &LayerControl {
+        &self.control
+    }
+
+    /// Stereo output of frame `index` of the last block.
+    pub fn frame(&self, index: usize) -> (f32, f32) {
+        (self.left[index], self.right[index])
+    }
+
+    /// Renders `frames` (at most `MAX_BLOCK_FRAMES`) frames. `any_solo`
+    /// tells whether some layer of the engine is soloed.
+    pub fn render(&mut self, frames: usize, any_solo: bool) {
+        let frames = frames.min(MAX_BLOCK_FRAMES);
+        let synth = &self.synth;
+        let sample_rate = self.sample_rate;
+
+        // 1. gather any newly scheduled grains; the spectral and PSOLA modes
+        // make their own, so time-domain grains are dropped while they are on
+        let processing_mode = synth.params.processing_mode();
+        while let Ok(grain) = synth.grain_receiver.try_recv() {
+            if processing_mode == ProcessingMode::Granular {
+                self.pool.trigger(grain);
+            }
+        }
+
+        // 2. fill the layer buffers
+        // The sources, the grain envelope, the wavetable and the analyses are
+        // snapshots borrowed for the block without locking or waiting
+        let env = synth.grain_env.load();
+        let wavetable = synth.wavetable.load();
+        let spectral_frames = synth.spectral_frames.load();
+        let pitch_marks = synth.pitch_marks.load();
+        let live_buffer = synth.live_input.buffer();
+        let bank = &synth.bank;
+        let slots: [_; MAX_SOURCES] = std::array::from_fn(|slot| bank.slot(slot));
+        let sources = SourceView::new(
+            std::array::from_fn(|slot| &slots[slot].current[..]),
+            std::array::from_fn(|slot| &slots[slot].retired[..]),
+            std::array::from_fn(|slot| slots[slot].generation),
+        );
+        let source = sources.source(0, slots[0].generation);
+        let params = &synth.params;
+        let (mode, time_ms) = params.smoothing();
+        self.gain.configure(mode, time_ms, sample_rate);
+        self.pan.configure(mode, time_ms, sample_rate);
+        self.level.configure(mode, time_ms, sample_rate);
+        self.gain.set_target(params.gain());
+        self.pan.set_target(params.pan());
+        let audible = !self.control.is_muted() && (!any_solo || self.control.is_soloed());
+        self.level.set_target(if audible { 1.0 } else { 0.0 });
+        self.followers.prepare(&synth.modulation, sample_rate);
+        let grain_params = params.snapshot();
+        let filesize = grain_params.specs.filesize;
+        let bounds = synth.region.bounds(filesize);
+        let position = bounds.map_or(grain_params.grain_start, |b| {
+            b.map(grain_params.grain_start / filesize as f32)
+        });
+        let spectral_control = SpectralControl {
+            position,
+            spread_samples: synth.spectral.spread_ms() * sample_rate as f32 / 1000.0,
+            pitch: grain_params.grain_pitch,
+            frozen: synth.spectral.is_frozen(),
+            phase_randomness: synth.spectral.phase_randomness(),
+            bounds,
+        };
+        let psola_control = PsolaControl {
+            position,
+            pitch: grain_params.grain_pitch,
+        };
+        let mode_level = grain_params.grain_amplitude;
+        let (mode_l, mode_r) = balance(0.0);
+
+        let cloud_envelope = &synth.cloud_envelope;
+        let cloud_env_enabled = cloud_envelope.is_enabled();
+        let cloud_env_shape = cloud_envelope.shape();
+        let triggers = cloud_envelope.triggers();
+        if triggers != self.cloud_env_triggers {
+            self.cloud_env_triggers = triggers;
+            self.cloud_env.trigger(&cloud_env_shape, sample_rate);
+        }
+        if !cloud_envelope.gate() {
+            self.cloud_env.release(&cloud_env_shape, sample_rate);
+        }
+        // Note voices use the cloud envelope shape
+        for (i, voice) in synth.midi.voices().iter().enumerate() {
+            let triggers = voice.triggers();
+            if triggers != self.note_env_triggers[i] {
+                self.note_env_triggers[i] = triggers;
+                self.note_envs[i].trigger(&cloud_env_shape, sample_rate);
+            }
+            if !voice.gate() {
+                self.note_envs[i].release(&cloud_env_shape, sample_rate);
+            }
+        }
+
+        for i in 0..frames {
+            self.cloud_levels[0] = if cloud_env_enabled {
+                self.cloud_env.tick(&cloud_env_shape, sample_rate)
+            } else {
+                1.0
+            };
+            for (level, note_env) in self.cloud_levels[1..].iter_mut().zip(self.note_envs.iter_mut()) {
+                *level = note_env.tick(&cloud_env_shape, sample_rate);
+            }
+            let (mut left, mut right) = self.pool.next_frame(
+                &sources,
+                live_buffer.as_deref(),
+                &env[..],
+                &wavetable[..],
+                &self.cloud_levels,
+            );
+            let sample = match processing_mode {
+                ProcessingMode::Granular => 0.0,
+                ProcessingMode::Spectral => {
+                    self.spectral.next_sample(&spectral_frames, &spectral_control)
+                }
+                ProcessingMode::Psola => {
+                    self.psola.next_sample(source, &pitch_marks, &psola_control)
+                }
+            } * mode_level
+                * self.cloud_levels[0];
+            left += sample * mode_l;
+            right += sample * mode_r;
+            let gain_now = self.gain.tick() * self.level.tick();
+            let (pan_l, pan_r) = balance(self.pan.tick());
+            let (left, right) = (left * gain_now * pan_l, right * gain_now * pan_r);
+            self.followers.process(0.5 * (left + right));
+            self.peak_left = self.peak_left.max(left.abs());
+            self.peak_right = self.peak_right.max(right.abs());
+            self.left[i] = left;
+            self.right[i] = right;
+        }
+        self.followers.publish(&synth.modulation);
+        for (i, voice) in synth.midi.voices().iter().enumerate() {
+            if voice.is_active() && self.note_envs[i].is_idle() {
+                voice.retire(self.note_env_triggers[i]);
+            }
+        }
+        // Lets the loader free a retired buffer once no grain reads it
+        for (slot, in_use) in self.pool.retired_in_use(&sources).into_iter().enumerate() {
+            bank.set_retired_in_use(slot, in_use);
+        }
+        drop(slots);
+        drop(live_buffer);
+        drop(pitch_marks);
+        drop(spectral_frames);
+        drop(wavetable);
+        drop(env);
+
+        // Stem recording, laid out like the master output
+        if self.stem_recording.load(Ordering::Relaxed) {
+            'frames: for i in 0..frames {
+                let (left, right) = (self.left[i], self.right[i]);
+                let mono = 0.5 * (left + right);
+                for ch in 0..self.channels {
+                    let sample = match ch {
+                        _ if self.channels == 1 => mono,
+                        0 => left,
+                        1 => right,
+                        _ => mono,
+                    };
+                    if self.stem_producer.push(sample).is_err() {
+                        break 'frames;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publishes the peaks since the last call to the layer's meters. The
+    /// main layer's meters show the master output instead, so it skips this.
+    pub fn publish_peaks(&mut self) {
+        if self.id != MAIN_LAYER {
+            self.synth.meters.set_peaks(self.peak_left, self.peak_right);
+        }
+        self.peak_left = 0.0;
+        self.peak_right = 0.0;
+    }
+}
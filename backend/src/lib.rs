@@ -8,35 +8,363 @@ use crossbeam_channel::{Sender, Receiver};
 use dasp_signal::{self as signal, Signal, FromInterleavedSamplesIterator};
 use dasp_interpolate::linear::Linear;
 use rand::Rng;
+use rtrb::{Consumer, RingBuffer};
+use arc_swap::ArcSwap;
+use smoothing::{GrainSmoothers, SmoothedParam, SmoothingMode};
+use envelope::{CloudEnvelope, EnvelopeShape, MAX_ENVELOPE_SEGMENTS};
+use midi::{MidiEvent, MidiState, MAX_POLYPHONY};
+use midi_map::{CcMapper, CcMapping, CcSource, MappingCurve, MappingTarget};
+use preset::Preset;
+use bank::{SourceBank, SourceView, MAX_SOURCES};
+use loader::{LoadOutcome, Loader};
+use osc::OscServer;
+use spectral::{SpectralFrames, SpectralSettings};
+use psola::PitchMarks;
+use region::{BoundaryMode, SourceBounds, SourceRegion};
+use shifter::PitchShifter;
+use stretch::StretchSettings;
+use descriptors::{Corpus, Descriptor, DescriptorSettings};
+use glisson::{Glide, GlideCurve, GlideDirection, GlissonSettings};
+use oscillator::{GrainOscillator, OscillatorSettings, Waveform};
+use effects::{Effect, EffectChain, MasterEffects, FX_COUNT};
+use layers::{LayerSet, MAX_BLOCK_FRAMES, MAX_LAYERS};
+use live::{LiveBuffer, LiveInput};
+use filter::{FilterMode, FilterTopology, GrainFilter, GrainFilterSettings};
+use tempo::{DivisionModifier, StepClock, TempoSync, Transport};
+use midir::MidiInputConnection;
+use modulation::{
+    LfoShape, ModRoute, ModSource, ModState, ModTarget, Modulation,
+};
 use std::{
     sync::{Arc, Mutex},
     f32::consts::PI,
     time::{Duration, Instant},
-    sync::atomic::AtomicBool,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicUsize},
     thread::{self, JoinHandle},
     sync::atomic::Ordering,
     fs::File,
     io::BufWriter,
 };
 
+pub mod bank;
+pub mod descriptors;
+pub mod effects;
+pub mod envelope;
+pub mod filter;
+pub mod glisson;
+pub mod layers;
+pub mod live;
+pub mod loader;
+pub mod midi;
+pub mod midi_map;
+pub mod modulation;
+pub mod osc;
+pub mod oscillator;
+pub mod preset;
+pub mod psola;
+pub mod region;
+pub mod shifter;
+pub mod smoothing;
+pub mod spectral;
+pub mod stretch;
+pub mod tempo;
+
 
 // -------------------------------------
 // SPECS, PARAMS
 // -------------------------------------
+#[derive(Clone, Copy)]
 pub struct Specs {
     pub sample_rate: u32,
     pub channels: u16,
     pub filesize: usize,
 }
 
+#[derive(Clone, Copy)]
 pub struct GrainParams {
     pub grain_start: f32,
     pub grain_duration: usize,
     pub grain_overlap: f32,
     pub grain_pitch: f32,
+    pub grain_amplitude: f32,
+    pub grain_pan: f32,
     pub specs: Specs,
 }
 
+/// How a synth turns its source into sound.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ProcessingMode {
+    /// Time-domain grains from the scheduler
+    Granular,
+    /// STFT frames resynthesized by overlap-add, see `spectral`
+    Spectral,
+    /// Pitch-synchronous overlap-add that keeps the formants, see `psola`
+    Psola,
+}
+
+impl ProcessingMode {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(ProcessingMode::Granular),
+            1 => Some(ProcessingMode::Spectral),
+            2 => Some(ProcessingMode::Psola),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            ProcessingMode::Granular => 0,
+            ProcessingMode::Spectral => 1,
+            ProcessingMode::Psola => 2,
+        }
+    }
+}
+
+/// `f32` stored in an `AtomicU32` through its bit pattern.
+pub struct AtomicF32(AtomicU32);
+impl AtomicF32 {
+    pub fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Lock-free home of the grain parameters. The FFI setters write single
+/// fields and the scheduler reads a `GrainParams` snapshot per grain, so
+/// neither side ever waits on the other.
+pub struct SharedParams {
+    grain_start: AtomicF32,
+    grain_duration: AtomicUsize,
+    grain_overlap: AtomicF32,
+    grain_pitch: AtomicF32,
+    sample_rate: AtomicU32,
+    channels: AtomicU16,
+    filesize: AtomicUsize,
+    // Output level and stereo balance, smoothed per sample in the callback
+    gain: AtomicF32,
+    pan: AtomicF32,
+    grain_amplitude: AtomicF32,
+    grain_pan: AtomicF32,
+    // Gain compensation for the number of overlapping grains, and the mean
+    // square of the grain envelope it uses
+    gain_compensation: AtomicBool,
+    envelope_energy: AtomicF32,
+    smoothing_mode: AtomicU8,
+    smoothing_time_ms: AtomicF32,
+    processing_mode: AtomicU8,
+}
+impl SharedParams {
+    pub fn new(params: GrainParams) -> Self {
+        Self {
+            grain_start: AtomicF32::new(params.grain_start),
+            grain_duration: AtomicUsize::new(params.grain_duration),
+            grain_overlap: AtomicF32::new(params.grain_overlap),
+            grain_pitch: AtomicF32::new(params.grain_pitch),
+            sample_rate: AtomicU32::new(params.specs.sample_rate),
+            channels: AtomicU16::new(params.specs.channels),
+            filesize: AtomicUsize::new(params.specs.filesize),
+            gain: AtomicF32::new(1.0),
+            pan: AtomicF32::new(0.0),
+            grain_amplitude: AtomicF32::new(params.grain_amplitude),
+            grain_pan: AtomicF32::new(params.grain_pan),
+            gain_compensation: AtomicBool::new(false),
+            envelope_energy: AtomicF32::new(1.0),
+            smoothing_mode: AtomicU8::new(SmoothingMode::Linear.index()),
+            smoothing_time_ms: AtomicF32::new(20.0),
+            processing_mode: AtomicU8::new(ProcessingMode::Granular.index()),
+        }
+    }
+
+    pub fn snapshot(&self) -> GrainParams {
+        GrainParams {
+            grain_start: self.grain_start.load(),
+            grain_duration: self.grain_duration.load(Ordering::Relaxed),
+            grain_overlap: self.grain_overlap.load(),
+            grain_pitch: self.grain_pitch.load(),
+            grain_amplitude: self.grain_amplitude.load(),
+            grain_pan: self.grain_pan.load(),
+            specs: self.specs(),
+        }
+    }
+
+    pub fn specs(&self) -> Specs {
+        Specs {
+            sample_rate: self.sample_rate.load(Ordering::Relaxed),
+            channels: self.channels.load(Ordering::Relaxed),
+            filesize: self.filesize.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Grain start as a 0..1 position over the loaded file.
+    pub fn normalized_grain_start(&self) -> f32 {
+        let filesize = self.filesize.load(Ordering::Relaxed);
+        if filesize == 0 {
+            return 0.0;
+        }
+        self.grain_start.load() / filesize as f32
+    }
+
+    /// `start` is normalized 0..1 over the loaded file.
+    pub fn set_grain_start(&self, start: f32) {
+        let filesize = self.filesize.load(Ordering::Relaxed);
+        self.grain_start.store(start.clamp(0.0, 1.0) * filesize as f32);
+    }
+
+    pub fn set_grain_duration(&self, duration: usize) {
+        self.grain_duration.store(duration, Ordering::Relaxed);
+    }
+
+    pub fn set_grain_overlap(&self, overlap: f32) {
+        self.grain_overlap.store(overlap.clamp(1.0, 2.0));
+    }
+
+    pub fn set_grain_pitch(&self, pitch: f32) {
+        self.grain_pitch.store(pitch.clamp(0.1, 2.0));
+    }
+
+    pub fn set_specs(&self, specs: Specs) {
+        self.sample_rate.store(specs.sample_rate, Ordering::Relaxed);
+        self.channels.store(specs.channels, Ordering::Relaxed);
+        self.filesize.store(specs.filesize, Ordering::Relaxed);
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain.load()
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.clamp(0.0, 2.0));
+    }
+
+    pub fn pan(&self) -> f32 {
+        self.pan.load()
+    }
+
+    /// -1.0 is hard left, 1.0 hard right.
+    pub fn set_pan(&self, pan: f32) {
+        self.pan.store(pan.clamp(-1.0, 1.0));
+    }
+
+    pub fn grain_amplitude(&self) -> f32 {
+        self.grain_amplitude.load()
+    }
+
+    /// Level of every grain, before gain compensation and the output gain.
+    pub fn set_grain_amplitude(&self, amplitude: f32) {
+        self.grain_amplitude.store(amplitude.clamp(0.0, 2.0));
+    }
+
+    pub fn grain_pan(&self) -> f32 {
+        self.grain_pan.load()
+    }
+
+    /// Pan of every grain (-1..1), before pan modulation and the output pan.
+    pub fn set_grain_pan(&self, pan: f32) {
+        self.grain_pan.store(pan.clamp(-1.0, 1.0));
+    }
+
+    pub fn is_gain_compensation_enabled(&self) -> bool {
+        self.gain_compensation.load(Ordering::Relaxed)
+    }
+
+    pub fn set_gain_compensation(&self, enabled: bool) {
+        self.gain_compensation.store(enabled, Ordering::Relaxed);
+    }
+
+    fn set_envelope_energy(&self, energy: f32) {
+        self.envelope_energy.store(energy);
+    }
+
+    /// Grain gain that keeps the loudness steady when `expected_overlap`
+    /// grains sound at once. Grains read from random positions add up in
+    /// power, so the sum is scaled by 1 / sqrt(overlap * envelope energy).
+    /// 1.0 while compensation is off.
+    pub fn gain_compensation(&self, expected_overlap: f32) -> f32 {
+        if !self.is_gain_compensation_enabled() {
+            return 1.0;
+        }
+        let power = expected_overlap.max(0.0) * self.envelope_energy.load();
+        // Cap the boost at 4x for sparse clouds and near-silent envelopes
+        1.0 / power.max(1.0 / 16.0).sqrt()
+    }
+
+    pub fn smoothing(&self) -> (SmoothingMode, f32) {
+        (
+            SmoothingMode::from_index(self.smoothing_mode.load(Ordering::Relaxed)),
+            self.smoothing_time_ms.load(),
+        )
+    }
+
+    pub fn set_smoothing_mode(&self, mode: SmoothingMode) {
+        self.smoothing_mode.store(mode.index(), Ordering::Relaxed);
+    }
+
+    /// Ramp length (linear) or time constant (one-pole) for every
+    /// continuous control.
+    pub fn set_smoothing_time(&self, time_ms: f32) {
+        self.smoothing_time_ms.store(time_ms.max(0.0));
+    }
+
+    pub fn processing_mode(&self) -> ProcessingMode {
+        ProcessingMode::from_index(self.processing_mode.load(Ordering::Relaxed))
+            .unwrap_or(ProcessingMode::Granular)
+    }
+
+    pub fn set_processing_mode(&self, mode: ProcessingMode) {
+        self.processing_mode.store(mode.index(), Ordering::Relaxed);
+    }
+}
+
+/// Values published for level meters and remote control: the output peak
+/// of every block from the audio callback and the position of the latest
+/// grain from the scheduler.
+pub struct Meters {
+    peak_left: AtomicF32,
+    peak_right: AtomicF32,
+    playhead: AtomicF32,
+}
+impl Meters {
+    pub fn new() -> Self {
+        Self {
+            peak_left: AtomicF32::new(0.0),
+            peak_right: AtomicF32::new(0.0),
+            playhead: AtomicF32::new(0.0),
+        }
+    }
+
+    pub fn peaks(&self) -> (f32, f32) {
+        (self.peak_left.load(), self.peak_right.load())
+    }
+
+    fn set_peaks(&self, left: f32, right: f32) {
+        self.peak_left.store(left);
+        self.peak_right.store(right);
+    }
+
+    /// Start of the latest grain, 0..1 over the source.
+    pub fn playhead(&self) -> f32 {
+        self.playhead.load()
+    }
+
+    fn set_playhead(&self, position: f32) {
+        self.playhead.store(position);
+    }
+}
+
+impl Default for Meters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // -------------------------------------
 // GRAIN VOICE
 // -------------------------------------
@@ -47,7 +375,7 @@ pub struct GrainVoice {
     mydur: f32,
     interpolation: Interpolation
 }
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub enum Interpolation {
     FourPoint,
     Sinc,
@@ -65,75 +393,128 @@ impl GrainVoice {
         }
     }
 
+    /// Resolves this voice against the current parameters into a `Grain`
+    /// that can be rendered sample by sample.
+    pub fn grain(&self, grain_params: &GrainParams) -> Grain {
+        let sample_rate = grain_params.specs.sample_rate;
+        // total # of samples for this grain
+        let duration_in_samples =
+            (self.mydur * grain_params.grain_duration as f32)
+            / 1000.0
+            * sample_rate as f32;
+
+        Grain {
+            source_start: grain_params.grain_start + self.mystart,
+            playback_rate: self.mypitch * grain_params.grain_pitch,
+            length: duration_in_samples as usize,
+            amplitude: grain_params.grain_amplitude,
+            pan: grain_params.grain_pan,
+            cloud: 0,
+            interpolation: self.interpolation,
+            filter: None,
+            oscillator: None,
+            glide: None,
+            live: false,
+            bounds: None,
+            source: 0,
+            generation: 0,
+        }
+    }
+
     pub fn process_grain(
         &self,
         source_array: &[f32],
         grain_env: &[f32],
         grain_params: &GrainParams,
     ) -> Vec<f32> {
-        // How many samples in this grain?
-        let (sample_rate, _channels) = (
-            grain_params.specs.sample_rate,
-            grain_params.specs.channels
-            );
-        // total # of samples for this grain
-        let duration_in_samples =
-            (self.mydur * grain_params.grain_duration as f32) 
-            / 1000.0 
-            * sample_rate as f32;
-        
-        let base_source_start = grain_params.grain_start + self.mystart;
-        let playback_rate = self.mypitch * grain_params.grain_pitch;
-        // let total_duration_samples = duration_in_samples / playback_rate;
-        let mut output = vec![0.0; duration_in_samples as usize];
-
-        for i in 0..duration_in_samples as usize {
-            // ----------------------------
-            // 1) Envelope ramp
-            // ----------------------------
-            // env_pos goes from 0..1 across duration_in_samples
-            let env_pos = i as f32 / (duration_in_samples as f32);
-            // Map env_pos [0..1] -> [0..grain_env.len()-1] 
-            let env_index_float = env_pos * (grain_env.len() as f32 - 1.0);
-            // let env_index = env_index_float.floor() as usize;
-            // Later do linear interpolation for a smoother read
-            let envelope_value = match self.interpolation {
-                Interpolation::FourPoint => {
-                        four_point_interpolation(grain_env, env_index_float)
-                },
-                Interpolation::Sinc => {
-                        sinc_interpolation(grain_env, env_index_float)
-                },
-                Interpolation::Cubic => {
-                        cubic_interpolation(grain_env, env_index_float)
-                },
-                Interpolation::Linear => {
-                        linear_interpolation(grain_env, env_index_float)
-                },
-            };
-            // ----------------------------
-            // 2) Source read ramp
-            // ----------------------------
-            // Each sample, we move forward by `playback_rate` (set by pitch)
-            // starting from `base_source_start`.
-            let source_index_float = base_source_start + (i as f32 * playback_rate);
-            let source_value = match self.interpolation {
-                Interpolation::FourPoint => {
-                        four_point_interpolation(source_array, source_index_float)
-                },
-                Interpolation::Sinc => {
-                        sinc_interpolation(source_array, source_index_float)
-                },
-                Interpolation::Cubic => {
-                        cubic_interpolation(source_array, source_index_float)
-                },
-                Interpolation::Linear => {
-                        linear_interpolation(source_array, source_index_float)
+        let mut grain = ActiveGrain::new(self.grain(grain_params));
+        (0..grain.grain.length)
+            .map(|_| grain.next_sample(&SourceView::single(source_array), None, grain_env, &[]))
+            .collect()
+    }
+}
+
+// -------------------------------------
+// GRAIN
+// -------------------------------------
+/// Everything needed to render one grain. It is small and `Copy` so it can
+/// travel from the scheduler to the audio thread without allocating.
+#[derive(Clone, Copy)]
+pub struct Grain {
+    pub source_start: f32,
+    pub playback_rate: f32,
+    pub length: usize,
+    pub amplitude: f32,
+    /// -1.0 is hard left, 1.0 hard right.
+    pub pan: f32,
+    /// Which cloud the grain belongs to: 0 is the free-running cloud,
+    /// 1..=MAX_POLYPHONY the MIDI note voices.
+    pub cloud: u8,
+    pub interpolation: Interpolation,
+    /// Optional filter with its own cutoff/resonance, run on the
+    /// enveloped grain.
+    pub filter: Option<GrainFilter>,
+    /// Synthetic waveform played instead of the source file when set.
+    pub oscillator: Option<GrainOscillator>,
+    /// Glisson: pitch glide from `playback_rate` over the grain.
+    pub glide: Option<Glide>,
+    /// Read from the live input buffer instead of the source file;
+    /// `source_start` is then a position in that buffer.
+    pub live: bool,
+    /// Source region the reads are confined to (source file grains only).
+    pub bounds: Option<SourceBounds>,
+    /// Source bank slot read by the grain; 0 is the main source.
+    pub source: u8,
+    /// Generation of the slot when the grain started: after a swap the
+    /// grain keeps reading the buffer it started on.
+    pub generation: u32,
+}
+
+impl Grain {
+    /// Renders sample `i` of the grain straight from the source (or the live
+    /// buffer) and envelope.
+    pub fn sample_at(
+        &self,
+        i: usize,
+        source_array: &[f32],
+        live_buffer: Option<&LiveBuffer>,
+        grain_env: &[f32],
+    ) -> f32 {
+        // ----------------------------
+        // 1) Envelope ramp
+        // ----------------------------
+        let envelope_value = self.envelope_at(i, grain_env);
+        // ----------------------------
+        // 2) Source read ramp
+        // ----------------------------
+        // Each sample, we move forward by `playback_rate` (set by pitch)
+        // starting from `source_start`.
+        let offset = match self.glide {
+            Some(glide) => glide.offset_at(i, self.length),
+            None => i as f32,
+        };
+        let source_index_float = self.source_start + (offset * self.playback_rate);
+        let source_value = match (self.live, live_buffer) {
+            (false, _) => match self.bounds {
+                Some(bounds) => {
+                    interpolate(self.interpolation, source_array, bounds.fold(source_index_float))
                 }
-            };
-            output[i] = source_value * envelope_value;
-        }
-        output
+                None => interpolate(self.interpolation, source_array, source_index_float),
+            },
+            (true, Some(buffer)) => buffer.read(self.interpolation, source_index_float),
+            (true, None) => 0.0,
+        };
+
+        source_value * envelope_value * self.amplitude
+    }
+
+    /// Envelope level at sample `i` of the grain.
+    pub fn envelope_at(&self, i: usize, grain_env: &[f32]) -> f32 {
+        // env_pos goes from 0..1 across the grain length
+        let env_pos = i as f32 / self.length as f32;
+        // Map env_pos [0..1] -> [0..grain_env.len()-1]
+        let env_index_float = env_pos * (grain_env.len() as f32 - 1.0);
+        interpolate(self.interpolation, grain_env, env_index_float)
     }
 }
 // -------------------------------------
@@ -192,6 +573,95 @@ impl Default for UserRecordingSettings {
         }
     }
 }
+// -------------------------------------
+// RECORDER
+// -------------------------------------
+// The writer thread, returning any write/finalize error
+type WriterHandle = JoinHandle<Result<(), String>>;
+
+/// Recording state shared between the engine, its writer thread and remote
+/// control (OSC). Cloning shares the same recording.
+#[derive(Clone)]
+pub struct Recorder {
+    is_recording: Arc<AtomicBool>,
+    // Read side of the ring buffer the audio callback pushes recorded
+    // samples into. Only the writer thread and `start` touch this lock.
+    consumer: Arc<Mutex<Option<Consumer<f32>>>>,
+    thread: Arc<Mutex<Option<WriterHandle>>>,
+    // Last format resolved by the engine
+    spec: Arc<Mutex<Option<hound::WavSpec>>>,
+}
+
+impl Recorder {
+    fn new() -> Self {
+        Self {
+            is_recording: Arc::new(AtomicBool::new(false)),
+            consumer: Arc::new(Mutex::new(None)),
+            thread: Arc::new(Mutex::new(None)),
+            spec: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn set_spec(&self, spec: hound::WavSpec) {
+        *self.spec.lock().unwrap() = Some(spec);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.is_recording.load(Ordering::SeqCst)
+    }
+
+    /// Starts writing the output to `output_path` in the engine's format.
+    pub fn start(&self, output_path: &str) -> Result<(), String> {
+        let mut thread_slot = self.thread.lock().unwrap();
+        if self.is_recording() || thread_slot.is_some() {
+            return Err("Already recording!".to_string());
+        }
+        let spec = self.spec
+            .lock()
+            .unwrap()
+            .ok_or("No recording format set")?;
+        println!(
+            "Recording {} Hz, {} channels, {} bit",
+            spec.sample_rate, spec.channels, spec.bits_per_sample
+        );
+
+        let file = File::create(output_path).map_err(|e| e.to_string())?;
+        let bw = BufWriter::new(file);
+        let wav_writer = hound::WavWriter::new(bw, spec)
+            .map_err(|e| e.to_string())?;
+        let writer = Writers::WavWriter(wav_writer);
+
+        // Drop whatever was left in the ring from a previous take
+        if let Some(consumer) = self.consumer.lock().unwrap().as_mut() {
+            while consumer.pop().is_ok() {}
+        }
+
+        self.is_recording.store(true, Ordering::SeqCst);
+        let is_recording = Arc::clone(&self.is_recording);
+        let consumer = Arc::clone(&self.consumer);
+        let bit_depth = spec.bits_per_sample;
+        *thread_slot = Some(thread::spawn(move || {
+            AudioEngine::drain_recording(writer, consumer, is_recording, bit_depth)
+        }));
+
+        Ok(())
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        let mut thread_slot = self.thread.lock().unwrap();
+        if !self.is_recording.swap(false, Ordering::SeqCst) {
+            return Err("Not currently recording!".to_string());
+        }
+        // The writer thread drains what is left and finalizes the file
+        match thread_slot.take() {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| "Recording thread panicked".to_string())?,
+            None => Ok(()),
+        }
+    }
+}
+
 // -------------------------------------
 // AUDIO ENGINE STRUCT
 // -------------------------------------
@@ -199,10 +669,17 @@ pub struct AudioEngine {
     synth: Arc<GranularSynth>,
     output_device: Option<cpal::platform::Device>,
     stream: Option<cpal::Stream>,
+    // Feeds the main synth's live buffer while running
+    input_device: Option<cpal::platform::Device>,
+    input_stream: Option<cpal::Stream>,
     user_recording_settings: UserRecordingSettings,
     device_default_config: Option<cpal::SupportedStreamConfig>,
-    is_recording: Arc<Mutex<bool>>,
-    writer: Arc<Mutex<Option<Writers>>>,
+    recorder: Recorder,
+    osc_server: Option<OscServer>,
+    effects: Arc<MasterEffects>,
+    layers: LayerSet,
+    // Output level after the master effects, smoothed in the callback
+    master_gain: Arc<AtomicF32>,
 }
 
 impl AudioEngine {
@@ -217,13 +694,18 @@ impl AudioEngine {
         });
 
         AudioEngine {
+            layers: LayerSet::new(Arc::clone(&synth)),
             synth,
             output_device,
             stream: None,
+            input_device: None,
+            input_stream: None,
             user_recording_settings: user_settings,
             device_default_config,
-            is_recording: Arc::new(Mutex::new(false)),
-            writer: Arc::new(Mutex::new(None)),
+            recorder: Recorder::new(),
+            osc_server: None,
+            effects: Arc::new(MasterEffects::new()),
+            master_gain: Arc::new(AtomicF32::new(1.0)),
         }
     }
     // ---------------
@@ -237,6 +719,13 @@ impl AudioEngine {
 
     pub fn set_sample_rate(&mut self, sample_rate: u32){
         self.user_recording_settings.sample_rate = Some(sample_rate);
+        // The live input runs at the master rate; restarting it resizes the
+        // live buffer for the new one
+        if self.input_stream.is_some() {
+            if let Err(e) = self.start_live_input() {
+                eprintln!("Failed to restart live input: {}", e);
+            }
+        }
     }
 
     pub fn set_bit_depth(&mut self, bit_depth: u16) {
@@ -355,54 +844,94 @@ impl AudioEngine {
         };
         println!("Sample Rate for Stream: {}", user_rate);
 
-        let active_grains = Arc::new(Mutex::new(Vec::<ActiveGrain>::new()));
-        let grains_arc = Arc::clone(&active_grains);
-        
-        let receiver_for_callback = Arc::clone(&self.synth.grain_receiver);
-
-        // For recording
-        let writer_for_callback = Arc::clone(&self.writer);
+        // Every layer's grain pool, buffers and envelopes are allocated here,
+        // not in the callback
+        let (mut layers, layer_changes) = self.layers.start(user_rate, num_channels);
+        let meters_for_callback = Arc::clone(&self.synth.meters);
+        let effects_for_callback = Arc::clone(&self.effects);
+        // Delay lines and reverb buffers are allocated here, not in the callback
+        let mut effect_chain = EffectChain::new(user_rate);
+        let channels = num_channels.max(1) as usize;
+        let params_for_callback = Arc::clone(&self.synth.params);
+        let master_gain_for_callback = Arc::clone(&self.master_gain);
+        let mut master_gain = SmoothedParam::new(self.master_gain.load());
+
+        // For recording: the callback only pushes into a preallocated ring
+        // buffer, the writer thread spawned by `record` does the file I/O.
+        let (mut record_producer, record_consumer) =
+            RingBuffer::<f32>::new(user_rate as usize * num_channels as usize);
+        *self.recorder.consumer.lock().unwrap() = Some(record_consumer);
+        if let Ok(spec) = self.recording_spec() {
+            self.recorder.set_spec(spec);
+        }
 
-        let is_recording_for_callback = self.is_recording.clone();
-        let is_recording_clone = Arc::clone(&is_recording_for_callback);
+        let is_recording_clone = Arc::clone(&self.recorder.is_recording);
 
         let stream = match output_device.build_output_stream(
             &config.clone().into(),
             move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                // 1. gather any newly scheduled grains
-                while let Ok(grain_data) = receiver_for_callback.try_recv() {
-                    let mut vec = grains_arc.lock().unwrap();
-                    vec.push(ActiveGrain::new(grain_data));
+                // 1. apply layer changes; removed layers go back to the
+                // control side to be freed there (the retired channel has
+                // room for every renderer, so none is dropped here)
+                while let Ok(layer) = layer_changes.added.try_recv() {
+                    if layers.len() < MAX_LAYERS {
+                        layers.push(layer);
+                    } else {
+                        let _ = layer_changes.retired.try_send(layer);
+                    }
+                }
+                while let Ok(id) = layer_changes.removed.try_recv() {
+                    if let Some(index) = layers.iter().position(|layer| layer.id() == id) {
+                        let _ = layer_changes.retired.try_send(layers.remove(index));
+                    }
                 }
 
-                // 2. fill the audio buffer
-                let mut grains = grains_arc.lock().unwrap();
-                for frame in data.chunks_mut(num_channels.into()) {
-                    let mut mix_sample = 0.0;
-                    for g in grains.iter_mut() {
-                        mix_sample += g.next_sample();
+                // 2. render every layer and sum them into the master bus
+                let (mode, time_ms) = params_for_callback.smoothing();
+                effect_chain.prepare(&effects_for_callback, mode, time_ms);
+                master_gain.configure(mode, time_ms, user_rate);
+                master_gain.set_target(master_gain_for_callback.load());
+                let any_solo = layers.iter().any(|layer| layer.control().is_soloed());
+                let (mut peak_l, mut peak_r) = (0.0f32, 0.0f32);
+                for block in data.chunks_mut(MAX_BLOCK_FRAMES * channels) {
+                    let frames = block.len() / channels;
+                    for layer in layers.iter_mut() {
+                        layer.render(frames, any_solo);
                     }
-                    for sample in frame.iter_mut() {
-                        *sample = mix_sample;
+                    for (i, frame) in block.chunks_mut(channels).enumerate() {
+                        let (mut left, mut right) = (0.0f32, 0.0f32);
+                        for layer in layers.iter() {
+                            let (l, r) = layer.frame(i);
+                            left += l;
+                            right += r;
+                        }
+                        let (left, right) = effect_chain.process(left, right);
+                        let gain_now = master_gain.tick();
+                        let (left, right) = (left * gain_now, right * gain_now);
+                        let mono = 0.5 * (left + right);
+                        peak_l = peak_l.max(left.abs());
+                        peak_r = peak_r.max(right.abs());
+                        for (ch, sample) in frame.iter_mut().enumerate() {
+                            *sample = match ch {
+                                _ if num_channels == 1 => mono,
+                                0 => left,
+                                1 => right,
+                                _ => mono,
+                            };
+                        }
                     }
                 }
-                grains.retain(|g| !g.is_finished());
-
-                let is_recording = is_recording_clone.lock().unwrap();
-                let mut guard = writer_for_callback.lock().unwrap();
+                for layer in layers.iter_mut() {
+                    layer.publish_peaks();
+                }
+                meters_for_callback.set_peaks(peak_l, peak_r);
 
                 // Recording
-                if *is_recording {
-                    if let Some(ref mut writer) = *guard {
-                        match &mut *writer {
-                            Writers::WavWriter(wav_writer) => {
-                                if let Err(e) = AudioEngine::write_wav_samples(wav_writer, data, bit_depth) {
-                                    eprintln!("Error writing WAV samples: {}", e);
-                                }
-                            },
+                if is_recording_clone.load(Ordering::Relaxed) {
+                    for &sample in data.iter() {
+                        if record_producer.push(sample).is_err() {
+                            break;
                         }
-                    } else {
-                        println!("writer_for_callback == None, so no actual recording possible");
                     }
                 }
             },
@@ -433,21 +962,20 @@ impl AudioEngine {
         if let Some(existing) = self.stream.take() {
             drop(existing);
         }
+        self.layers.stop();
     }
 
     // ----------------------
     // RECORDING
     // ----------------------
     pub fn record(&mut self, output_path: &str) -> Result<(), String> { 
-        let mut is_recording = self.is_recording.lock().unwrap();
-        if *is_recording {
-            return Err("Already recording!".to_string());
-        }
-        let mut guard = self.writer.lock().unwrap();
-        if guard.is_some() {
-            return Err("Already recording!".to_string());
-        }
+        let spec = self.recording_spec()?;
+        self.recorder.set_spec(spec);
+        self.recorder.start(output_path)
+    }
 
+    /// Resolves the user settings against the device defaults.
+    fn recording_spec(&self) -> Result<hound::WavSpec, String> {
         let final_sample_rate = match self.user_recording_settings.sample_rate {
             Some(rate) => rate,
             None => {
@@ -457,7 +985,6 @@ impl AudioEngine {
                     .unwrap_or(48000)
             }
         };
-        println!("Final sample rate: {}", final_sample_rate);
 
         let final_channels = if let Some(ch) = self.user_recording_settings.channels {
             ch
@@ -468,175 +995,486 @@ impl AudioEngine {
         };
 
         let final_bit_depth = self.user_recording_settings.bit_depth.unwrap_or(16);
-        println!("Final bit_depth: {}", final_bit_depth);
 
         let final_format = self.user_recording_settings
             .format
             .as_deref()
             .unwrap_or("wav");
-        println!("Final format: {}", final_format);
 
         match final_format {
-             "wav" => {
-                let spec = hound::WavSpec {
-                    channels: final_channels,
-                    sample_rate: final_sample_rate,
-                    bits_per_sample: final_bit_depth,
-                    sample_format: hound::SampleFormat::Int,
-                };
-                let file = File::create(output_path).map_err(|e| e.to_string())?;
-                let bw = BufWriter::new(file);
-                let wav_writer = hound::WavWriter::new(bw, spec)
-                    .map_err(|e| e.to_string())?;
-                 *guard = Some(Writers::WavWriter(wav_writer));
-            },
-            other => {
-                return Err(format!("Unsupported format for recording: {}", other));
-            },
-         };
+            "wav" => Ok(hound::WavSpec {
+                channels: final_channels,
+                sample_rate: final_sample_rate,
+                bits_per_sample: final_bit_depth,
+                sample_format: hound::SampleFormat::Int,
+            }),
+            other => Err(format!("Unsupported format for recording: {}", other)),
+        }
+    }
 
-         
-         *is_recording = true;
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        self.recorder.stop()
+    }
 
-         Ok(())
+    /// Handle for starting and stopping recordings from other threads.
+    pub fn recorder(&self) -> Recorder {
+        self.recorder.clone()
     }
 
-    pub fn stop_recording(&mut self) -> Result<(), String> {
-        let mut is_recording = self.is_recording.lock().unwrap();
-        if !*is_recording {
-            return Err("Not currently recording!".to_string());
-        }
-        // Finalize the writer
-        let mut guard = self.writer.lock().unwrap();
-        if let Some(writer) = guard.take() {
-            //let writer = std::mem::replace(&mut writer_arc, &mut Writers::WavWriter(dummy_placeholder()));
-            match writer {
-                Writers::WavWriter(wav_writer) => {
-                    wav_writer.finalize().map_err(|e| e.to_string())?;
-                },
-            }
-        }
+    // ----------------------
+    // MASTER EFFECTS
+    // ----------------------
+    /// Settings of the effects applied to the mix, before recording.
+    pub fn effects(&self) -> &MasterEffects {
+        &self.effects
+    }
 
-        *is_recording = false;
-        Ok(())
+    pub fn set_effect_order(&self, order: [Effect; FX_COUNT]) -> Result<(), String> {
+        self.effects.set_order(order)
     }
 
-    fn write_wav_samples(
-        wav_writer: &mut hound::WavWriter<std::io::BufWriter<std::fs::File>>,
-        data: &[f32],
-        bit_depth: u16,
-    ) -> Result<(), String> {
-        match bit_depth {
-            16 => {
-                for &sample in data.iter() {
-                    let sample_i16 = (sample * std::i16::MAX as f32) as i16;
-                    wav_writer.write_sample(sample_i16).map_err(|e| e.to_string())?;
-                }
-            }
-            24 => {
-                for &sample in data.iter() {
-                    let sample_i32 = (sample * (1 << 23) as f32) as i32; // Scale for 24-bit
-                    wav_writer.write_sample(sample_i32).map_err(|e| e.to_string())?;
-                }
-            }
-            32 => {
-                for &sample in data.iter() {
-                    let sample_i32 = (sample * std::i32::MAX as f32) as i32;
-                    wav_writer.write_sample(sample_i32).map_err(|e| e.to_string())?;
-                }
-            }
-            _ => return Err(format!("Unsupported bit depth: {}", bit_depth)),
-        }
-        Ok(())
+    pub fn set_effect_bypass(&self, effect: Effect, bypass: bool) {
+        self.effects.set_bypass(effect, bypass);
     }
 
-}
-// -------------------------------------
-// MAIN SYNTH STRUCT
-// -------------------------------------
-pub struct GranularSynth {
-    source_array: Arc<Mutex<Vec<f32>>>,
-    grain_env: Arc<Mutex<Vec<f32>>>,
-    grain_voices: Arc<Mutex<Vec<GrainVoice>>>,
-    params: Arc<Mutex<GrainParams>>,
-    counter: Arc<Mutex<usize>>,
-    should_stop: Arc<AtomicBool>,
-    pub grain_sender: Arc<Sender<Vec<f32>>>,
-    pub grain_receiver: Arc<Receiver<Vec<f32>>>,
-    thread_handle: Option<JoinHandle<()>>,
-}
-impl GranularSynth {
-    // Maybe add a function to set the numbrt of grain_voices
-    pub fn new(sample_rate: u32) -> Self {
-        let specs = Specs {
-            sample_rate: sample_rate,
-            channels: 2,
-            filesize: 0,
-        };
+    pub fn set_effect_mix(&self, effect: Effect, mix: f32) {
+        self.effects.set_mix(effect, mix);
+    }
 
-        let (s, r) = crossbeam_channel::unbounded();
+    pub fn master_gain(&self) -> f32 {
+        self.master_gain.load()
+    }
 
-        let mut grain_voices = Vec::new();
-        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
-        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
-        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
-        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
+    /// Output level after the master effects, applied to what is heard,
+    /// metered and recorded.
+    pub fn set_master_gain(&self, gain: f32) {
+        self.master_gain.store(gain.clamp(0.0, 2.0));
+    }
 
-        Self {
-            source_array: Arc::new(Mutex::new(vec![])),
-            grain_env: Arc::new(Mutex::new(vec![])),
-            grain_voices: Arc::new(Mutex::new(grain_voices)),
-            params: Arc::new(Mutex::new(GrainParams {
-                grain_start: 0.0,
-                grain_duration: 100,
+    // ----------------------
+    // LAYERS
+    // ----------------------
+    /// Adds a layer playing `synth` next to the engine's own synth (layer
+    /// `MAIN_LAYER`) and returns its id. The synth keeps its own source,
+    /// parameters, gain, pan and scheduler. Works while the stream runs.
+    pub fn add_layer(&mut self, synth: Arc<GranularSynth>) -> Result<usize, String> {
+        self.layers.add(synth)
+    }
+
+    /// Removes a layer, stopping its stem recording.
+    pub fn remove_layer(&mut self, id: usize) -> Result<(), String> {
+        self.layers.remove(id)
+    }
+
+    pub fn layer_ids(&self) -> Vec<usize> {
+        self.layers.layers().iter().map(|layer| layer.id()).collect()
+    }
+
+    pub fn layer_synth(&self, id: usize) -> Result<Arc<GranularSynth>, String> {
+        self.layers.get(id).map(|layer| Arc::clone(layer.synth()))
+    }
+
+    pub fn set_layer_mute(&self, id: usize, muted: bool) -> Result<(), String> {
+        self.layers.get(id)?.control().set_muted(muted);
+        Ok(())
+    }
+
+    /// While any layer is soloed only soloed layers are heard.
+    pub fn set_layer_solo(&self, id: usize, soloed: bool) -> Result<(), String> {
+        self.layers.get(id)?.control().set_soloed(soloed);
+        Ok(())
+    }
+
+    /// Records one layer, after its gain, pan and mute/solo and before the
+    /// master effects, in the same format as `record`.
+    pub fn record_layer(&mut self, id: usize, output_path: &str) -> Result<(), String> {
+        let spec = self.recording_spec()?;
+        let stem = self.layers.get(id)?.stem();
+        stem.set_spec(spec);
+        stem.start(output_path)
+    }
+
+    pub fn stop_layer_recording(&mut self, id: usize) -> Result<(), String> {
+        self.layers.get(id)?.stem().stop()
+    }
+
+    // ----------------------
+    // LIVE INPUT
+    // ----------------------
+    pub fn get_input_devices(&self) -> Vec<(usize, String)> {
+        let host = cpal::default_host();
+        let mut results = Vec::new();
+        let devices = match host.input_devices() {
+            Ok(devs) => devs,
+            Err(e) => {
+                eprintln!("Failed to get input devices: {}", e);
+                return results;
+            }
+        };
+        for (index, device) in devices.enumerate() {
+            let name = device.name().unwrap_or("Unknown".to_string());
+            results.push((index, name));
+        }
+        results
+    }
+
+    /// Takes effect the next time live input is started.
+    pub fn set_input_device_by_index(&mut self, index: usize) -> Result<(), String> {
+        let host = cpal::default_host();
+        let mut devices = host.input_devices().map_err(|e| e.to_string())?;
+        let dev = devices.nth(index).ok_or("Invalid device index")?;
+        self.input_device = Some(dev);
+        Ok(())
+    }
+
+    pub fn set_default_input_device(&mut self) -> Result<(), String> {
+        let host = cpal::default_host();
+        let default_dev = host
+            .default_input_device()
+            .ok_or("No default input device found!")?;
+        self.input_device = Some(default_dev);
+        Ok(())
+    }
+
+    /// Opens the input device (the default one if none was chosen) at the
+    /// master sample rate and writes it into the main synth's live buffer,
+    /// which grains read once `set_live_input_enabled` is on. Restarts a
+    /// running input stream.
+    pub fn start_live_input(&mut self) -> Result<(), String> {
+        self.stop_live_input();
+        if self.input_device.is_none() {
+            self.set_default_input_device()?;
+        }
+        let input_device = self.input_device.as_ref().ok_or("No input device set")?;
+        let channels = input_device
+            .default_input_config()
+            .map(|c| c.channels())
+            .unwrap_or(1)
+            .max(1);
+        let sample_rate = self.get_master_sample_rate();
+        let config = cpal::StreamConfig {
+            channels,
+            sample_rate: cpal::SampleRate(sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+
+        // The buffer is allocated here, never in the callback, and sized
+        // for this stream's rate
+        let buffer = self.synth.live_input.buffer_for(sample_rate);
+        let live_input = Arc::clone(&self.synth.live_input);
+        let stream = input_device
+            .build_input_stream(
+                &config,
+                move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                    if live_input.is_frozen() {
+                        return;
+                    }
+                    for frame in data.chunks(channels as usize) {
+                        buffer.write_frame(frame);
+                    }
+                },
+                move |err| {
+                    eprintln!("Input stream error: {}", err);
+                },
+                None,
+            )
+            .map_err(|e| format!("Failed to build input stream: {}", e))?;
+        stream
+            .play()
+            .map_err(|e| format!("Failed to play input stream: {}", e))?;
+        self.input_stream = Some(stream);
+        Ok(())
+    }
+
+    pub fn stop_live_input(&mut self) {
+        if let Some(existing) = self.input_stream.take() {
+            drop(existing);
+        }
+    }
+
+    // ----------------------
+    // REMOTE CONTROL
+    // ----------------------
+    /// Starts the OSC server on `bind_addr` (e.g. `"0.0.0.0:9000"`),
+    /// replacing a running one. Returns the address actually bound.
+    pub fn start_osc_server(&mut self, bind_addr: &str) -> Result<std::net::SocketAddr, String> {
+        self.stop_osc_server();
+        let server = OscServer::start(bind_addr, Arc::clone(&self.synth), self.recorder())?;
+        let local_addr = server.local_addr();
+        self.osc_server = Some(server);
+        Ok(local_addr)
+    }
+
+    pub fn stop_osc_server(&mut self) {
+        if let Some(mut server) = self.osc_server.take() {
+            server.stop();
+        }
+    }
+
+    /// Body of the writer thread: moves samples from the ring buffer into
+    /// the file until recording stops, then finalizes it.
+    fn drain_recording(
+        mut writer: Writers,
+        consumer: Arc<Mutex<Option<Consumer<f32>>>>,
+        is_recording: Arc<AtomicBool>,
+        bit_depth: u16,
+    ) -> Result<(), String> {
+        let mut chunk = Vec::new();
+        loop {
+            // Read the flag before draining so the last pass picks up
+            // everything pushed before recording stopped.
+            let recording = is_recording.load(Ordering::SeqCst);
+            chunk.clear();
+            if let Some(consumer) = consumer.lock().unwrap().as_mut() {
+                while let Ok(sample) = consumer.pop() {
+                    chunk.push(sample);
+                }
+            }
+            match &mut writer {
+                Writers::WavWriter(wav_writer) => {
+                    AudioEngine::write_wav_samples(wav_writer, &chunk, bit_depth)?;
+                },
+            }
+            if !recording {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        match writer {
+            Writers::WavWriter(wav_writer) => {
+                wav_writer.finalize().map_err(|e| e.to_string())
+            },
+        }
+    }
+
+
+    fn write_wav_samples(
+        wav_writer: &mut hound::WavWriter<std::io::BufWriter<std::fs::File>>,
+        data: &[f32],
+        bit_depth: u16,
+    ) -> Result<(), String> {
+        match bit_depth {
+            16 => {
+                for &sample in data.iter() {
+                    let sample_i16 = (sample * std::i16::MAX as f32) as i16;
+                    wav_writer.write_sample(sample_i16).map_err(|e| e.to_string())?;
+                }
+            }
+            24 => {
+                for &sample in data.iter() {
+                    let sample_i32 = (sample * (1 << 23) as f32) as i32; // Scale for 24-bit
+                    wav_writer.write_sample(sample_i32).map_err(|e| e.to_string())?;
+                }
+            }
+            32 => {
+                for &sample in data.iter() {
+                    let sample_i32 = (sample * std::i32::MAX as f32) as i32;
+                    wav_writer.write_sample(sample_i32).map_err(|e| e.to_string())?;
+                }
+            }
+            _ => return Err(format!("Unsupported bit depth: {}", bit_depth)),
+        }
+        Ok(())
+    }
+
+}
+// -------------------------------------
+// MAIN SYNTH STRUCT
+// -------------------------------------
+pub struct GranularSynth {
+    // Immutable snapshot swapped whole, so the audio thread reads it
+    // without locking
+    grain_env: Arc<ArcSwap<Arc<[f32]>>>,
+    // Template voices; the scheduler thread works on its own copy
+    grain_voices: Vec<GrainVoice>,
+    params: Arc<SharedParams>,
+    counter: Arc<AtomicUsize>,
+    modulation: Arc<Modulation>,
+    cloud_envelope: Arc<CloudEnvelope>,
+    midi: Arc<MidiState>,
+    cc_mapper: Arc<Mutex<CcMapper>>,
+    meters: Arc<Meters>,
+    transport: Arc<Transport>,
+    grain_filter: Arc<GrainFilterSettings>,
+    oscillator: Arc<OscillatorSettings>,
+    glisson: Arc<GlissonSettings>,
+    spectral: Arc<SpectralSettings>,
+    // STFT of the source, computed while the spectral mode is on
+    spectral_frames: Arc<ArcSwap<SpectralFrames>>,
+    // Pitch marks of the source, computed while the PSOLA mode is on
+    pitch_marks: Arc<ArcSwap<PitchMarks>>,
+    descriptors: Arc<DescriptorSettings>,
+    // Descriptors of the source, computed while descriptor selection is on
+    corpus: Arc<Mutex<Corpus>>,
+    live_input: Arc<LiveInput>,
+    region: Arc<SourceRegion>,
+    bank: Arc<SourceBank>,
+    loader: Arc<Loader>,
+    // Single cycle read by `Waveform::Wavetable` grains, an immutable
+    // snapshot like the grain envelope
+    wavetable: Arc<ArcSwap<Arc<[f32]>>>,
+    // The connection is not `Sync`; the lock keeps `GranularSynth` shareable
+    midi_connection: Mutex<Option<MidiInputConnection<()>>>,
+    should_stop: Arc<AtomicBool>,
+    pub grain_sender: Arc<Sender<Grain>>,
+    pub grain_receiver: Arc<Receiver<Grain>>,
+    // Shared by every clone so any of them can stop the scheduler
+    thread_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+impl GranularSynth {
+    // Maybe add a function to set the numbrt of grain_voices
+    pub fn new(sample_rate: u32) -> Self {
+        let specs = Specs {
+            sample_rate: sample_rate,
+            channels: 2,
+            filesize: 0,
+        };
+
+        let (s, r) = crossbeam_channel::bounded(MAX_ACTIVE_GRAINS);
+
+        let mut grain_voices = Vec::new();
+        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
+        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
+        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
+        grain_voices.push(GrainVoice::new(0.0, 1.0, 1.0));
+
+        Self {
+            grain_env: Arc::new(ArcSwap::from_pointee(Arc::from(Vec::new()))),
+            grain_voices,
+            params: Arc::new(SharedParams::new(GrainParams {
+                grain_start: 0.0,
+                grain_duration: 100,
                 grain_overlap: 2.0,
                 grain_pitch: 1.0,
+                grain_amplitude: 1.0,
+                grain_pan: 0.0,
                 specs: specs,
             })),
-            counter: Arc::new(Mutex::new(0)),
+            counter: Arc::new(AtomicUsize::new(0)),
+            modulation: Arc::new(Modulation::new()),
+            cloud_envelope: Arc::new(CloudEnvelope::new()),
+            midi: Arc::new(MidiState::new()),
+            cc_mapper: Arc::new(Mutex::new(CcMapper::new())),
+            meters: Arc::new(Meters::new()),
+            transport: Arc::new(Transport::new()),
+            grain_filter: Arc::new(GrainFilterSettings::new()),
+            oscillator: Arc::new(OscillatorSettings::new()),
+            glisson: Arc::new(GlissonSettings::new()),
+            spectral: Arc::new(SpectralSettings::new()),
+            spectral_frames: Arc::new(ArcSwap::from_pointee(SpectralFrames::empty())),
+            pitch_marks: Arc::new(ArcSwap::from_pointee(PitchMarks::empty())),
+            descriptors: Arc::new(DescriptorSettings::new()),
+            corpus: Arc::new(Mutex::new(Corpus::empty())),
+            live_input: Arc::new(LiveInput::new()),
+            region: Arc::new(SourceRegion::new()),
+            bank: Arc::new(SourceBank::new()),
+            loader: Arc::new(Loader::new()),
+            wavetable: Arc::new(ArcSwap::from_pointee(Arc::from(Vec::new()))),
+            midi_connection: Mutex::new(None),
             should_stop: Arc::new(AtomicBool::new(false)),
             grain_sender: Arc::new(s),
             grain_receiver: Arc::new(r), 
-            thread_handle: None,
+            thread_handle: Arc::new(Mutex::new(None)),
         }
     }
 
     pub fn calculate_metro_time_in_ms(&self) -> f32 {
-        let params = self.params.lock().unwrap();
+        let params = self.params.snapshot();
         let interval_ms = params.grain_duration as f32 / params.grain_overlap;
         interval_ms
     }
 
-    pub fn start_scheduler(&mut self) {
+    /// Does nothing if the scheduler is already running.
+    pub fn start_scheduler(&self) {
+        let mut thread_handle = self.thread_handle.lock().unwrap();
+        if thread_handle.as_ref().is_some_and(|h| !h.is_finished()) {
+            return;
+        }
         let synth_clone = self.clone_for_thread(); 
         self.should_stop.store(false, Ordering::SeqCst);
         let handle = thread::spawn(move || {
-            let metro_time = synth_clone.calculate_metro_time_in_ms();
-            let interval = Duration::from_millis(metro_time as u64);
-            let mut next_time = Instant::now();
+            let mut voices = synth_clone.grain_voices.clone();
+            let mut smoothers = GrainSmoothers::new(&synth_clone.params.snapshot());
+            let mut modulation = ModState::new();
+            let mut last_time = Instant::now();
+            // Next onset of every cloud: 0 is the free-running cloud, 1.. the
+            // MIDI note voices
+            let mut next_times = [Instant::now(); 1 + MAX_POLYPHONY];
+            let mut seen_triggers = [0u32; MAX_POLYPHONY];
+            // With tempo sync every cloud plays on the transport's steps
+            let transport = &synth_clone.transport;
+            let mut step_clock = StepClock::new(transport);
+            let mut last_sync = transport.sync();
             // mientras sea falso
             while !synth_clone.should_stop.load(Ordering::SeqCst) {
                 let now = Instant::now();
-                if now >= next_time {
-                    synth_clone.route_to_grainvoice(
-                        &synth_clone.get_source_array(),
-                        &synth_clone.get_grain_envelope(),
-                    );
-                    synth_clone.increment_counter();
-                    // Schedule next event
-                    next_time = now + interval;
-                } else {
+                let midi = &synth_clone.midi;
+                let sync = transport.sync();
+                if sync != last_sync {
+                    last_sync = sync;
+                    step_clock.reset();
+                }
+                let step_hit = match sync {
+                    TempoSync::Off => None,
+                    _ => Some(step_clock.poll(transport, now) == Some(true)),
+                };
+                let mut due = [false; 1 + MAX_POLYPHONY];
+                due[0] = !midi.is_midi_mode() && step_hit.unwrap_or(now >= next_times[0]);
+                for (i, voice) in midi.voices().iter().enumerate() {
+                    if !voice.is_active() {
+                        continue;
+                    }
+                    // A new note starts its cloud right away (on the next
+                    // step when synced)
+                    let triggers = voice.triggers();
+                    if triggers != seen_triggers[i] {
+                        seen_triggers[i] = triggers;
+                        next_times[i + 1] = now;
+                    }
+                    due[i + 1] = step_hit.unwrap_or(now >= next_times[i + 1]);
+                }
+                if !due.contains(&true) {
                     thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+
+                let sample_rate = synth_clone.params.specs().sample_rate;
+                let elapsed = (now - last_time).as_secs_f32();
+                last_time = now;
+                synth_clone.bank.advance_crossfade(elapsed);
+                let mut params = smoothers.update(
+                    &synth_clone.params,
+                    (elapsed * sample_rate as f32) as usize,
+                );
+                modulation.apply(&synth_clone.modulation, elapsed, &mut params);
+                // Schedule next event, picking up any new duration/overlap
+                let metro_time = params.grain_duration as f32 / params.grain_overlap;
+                let expected_overlap = match sync {
+                    TempoSync::Off => params.grain_overlap,
+                    _ => params.grain_duration as f32 / transport.step_ms() * transport.hit_ratio(),
+                };
+                params.grain_amplitude *= synth_clone.params.gain_compensation(expected_overlap);
+
+                for cloud in (0..due.len()).filter(|&c| due[c]) {
+                    let mut cloud_params = params;
+                    if cloud > 0 {
+                        let voice = &midi.voices()[cloud - 1];
+                        cloud_params.grain_pitch *= midi.transpose(voice.note());
+                        cloud_params.grain_amplitude *= voice.velocity();
+                    }
+                    synth_clone.route_to_grainvoice(&mut voices, &cloud_params, cloud as u8);
+                    synth_clone.increment_counter();
+                    next_times[cloud] = now + Duration::from_millis(metro_time as u64);
                 }
             }
         });
-        self.thread_handle = Some(handle);
+        *thread_handle = Some(handle);
     }
 
     /// We could also drop the Arc if we wanted.
-    pub fn stop_scheduler(&mut self) {
+    pub fn stop_scheduler(&self) {
         self.should_stop.store(true, Ordering::SeqCst);
-        if let Some(handle) = self.thread_handle.take() {
+        if let Some(handle) = self.thread_handle.lock().unwrap().take() {
             let _ = handle.join();
         }
     }
@@ -646,46 +1484,114 @@ impl GranularSynth {
     fn clone_for_thread(&self) -> GranularSynth {
         //let (new_sender, new_receiver) = crossbeam_channel::unbounded();
         GranularSynth {
-            source_array: Arc::clone(&self.source_array),
             grain_env: Arc::clone(&self.grain_env),
-            grain_voices: Arc::clone(&self.grain_voices),
+            grain_voices: self.grain_voices.clone(),
             params: Arc::clone(&self.params),
             counter: Arc::clone(&self.counter),
+            modulation: Arc::clone(&self.modulation),
+            cloud_envelope: Arc::clone(&self.cloud_envelope),
+            midi: Arc::clone(&self.midi),
+            cc_mapper: Arc::clone(&self.cc_mapper),
+            meters: Arc::clone(&self.meters),
+            transport: Arc::clone(&self.transport),
+            grain_filter: Arc::clone(&self.grain_filter),
+            oscillator: Arc::clone(&self.oscillator),
+            glisson: Arc::clone(&self.glisson),
+            spectral: Arc::clone(&self.spectral),
+            spectral_frames: Arc::clone(&self.spectral_frames),
+            pitch_marks: Arc::clone(&self.pitch_marks),
+            descriptors: Arc::clone(&self.descriptors),
+            corpus: Arc::clone(&self.corpus),
+            live_input: Arc::clone(&self.live_input),
+            region: Arc::clone(&self.region),
+            bank: Arc::clone(&self.bank),
+            loader: Arc::clone(&self.loader),
+            wavetable: Arc::clone(&self.wavetable),
+            midi_connection: Mutex::new(None),
             should_stop: Arc::clone(&self.should_stop),
             grain_receiver: Arc::clone(&self.grain_receiver),
             grain_sender: Arc::clone(&self.grain_sender),
-            thread_handle: None,
+            thread_handle: Arc::clone(&self.thread_handle),
         }
     }
 
     pub fn increment_counter(&self) {
-        let mut counter = self.counter.lock().unwrap();
-        *counter = (*counter + 1) % 4;
+        let num_voices = self.grain_voices.len();
+        let _ = self.counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+            Some((c + 1) % num_voices)
+        });
     }
 
     pub fn route_to_grainvoice(
         &self,
-        source_array: &[f32],
-        grain_env: &[f32],
-        ) {
+        voices: &mut [GrainVoice],
+        params: &GrainParams,
+        cloud: u8,
+    ) {
         let (r_a, r_b) = Self::generate_random_parameters();
-        let counter = self.counter.lock().unwrap();
-
-        {
-            let mut voices = self.grain_voices.lock().unwrap();
-            let voice = &mut voices[*counter];
-            voice.mystart = r_a;
-            voice.mypitch = r_b;
-            voice.mydur = 1.0;
+        let counter = self.counter.load(Ordering::Relaxed) % voices.len();
+
+        let voice = &mut voices[counter];
+        voice.mystart = r_a;
+        voice.mypitch = r_b;
+        voice.mydur = 1.0;
+
+        // Each grain reads one source of the bank; the grain start spans the
+        // source region of that source rather than the main file
+        let source = self.bank.pick();
+        let filesize = params.specs.filesize;
+        let source_size = if source == 0 { filesize } else { self.bank.filesize(source) };
+        let bounds = self.region.bounds(source_size);
+        let mut params = *params;
+        if let Some(bounds) = bounds {
+            let normalized = if filesize > 0 { params.grain_start / filesize as f32 } else { 0.0 };
+            params.grain_start = bounds.map(normalized);
         }
-
-        let voices = self.grain_voices.lock().unwrap();
-        let params = self.params.lock().unwrap();
-        let grain_data = voices[*counter]
-            .process_grain(source_array, grain_env, &params);
-        //eprintln!("Grain data length = {}", grain_data.len());
-
-        self.grain_sender.send(grain_data).ok();
+        let mut grain = voice.grain(&params);
+        grain.source = source as u8;
+        grain.generation = self.bank.generation(source);
+        // Descriptors describe the main source only
+        if source == 0 && self.descriptors.is_enabled() {
+            let target = self.descriptors.target();
+            let candidates = self.descriptors.candidates();
+            let range = bounds.map_or(0..usize::MAX, |b| b.start as usize..b.end as usize);
+            if let Some(start) = self.corpus.lock().unwrap().select(&target, candidates, range) {
+                grain.source_start = start as f32;
+            }
+        }
+        grain.glide = self.glisson.random_glide();
+        let sample_rate = params.specs.sample_rate;
+        if let Some(start) = self.live_input.grain_start(grain.length, grain.playback_rate, sample_rate) {
+            grain.source_start = start;
+            grain.live = true;
+        } else {
+            // Source samples the grain reads, glide included; the region may
+            // shorten the grain so it fits
+            if let Some(bounds) = bounds {
+                let reach = |grain: &Grain| {
+                    let offset = grain.glide.map_or(grain.length as f32, |glide| {
+                        glide.offset_at(grain.length, grain.length)
+                    });
+                    offset * grain.playback_rate
+                };
+                grain.length = bounds.grain_length(grain.length, reach(&grain));
+                grain.source_start = bounds.grain_start(grain.source_start, reach(&grain));
+                grain.bounds = Some(bounds);
+            }
+            // The playhead follows the final start of file grains, after the
+            // descriptor selection and the region; live grains leave it alone
+            let position = if source_size > 0 { grain.source_start / source_size as f32 } else { 0.0 };
+            self.meters.set_playhead(position.clamp(0.0, 1.0));
+        }
+        grain.cloud = cloud;
+        grain.filter = self.grain_filter.random_filter(params.specs.sample_rate);
+        grain.oscillator = self
+            .oscillator
+            .grain_oscillator(grain.playback_rate, params.specs.sample_rate);
+
+        // A full channel means the pool is saturated; drop the grain rather
+        // than block the scheduler.
+        self.grain_sender.try_send(grain).ok();
     }
 
     pub fn generate_random_parameters() -> (f32, f32) {
@@ -708,54 +1614,119 @@ impl GranularSynth {
         };
         let file_path_str = std::str::from_utf8(file_path_slice).unwrap_or("");
 
-        match hound::WavReader::open(file_path_str) {
-            Ok(mut reader) => {
-                let spec = reader.spec();
-                let input_sample_rate = spec.sample_rate; // e.g. 48000
-                let input_channels = spec.channels;
-
-                let float_samples: Vec<f32> = reader
-                    .samples::<i16>()
-                    .filter_map(|s| s.ok())
-                    .map(|sample_i16| sample_i16 as f32 / 32768.0)
-                    .collect();
-
-                let final_samples = resample_to_master(
-                    &float_samples,
-                    input_channels,
-                    input_sample_rate,
-                    master_rate,
-                );
+        let never = AtomicBool::new(false);
+        let decoded = Self::decode_file(file_path_str, master_rate);
+        match decoded.and_then(|(samples, channels)| self.install_source(0, samples, channels, master_rate, &never)) {
+            Ok(_) => 0,
+            Err(_) => -1,
+        }
+    }
 
-                let filesize = final_samples.len();
-                let mut params = self.params.lock().unwrap();
-                params.specs.filesize = filesize;
-                params.specs.sample_rate = master_rate;
-                params.specs.channels = input_channels;
-                drop(params);
+    /// Reads a WAV file and resamples it to `master_rate`; returns the
+    /// samples (interleaved as in the file) and the channel count.
+    fn decode_file(path: &str, master_rate: u32) -> Result<(Vec<f32>, u16), String> {
+        let never = AtomicBool::new(false);
+        loader::decode_file(path, master_rate, &never, &mut |_| {}).map(Option::unwrap_or_default)
+    }
 
-                let mut buffer = self.source_array.lock().unwrap();
-                *buffer = final_samples;
+    /// Swaps decoded samples into bank slot `slot` and returns its new
+    /// generation, or `None` once `cancel` is raised. Grains already playing
+    /// finish on the old buffer. The main source (slot 0) is analyzed before
+    /// the swap, so a cancelled load leaves the old analyses in place, and
+    /// also sets the specs.
+    fn install_source(
+        &self,
+        slot: usize,
+        samples: Vec<f32>,
+        channels: u16,
+        master_rate: u32,
+        cancel: &AtomicBool,
+    ) -> Result<Option<u32>, String> {
+        let mode = self.params.processing_mode();
+        let descriptors = self.descriptors.is_enabled();
+        let analyses = if slot == 0 {
+            match self.analyze_source(&samples, master_rate, mode, descriptors, cancel) {
+                Some(analyses) => Some(analyses),
+                None => return Ok(None),
+            }
+        } else {
+            None
+        };
 
-                0
+        let _swap = self.loader.swap_lock();
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let filesize = samples.len();
+        let generation = self.swap_source(slot, samples)?;
+        if let Some((frames, marks, corpus)) = analyses {
+            self.params.set_specs(Specs {
+                sample_rate: master_rate,
+                channels,
+                filesize,
+            });
+            // A mode switched during the analyses redoes its analysis on
+            // the new source
+            loader::retire(self.spectral_frames.swap(Arc::new(frames)));
+            loader::retire(self.pitch_marks.swap(Arc::new(marks)));
+            *self.corpus.lock().unwrap() = corpus;
+            if self.params.processing_mode() != mode {
+                self.refresh_spectral_frames();
+                self.refresh_pitch_marks();
+            }
+            if self.descriptors.is_enabled() != descriptors {
+                self.refresh_corpus();
             }
-            Err(_) => -1,
         }
+        Ok(Some(generation))
+    }
+
+    /// Every analysis of a main source, as `install_source` publishes them.
+    fn analyze_source(
+        &self,
+        source: &[f32],
+        sample_rate: u32,
+        mode: ProcessingMode,
+        descriptors: bool,
+        cancel: &AtomicBool,
+    ) -> Option<(SpectralFrames, PitchMarks, Corpus)> {
+        Some((
+            self.analyze_spectral_frames(source, mode, cancel)?,
+            self.analyze_pitch_marks(source, sample_rate, mode, cancel)?,
+            self.analyze_corpus(source, sample_rate, descriptors, cancel)?,
+        ))
+    }
+
+    /// Swaps `samples` into bank slot `slot`; the buffer it replaces is
+    /// freed once the grains still reading it are done.
+    fn swap_source(&self, slot: usize, samples: Vec<f32>) -> Result<u32, String> {
+        let generation = self.bank.set_source(slot, samples)?;
+        loader::release_when_unused(Arc::clone(&self.bank), slot, generation);
+        Ok(generation)
     }
     pub fn generate_grain_envelope(&self, size: usize) {
-        let mut env = self.grain_env.lock().unwrap();
-        env.clear();
+        let mut env = Vec::with_capacity(size);
         for i in 0..size {
             let x = (i as f32 / size as f32) * 2.0 - 1.0;
             let value = 0.5 + (0.5 * (x * PI).cos());
             env.push(value);
         }
+        if size > 0 {
+            let energy = env.iter().map(|v| v * v).sum::<f32>() / size as f32;
+            self.params.set_envelope_energy(energy);
+        }
+        loader::retire(self.grain_env.swap(Arc::new(Arc::from(env))));
+    }
+    /// The main source, shared rather than copied.
+    pub fn source(&self) -> Arc<[f32]> {
+        self.bank.current(0)
     }
+    /// A copy of the main source, for callers that keep it.
     pub fn get_source_array(&self) -> Vec<f32> {
-        self.source_array.lock().unwrap().clone()
+        self.source().to_vec()
     }
     pub fn get_grain_envelope(&self) -> Vec<f32> {
-        self.grain_env.lock().unwrap().clone()
+        self.grain_env.load().to_vec()
     }
     pub fn set_params(
         &self, 
@@ -763,84 +1734,863 @@ impl GranularSynth {
         duration: usize, 
         overlap: f32,
         pitch: f32) {
-        let mut params = self.params.lock().unwrap();
-        params.grain_start = start.clamp(0.0, 1.0) as f32 * params.specs.filesize as f32;
-        params.grain_duration = duration;
-        params.grain_overlap = overlap.clamp(1.0, 2.0) as f32;
-        params.grain_pitch = pitch.clamp(0.1, 2.0) as f32;
+        self.params.set_grain_start(start);
+        self.params.set_grain_duration(duration);
+        self.params.set_grain_overlap(overlap);
+        self.params.set_grain_pitch(pitch);
     }
-}
 
-// -------------------------------------
-// ACTIVE GRAIN HELPER STRUCT
-// -------------------------------------
-pub struct ActiveGrain {
-    samples: Vec<f32>,
-    position: usize,
-}
-impl ActiveGrain {
-    fn new(samples: Vec<f32>) -> Self {
-        Self {
-            samples,
-            position: 0,
-        }
+    // ----------------------
+    // MODULATION
+    // ----------------------
+    pub fn set_lfo(&self, index: usize, shape: LfoShape, rate_hz: f32) -> Result<(), String> {
+        self.modulation.set_lfo(index, shape, rate_hz)
     }
 
-    fn is_finished(&self) -> bool {
-        self.position >= self.samples.len()
+    pub fn set_envelope_follower(
+        &self,
+        index: usize,
+        attack_ms: f32,
+        release_ms: f32,
+    ) -> Result<(), String> {
+        self.modulation.set_envelope_follower(index, attack_ms, release_ms)
     }
 
-    fn next_sample(&mut self) -> f32 {
-        if self.is_finished() {
-            0.0
-        } else {
-            let s = self.samples[self.position];
-            self.position += 1;
-            s
-        }
+    /// Routes `source` to `target` with `depth`; returns the route index.
+    pub fn add_mod_route(
+        &self,
+        source: ModSource,
+        target: ModTarget,
+        depth: f32,
+    ) -> Result<usize, String> {
+        self.modulation.add_route(ModRoute { source, target, depth })
     }
 
-}
-// -------------------------------------
-// HELPER FUNCTIONS
-// -------------------------------------
+    pub fn remove_mod_route(&self, index: usize) -> Result<(), String> {
+        self.modulation.remove_route(index)
+    }
 
-fn linear_interpolation(buffer: &[f32], x: f32) -> f32 {
-    // x is the fractional index. E.g. 12.3 => index0=12, index1=13, frac=0.3
-    let index0 = x.floor() as usize;
-    let index1 = index0 + 1;
-    let frac = x - (index0 as f32);
+    pub fn clear_mod_routes(&self) {
+        self.modulation.clear_routes();
+    }
 
-    let s0 = if index0 < buffer.len() { buffer[index0] } else { 0.0 };
-    let s1 = if index1 < buffer.len() { buffer[index1] } else { 0.0 };
+    // ----------------------
+    // CLOUD ENVELOPE
+    // ----------------------
+    pub fn set_adsr(&self, attack_ms: f32, decay_ms: f32, sustain: f32, release_ms: f32) {
+        self.cloud_envelope
+            .set_shape(&EnvelopeShape::adsr(attack_ms, decay_ms, sustain, release_ms));
+    }
 
-    let out = s0 + (s1 - s0) * frac;
-    out
-}
+    /// `segments` are (level, time in ms) pairs; the envelope holds at the
+    /// end of segment `sustain` until gate-off.
+    pub fn set_envelope_segments(
+        &self,
+        segments: &[(f32, f32)],
+        sustain: Option<usize>,
+        release_ms: f32,
+    ) -> Result<(), String> {
+        if segments.is_empty() || segments.len() > MAX_ENVELOPE_SEGMENTS {
+            return Err(format!(
+                "Envelope needs between 1 and {} segments", MAX_ENVELOPE_SEGMENTS
+            ));
+        }
+        let mut shape = EnvelopeShape::adsr(0.0, 0.0, 0.0, release_ms);
+        for (i, &(level, time_ms)) in segments.iter().enumerate() {
+            shape.levels[i] = level;
+            shape.times_ms[i] = time_ms;
+        }
+        shape.count = segments.len();
+        shape.sustain = sustain;
+        self.cloud_envelope.set_shape(&shape);
+        Ok(())
+    }
 
-fn sinc(x: f32) -> f32 {
-    if x == 0.0 {
-        1.0
-    } else {
-        (x * std::f32::consts::PI).sin() / (x * std::f32::consts::PI)
+    /// With the envelope disabled the cloud plays at full level. Enabling it
+    /// doesn't start it: the cloud is silent until the next `gate_on`.
+    pub fn set_cloud_envelope_enabled(&self, enabled: bool) {
+        self.cloud_envelope.set_enabled(enabled);
     }
-}
 
-fn sinc_interpolation(buffer: &[f32], x: f32) -> f32 {
-    let n = buffer.len() as isize;
-    let i = x.floor() as isize;
-    let frac = x - i as f32;
+    pub fn gate_on(&self) {
+        self.cloud_envelope.gate_on();
+    }
 
-    let mut result = 0.0;
-    let mut normalization = 0.0;
+    pub fn gate_off(&self) {
+        self.cloud_envelope.gate_off();
+    }
 
-    for j in -2..=2 {
-        let index = i + j;
-        if index >= 0 && index < n {
-            let sample = buffer[index as usize];
-            let sinc_value = sinc(frac - j as f32);
-            result += sample * sinc_value;
-            normalization += sinc_value;
+    // ----------------------
+    // MIDI
+    // ----------------------
+    /// Feeds one raw MIDI message to the synth, as if it came from a port.
+    /// Returns `false` if the message is not one we handle.
+    pub fn handle_midi_message(&self, bytes: &[u8]) -> bool {
+        match midi::parse_midi_message(bytes) {
+            Some(event) => {
+                handle_midi_event(
+                    event,
+                    &self.midi,
+                    &self.cc_mapper,
+                    &self.params,
+                    &self.transport,
+                )
+            }
+            None => false,
+        }
+    }
+
+    fn midi_handler(&self) -> impl FnMut(&[u8]) + Send + 'static {
+        let midi_state = Arc::clone(&self.midi);
+        let cc_mapper = Arc::clone(&self.cc_mapper);
+        let params = Arc::clone(&self.params);
+        let transport = Arc::clone(&self.transport);
+        move |bytes: &[u8]| {
+            if let Some(event) = midi::parse_midi_message(bytes) {
+                handle_midi_event(event, &midi_state, &cc_mapper, &params, &transport);
+            }
+        }
+    }
+
+    /// Opens MIDI input port `index` (see `midi::get_midi_input_ports`),
+    /// replacing any open connection.
+    pub fn open_midi_input(&self, index: usize) -> Result<(), String> {
+        self.close_midi_input();
+        let connection = midi::connect_midi_input(index, self.midi_handler())?;
+        *self.midi_connection.lock().unwrap() = Some(connection);
+        Ok(())
+    }
+
+    pub fn open_virtual_midi_input(&self, port_name: &str) -> Result<(), String> {
+        self.close_midi_input();
+        let connection = midi::create_virtual_midi_input(port_name, self.midi_handler())?;
+        *self.midi_connection.lock().unwrap() = Some(connection);
+        Ok(())
+    }
+
+    pub fn close_midi_input(&self) {
+        if let Some(connection) = self.midi_connection.lock().unwrap().take() {
+            connection.close();
+        }
+        self.midi.all_notes_off();
+    }
+
+    pub fn set_polyphony(&self, polyphony: usize) {
+        self.midi.set_polyphony(polyphony);
+    }
+
+    /// Note that plays the source at its original pitch.
+    pub fn set_root_note(&self, note: u8) {
+        self.midi.set_root_note(note);
+    }
+
+    /// In MIDI mode only note voices sound; the scheduler still has to be
+    /// running for them to produce grains.
+    pub fn set_midi_mode(&self, enabled: bool) {
+        self.midi.set_midi_mode(enabled);
+    }
+
+    // ----------------------
+    // GRAIN FILTER
+    // ----------------------
+    pub fn set_grain_filter_enabled(&self, enabled: bool) {
+        self.grain_filter.set_enabled(enabled);
+    }
+
+    pub fn set_grain_filter_type(&self, topology: FilterTopology, mode: FilterMode) {
+        self.grain_filter.set_type(topology, mode);
+    }
+
+    /// Each grain picks a cutoff (Hz) between `min` and `max`.
+    pub fn set_grain_filter_cutoff_range(&self, min: f32, max: f32) {
+        self.grain_filter.set_cutoff_range(min, max);
+    }
+
+    /// Each grain picks a resonance (Q) between `min` and `max`.
+    pub fn set_grain_filter_resonance_range(&self, min: f32, max: f32) {
+        self.grain_filter.set_resonance_range(min, max);
+    }
+
+    // ----------------------
+    // SYNTHETIC SOURCES
+    // ----------------------
+    /// Grains play an oscillator instead of the loaded file, so the synth
+    /// sounds without any sample.
+    pub fn set_oscillator_enabled(&self, enabled: bool) {
+        self.oscillator.set_enabled(enabled);
+    }
+
+    pub fn set_oscillator_waveform(&self, waveform: Waveform) {
+        self.oscillator.set_waveform(waveform);
+    }
+
+    /// Grain frequency in Hz at pitch 1; every grain is scaled by its own
+    /// playback rate.
+    pub fn set_oscillator_frequency(&self, frequency: f32) {
+        self.oscillator.set_frequency(frequency);
+    }
+
+    /// One cycle of any length, played by `Waveform::Wavetable` grains.
+    pub fn set_wavetable(&self, table: Vec<f32>) -> Result<(), String> {
+        if table.is_empty() {
+            return Err("Wavetable is empty".to_string());
+        }
+        loader::retire(self.wavetable.swap(Arc::new(Arc::from(table))));
+        Ok(())
+    }
+
+    // ----------------------
+    // GLISSON
+    // ----------------------
+    /// Grains glide in pitch from their own rate over their length.
+    pub fn set_glisson_enabled(&self, enabled: bool) {
+        self.glisson.set_enabled(enabled);
+    }
+
+    /// Each grain glides by a depth (semitones) between `min` and `max`.
+    pub fn set_glisson_depth_range(&self, min: f32, max: f32) {
+        self.glisson.set_depth_range(min, max);
+    }
+
+    pub fn set_glisson_direction(&self, direction: GlideDirection) {
+        self.glisson.set_direction(direction);
+    }
+
+    pub fn set_glisson_curve(&self, curve: GlideCurve) {
+        self.glisson.set_curve(curve);
+    }
+
+    // ----------------------
+    // PROCESSING MODE
+    // ----------------------
+    /// Switches between time-domain grains, spectral grains and PSOLA.
+    /// Entering the spectral or PSOLA mode analyzes the source if needed.
+    pub fn set_processing_mode(&self, mode: ProcessingMode) {
+        self.params.set_processing_mode(mode);
+        match mode {
+            ProcessingMode::Spectral
+                if self.spectral_frames.load().frame_count() == 0 =>
+            {
+                self.refresh_spectral_frames()
+            }
+            ProcessingMode::Psola if self.pitch_marks.load().is_empty() => {
+                self.refresh_pitch_marks()
+            }
+            _ => {}
+        }
+    }
+
+    pub fn processing_mode(&self) -> ProcessingMode {
+        self.params.processing_mode()
+    }
+
+    /// Finds the pitch marks of the source while the PSOLA mode is on;
+    /// otherwise drops the old ones so entering the mode redoes them.
+    fn refresh_pitch_marks(&self) {
+        let never = AtomicBool::new(false);
+        let (source, specs) = (self.source(), self.params.specs());
+        let mode = self.params.processing_mode();
+        if let Some(marks) = self.analyze_pitch_marks(&source, specs.sample_rate, mode, &never) {
+            loader::retire(self.pitch_marks.swap(Arc::new(marks)));
+        }
+    }
+
+    /// Pitch marks of `source` in `mode`, empty outside the PSOLA mode;
+    /// `None` once `cancel` is raised.
+    fn analyze_pitch_marks(
+        &self,
+        source: &[f32],
+        sample_rate: u32,
+        mode: ProcessingMode,
+        cancel: &AtomicBool,
+    ) -> Option<PitchMarks> {
+        if mode == ProcessingMode::Psola {
+            PitchMarks::analyze(source, sample_rate, cancel)
+        } else {
+            Some(PitchMarks::empty())
+        }
+    }
+
+    // ----------------------
+    // SPECTRAL MODE
+    // ----------------------
+    /// Replaces the time-domain grains with spectral grains: STFT frames of
+    /// the source resynthesized by overlap-add. Shorthand for
+    /// `set_processing_mode`.
+    pub fn set_spectral_enabled(&self, enabled: bool) {
+        self.set_processing_mode(if enabled {
+            ProcessingMode::Spectral
+        } else {
+            ProcessingMode::Granular
+        });
+    }
+
+    /// Holds the current spectral grain (spectral freeze).
+    pub fn set_spectral_freeze(&self, frozen: bool) {
+        self.spectral.set_frozen(frozen);
+    }
+
+    /// Spectral grains are picked at random up to `spread_ms` around the
+    /// grain start; 0 follows the grain start exactly.
+    pub fn set_spectral_spread(&self, spread_ms: f32) {
+        self.spectral.set_spread_ms(spread_ms);
+    }
+
+    /// 0 keeps every bin's phase running smoothly, 1 randomizes it every
+    /// hop for smeared textures.
+    pub fn set_spectral_phase_randomness(&self, amount: f32) {
+        self.spectral.set_phase_randomness(amount);
+    }
+
+    /// Analyzes the source while the spectral mode is on; otherwise drops
+    /// the old analysis so enabling the mode redoes it.
+    fn refresh_spectral_frames(&self) {
+        let never = AtomicBool::new(false);
+        let mode = self.params.processing_mode();
+        if let Some(frames) = self.analyze_spectral_frames(&self.source(), mode, &never) {
+            loader::retire(self.spectral_frames.swap(Arc::new(frames)));
+        }
+    }
+
+    /// STFT of `source` in `mode`, empty outside the spectral mode; `None`
+    /// once `cancel` is raised.
+    fn analyze_spectral_frames(
+        &self,
+        source: &[f32],
+        mode: ProcessingMode,
+        cancel: &AtomicBool,
+    ) -> Option<SpectralFrames> {
+        if mode == ProcessingMode::Spectral {
+            SpectralFrames::analyze(source, cancel)
+        } else {
+            Some(SpectralFrames::empty())
+        }
+    }
+
+    // ----------------------
+    // LIVE INPUT
+    // ----------------------
+    /// New grains read the live input buffer, filled by
+    /// `AudioEngine::start_live_input`, instead of the loaded file.
+    pub fn set_live_input_enabled(&self, enabled: bool) {
+        self.live_input.set_enabled(enabled);
+    }
+
+    /// Live grains start a random delay between `min_ms` and `max_ms`
+    /// behind the input, at least far enough back not to overtake it.
+    pub fn set_live_delay_range(&self, min_ms: f32, max_ms: f32) {
+        self.live_input.set_delay_range(min_ms, max_ms);
+    }
+
+    /// Stops overwriting the live buffer; grains keep playing what it holds.
+    pub fn set_live_freeze(&self, frozen: bool) {
+        self.live_input.set_frozen(frozen);
+    }
+
+    // ----------------------
+    // DESCRIPTOR SELECTION
+    // ----------------------
+    /// Grains start on source segments chosen by their descriptors instead
+    /// of at the grain start. Enabling analyzes the source.
+    pub fn set_descriptor_selection_enabled(&self, enabled: bool) {
+        self.descriptors.set_enabled(enabled);
+        if enabled && self.corpus.lock().unwrap().segments().is_empty() {
+            self.refresh_corpus();
+        }
+    }
+
+    /// Target `value` (0..1 over the range found in the source) and
+    /// `weight` (0 ignores the descriptor) of one descriptor.
+    pub fn set_descriptor_target(&self, descriptor: Descriptor, value: f32, weight: f32) {
+        self.descriptors.set_target(descriptor, value, weight);
+    }
+
+    /// Each grain is drawn from the `candidates` segments closest to the
+    /// target.
+    pub fn set_descriptor_candidates(&self, candidates: usize) {
+        self.descriptors.set_candidates(candidates);
+    }
+
+    /// Analyzes the source while descriptor selection is on; otherwise
+    /// drops the old corpus so enabling it redoes the analysis.
+    fn refresh_corpus(&self) {
+        let never = AtomicBool::new(false);
+        let (source, specs) = (self.source(), self.params.specs());
+        let enabled = self.descriptors.is_enabled();
+        if let Some(corpus) = self.analyze_corpus(&source, specs.sample_rate, enabled, &never) {
+            let old_corpus = std::mem::replace(&mut *self.corpus.lock().unwrap(), corpus);
+            drop(old_corpus);
+        }
+    }
+
+    /// Descriptors of `source` while `enabled`, empty otherwise; `None`
+    /// once `cancel` is raised.
+    fn analyze_corpus(
+        &self,
+        source: &[f32],
+        sample_rate: u32,
+        enabled: bool,
+        cancel: &AtomicBool,
+    ) -> Option<Corpus> {
+        if enabled {
+            Corpus::analyze(source, sample_rate, cancel)
+        } else {
+            Some(Corpus::empty())
+        }
+    }
+
+    // ----------------------
+    // SOURCE REGION
+    // ----------------------
+    /// Confines every grain read to `start..end` (0..1 over the file); the
+    /// grain start then spans this region.
+    pub fn set_source_region(&self, start: f32, end: f32) {
+        self.region.set_region(start, end);
+    }
+
+    pub fn source_region(&self) -> (f32, f32) {
+        self.region.region()
+    }
+
+    /// What grains do at the region edges: fit inside it, wrap around, or
+    /// bounce back.
+    pub fn set_region_boundary(&self, mode: BoundaryMode) {
+        self.region.set_boundary_mode(mode);
+    }
+
+    // ----------------------
+    // SOURCE BANK
+    // ----------------------
+    /// Loads a file into bank slot `slot` (1..MAX_SOURCES); slot 0 is the
+    /// main source, loaded by `load_audio_from_file`.
+    pub fn load_bank_source(&self, slot: usize, path: &str, master_rate: u32) -> Result<(), String> {
+        if slot == 0 || slot >= MAX_SOURCES {
+            return Err(format!("Bank slot {} out of range", slot));
+        }
+        let never = AtomicBool::new(false);
+        let (samples, channels) = Self::decode_file(path, master_rate)?;
+        self.install_source(slot, samples, channels, master_rate, &never).map(|_| ())
+    }
+
+    pub fn clear_bank_source(&self, slot: usize) -> Result<(), String> {
+        if slot == 0 {
+            return Err("The main source cannot be cleared".to_string());
+        }
+        self.swap_source(slot, Vec::new()).map(|_| ())
+    }
+
+    /// Relative chance (0..1) of grains reading `slot`.
+    pub fn set_source_weight(&self, slot: usize, weight: f32) -> Result<(), String> {
+        self.bank.set_weight(slot, weight)
+    }
+
+    /// While on, grains read `from` or `to` by the crossfade position
+    /// instead of by weight.
+    pub fn set_source_crossfade_enabled(&self, enabled: bool) {
+        self.bank.set_crossfade_enabled(enabled);
+    }
+
+    /// Morphs from slot `from` to slot `to`: the position (0..1) moves to
+    /// `position` at a rate of one full sweep per `time_ms`.
+    pub fn set_source_crossfade(
+        &self,
+        from: usize,
+        to: usize,
+        position: f32,
+        time_ms: f32,
+    ) -> Result<(), String> {
+        self.bank.set_crossfade(from, to, position, time_ms)
+    }
+
+    // ----------------------
+    // ASYNC LOADING
+    // ----------------------
+    /// Loads `path` into bank slot `slot` (0 is the main source) on a
+    /// background thread, cancelling any load in progress. `on_progress`
+    /// gets 0..1 as decoding, resampling and the analyses go; `on_done` gets
+    /// the outcome, once. Both run on the loading thread.
+    pub fn load_source_async<P, D>(
+        &self,
+        slot: usize,
+        path: String,
+        master_rate: u32,
+        on_progress: P,
+        on_done: D,
+    ) -> Result<(), String>
+    where
+        P: FnMut(f32) + Send + 'static,
+        D: FnOnce(LoadOutcome) + Send + 'static,
+    {
+        if slot >= MAX_SOURCES {
+            return Err(format!("Bank slot {} out of range", slot));
+        }
+        self.loader.cancel();
+        let synth = self.clone_for_thread();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let thread_cancel = Arc::clone(&cancel);
+        let handle = thread::spawn(move || {
+            let mut on_progress = on_progress;
+            let decoded = loader::decode_file(&path, master_rate, &thread_cancel, &mut on_progress);
+            let outcome = match decoded {
+                Ok(Some((samples, channels))) => {
+                    match synth.install_source(slot, samples, channels, master_rate, &thread_cancel) {
+                        Ok(Some(_)) => {
+                            on_progress(1.0);
+                            LoadOutcome::Loaded
+                        }
+                        Ok(None) => LoadOutcome::Cancelled,
+                        Err(e) => LoadOutcome::Failed(e),
+                    }
+                }
+                Ok(None) => LoadOutcome::Cancelled,
+                Err(e) => LoadOutcome::Failed(e),
+            };
+            on_done(outcome);
+        });
+        self.loader.start(cancel, handle);
+        Ok(())
+    }
+
+    /// Stops the load in progress, if any; its `on_done` gets `Cancelled`
+    /// unless the new source was already swapped in.
+    pub fn cancel_loading(&self) {
+        self.loader.cancel();
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loader.is_loading()
+    }
+
+    // ----------------------
+    // TEMPO
+    // ----------------------
+    /// `Off` keeps the millisecond grain rate; `Internal` and `MidiClock`
+    /// trigger grains on note divisions.
+    pub fn set_tempo_sync(&self, sync: TempoSync) {
+        self.transport.set_sync(sync);
+    }
+
+    pub fn set_bpm(&self, bpm: f32) {
+        self.transport.set_bpm(bpm);
+    }
+
+    /// Tempo in use, measured from the incoming clock when slaved.
+    pub fn bpm(&self) -> f32 {
+        self.transport.bpm()
+    }
+
+    /// One grain per 1/`denominator` note, dotted or as a triplet.
+    pub fn set_note_division(&self, denominator: u32, modifier: DivisionModifier) {
+        self.transport.set_division(denominator, modifier);
+    }
+
+    pub fn set_swing(&self, swing: f32) {
+        self.transport.set_swing(swing);
+    }
+
+    /// `pulses` grains spread over every `steps` steps; `steps == 0` plays
+    /// every step.
+    pub fn set_euclidean_pattern(&self, steps: u32, pulses: u32, rotation: u32) {
+        self.transport.set_euclidean(steps, pulses, rotation);
+    }
+
+    // ----------------------
+    // METERS
+    // ----------------------
+    /// Output peaks (left, right) of the last audio block.
+    pub fn output_peaks(&self) -> (f32, f32) {
+        self.meters.peaks()
+    }
+
+    /// Start of the latest grain, 0..1 over the source.
+    pub fn playhead(&self) -> f32 {
+        self.meters.playhead()
+    }
+
+    // ----------------------
+    // MIDI CC MAPPING
+    // ----------------------
+    /// The next CC, 14-bit CC pair or NRPN that moves gets mapped to
+    /// `target` over `min..max`.
+    pub fn start_midi_learn(&self, target: MappingTarget, min: f32, max: f32, curve: MappingCurve) {
+        self.cc_mapper.lock().unwrap().start_learn(target, min, max, curve);
+    }
+
+    pub fn cancel_midi_learn(&self) {
+        self.cc_mapper.lock().unwrap().cancel_learn();
+    }
+
+    pub fn is_midi_learning(&self) -> bool {
+        self.cc_mapper.lock().unwrap().is_learning()
+    }
+
+    /// Replaces any existing mapping of the same target.
+    pub fn add_cc_mapping(&self, mapping: CcMapping) {
+        self.cc_mapper.lock().unwrap().set_mapping(mapping);
+    }
+
+    pub fn remove_cc_mapping(&self, target: MappingTarget) {
+        self.cc_mapper.lock().unwrap().remove_mapping(target);
+    }
+
+    pub fn clear_cc_mappings(&self) {
+        self.cc_mapper.lock().unwrap().clear();
+    }
+
+    pub fn cc_mappings(&self) -> Vec<CcMapping> {
+        self.cc_mapper.lock().unwrap().mappings().to_vec()
+    }
+
+    // ----------------------
+    // PRESETS
+    // ----------------------
+    pub fn preset(&self) -> Preset {
+        let params = self.params.snapshot();
+        Preset {
+            grain_start: self.params.normalized_grain_start(),
+            grain_duration: params.grain_duration,
+            grain_overlap: params.grain_overlap,
+            grain_pitch: params.grain_pitch,
+            gain: self.params.gain(),
+            pan: self.params.pan(),
+            grain_amplitude: self.params.grain_amplitude(),
+            grain_pan: self.params.grain_pan(),
+            gain_compensation: self.params.is_gain_compensation_enabled(),
+            midi_mappings: self.cc_mappings(),
+        }
+    }
+
+    pub fn apply_preset(&self, preset: &Preset) {
+        self.params.set_grain_start(preset.grain_start);
+        self.params.set_grain_duration(preset.grain_duration);
+        self.params.set_grain_overlap(preset.grain_overlap);
+        self.params.set_grain_pitch(preset.grain_pitch);
+        self.params.set_gain(preset.gain);
+        self.params.set_pan(preset.pan);
+        self.params.set_grain_amplitude(preset.grain_amplitude);
+        self.params.set_grain_pan(preset.grain_pan);
+        self.params.set_gain_compensation(preset.gain_compensation);
+        self.cc_mapper.lock().unwrap().set_mappings(preset.midi_mappings.clone());
+    }
+
+    pub fn save_preset(&self, path: &str) -> Result<(), String> {
+        self.preset().save(path)
+    }
+
+    pub fn load_preset(&self, path: &str) -> Result<(), String> {
+        let preset = Preset::load(path)?;
+        self.apply_preset(&preset);
+        Ok(())
+    }
+}
+
+/// Notes go to the voice allocator, control changes to the CC mapper and
+/// clock messages to the transport.
+fn handle_midi_event(
+    event: MidiEvent,
+    midi_state: &MidiState,
+    cc_mapper: &Mutex<CcMapper>,
+    params: &SharedParams,
+    transport: &Transport,
+) -> bool {
+    match event {
+        MidiEvent::ControlChange { channel, controller, value } => {
+            cc_mapper.lock().unwrap().handle_cc(channel, controller, value, params);
+        }
+        MidiEvent::Clock => transport.clock_tick(),
+        MidiEvent::Start => transport.clock_start(),
+        MidiEvent::Continue => transport.clock_continue(),
+        MidiEvent::Stop => transport.clock_stop(),
+        _ => return midi_state.handle_event(event),
+    }
+    true
+}
+
+// -------------------------------------
+// ACTIVE GRAIN HELPER STRUCT
+// -------------------------------------
+/// Upper bound of grains sounding at once. The pool and the grain channel are
+/// allocated with this capacity up front so the audio thread never has to.
+pub const MAX_ACTIVE_GRAINS: usize = 64;
+
+#[derive(Clone, Copy)]
+pub struct ActiveGrain {
+    grain: Grain,
+    position: usize,
+}
+impl ActiveGrain {
+    fn new(grain: Grain) -> Self {
+        Self {
+            grain,
+            position: 0,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.position >= self.grain.length
+    }
+
+    fn next_sample(
+        &mut self,
+        sources: &SourceView,
+        live_buffer: Option<&LiveBuffer>,
+        grain_env: &[f32],
+        wavetable: &[f32],
+    ) -> f32 {
+        if self.is_finished() {
+            0.0
+        } else {
+            let mut s = match self.grain.oscillator.as_mut() {
+                Some(oscillator) => {
+                    let rate = match self.grain.glide {
+                        Some(glide) => {
+                            glide.rate_at(self.position as f32 / self.grain.length as f32)
+                        }
+                        None => 1.0,
+                    };
+                    oscillator.next_sample(wavetable, rate)
+                        * self.grain.envelope_at(self.position, grain_env)
+                        * self.grain.amplitude
+                }
+                None => {
+                    let source = sources.source(self.grain.source as usize, self.grain.generation);
+                    self.grain.sample_at(self.position, source, live_buffer, grain_env)
+                }
+            };
+            if let Some(filter) = self.grain.filter.as_mut() {
+                s = filter.process(s);
+            }
+            self.position += 1;
+            s
+        }
+    }
+
+}
+
+/// Fixed set of grain slots owned by the audio callback. Grains are rendered
+/// on the fly, so memory use does not depend on grain duration.
+pub struct GrainPool {
+    slots: Vec<Option<ActiveGrain>>,
+}
+impl GrainPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: vec![None; capacity],
+        }
+    }
+
+    /// Puts the grain in a free slot. Returns `false` (and drops the grain)
+    /// when every slot is busy.
+    pub fn trigger(&mut self, grain: Grain) -> bool {
+        match self.slots.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(ActiveGrain::new(grain));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sums the next sample of every active grain, scaled by the level of
+    /// its cloud and panned into a (left, right) pair, and frees finished
+    /// slots. `sources` is the source bank as this block sees it.
+    pub fn next_frame(
+        &mut self,
+        sources: &SourceView,
+        live_buffer: Option<&LiveBuffer>,
+        grain_env: &[f32],
+        wavetable: &[f32],
+        cloud_levels: &[f32],
+    ) -> (f32, f32) {
+        let (mut left, mut right) = (0.0, 0.0);
+        for slot in self.slots.iter_mut() {
+            if let Some(g) = slot {
+                let level = cloud_levels.get(g.grain.cloud as usize).copied().unwrap_or(1.0);
+                let sample = g.next_sample(sources, live_buffer, grain_env, wavetable) * level;
+                let (pan_l, pan_r) = balance(g.grain.pan);
+                left += sample * pan_l;
+                right += sample * pan_r;
+                if g.is_finished() {
+                    *slot = None;
+                }
+            }
+        }
+        (left, right)
+    }
+
+    pub fn active_count(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Bank slots whose retired buffer some active grain still reads.
+    pub fn retired_in_use(&self, sources: &SourceView) -> [bool; MAX_SOURCES] {
+        let mut in_use = [false; MAX_SOURCES];
+        for g in self.slots.iter().flatten() {
+            let slot = g.grain.source as usize;
+            if !g.grain.live
+                && g.grain.oscillator.is_none()
+                && sources.is_retired(slot, g.grain.generation)
+            {
+                in_use[slot] = true;
+            }
+        }
+        in_use
+    }
+}
+// -------------------------------------
+// HELPER FUNCTIONS
+// -------------------------------------
+
+/// Balance law: the centre leaves both sides at unity.
+fn balance(pan: f32) -> (f32, f32) {
+    ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0))
+}
+
+fn interpolate(interpolation: Interpolation, buffer: &[f32], x: f32) -> f32 {
+    match interpolation {
+        Interpolation::FourPoint => four_point_interpolation(buffer, x),
+        Interpolation::Sinc => sinc_interpolation(buffer, x),
+        Interpolation::Cubic => cubic_interpolation(buffer, x),
+        Interpolation::Linear => linear_interpolation(buffer, x),
+    }
+}
+
+fn linear_interpolation(buffer: &[f32], x: f32) -> f32 {
+    // x is the fractional index. E.g. 12.3 => index0=12, index1=13, frac=0.3
+    let index0 = x.floor() as usize;
+    let index1 = index0 + 1;
+    let frac = x - (index0 as f32);
+
+    let s0 = if index0 < buffer.len() { buffer[index0] } else { 0.0 };
+    let s1 = if index1 < buffer.len() { buffer[index1] } else { 0.0 };
+
+    let out = s0 + (s1 - s0) * frac;
+    out
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (x * std::f32::consts::PI).sin() / (x * std::f32::consts::PI)
+    }
+}
+
+fn sinc_interpolation(buffer: &[f32], x: f32) -> f32 {
+    let n = buffer.len() as isize;
+    let i = x.floor() as isize;
+    let frac = x - i as f32;
+
+    let mut result = 0.0;
+    let mut normalization = 0.0;
+
+    for j in -2..=2 {
+        let index = i + j;
+        if index >= 0 && index < n {
+            let sample = buffer[index as usize];
+            let sinc_value = sinc(frac - j as f32);
+            result += sample * sinc_value;
+            normalization += sinc_value;
         }
     }
 
@@ -851,325 +2601,1657 @@ fn sinc_interpolation(buffer: &[f32], x: f32) -> f32 {
     }
 }
 
-fn cubic_interpolation(buffer: &[f32], x: f32) -> f32 {
-    let n = buffer.len() as isize;
-    let i = x.floor() as isize;
-    let frac = x - i as f32;
+fn cubic_interpolation(buffer: &[f32], x: f32) -> f32 {
+    let n = buffer.len() as isize;
+    let i = x.floor() as isize;
+    let frac = x - i as f32;
+
+    let mut p = [0.0; 4];
+
+    for j in -1..=2 {
+        let index = i + j;
+        if index >= 0 && index < n {
+            p[(j + 1) as usize] = buffer[index as usize];
+        } else {
+            p[(j + 1) as usize] = 0.0; // Out of bounds, use 0.0
+        }
+    }
+
+    let a = (p[2] - p[0]) * 0.5;
+    let b = p[1] - p[0] - a;
+    let c = p[2] - p[1] - a;
+    let d = p[1];
+
+    // Cubic polynomial: a * frac^3 + b * frac^2 + c * frac + d
+    let frac2 = frac * frac;
+    let frac3 = frac2 * frac;
+
+    a * frac3 + b * frac2 + c * frac + d
+}
+
+fn four_point_interpolation(buffer: &[f32], x: f32) -> f32 {
+    // Runs on the audio thread: an empty buffer (no source, or a grain whose
+    // buffer is gone) is silence
+    if buffer.is_empty() {
+        return 0.0;
+    }
+    let i = x.floor() as isize;
+    let frac = x - (i as f32);
+
+    let x0 = i-1;
+    let x1 = i;
+    let x2 = i+1;
+    let x3 = i+2;
+
+    let s0 = *buffer.get(x0.max(0) as usize).unwrap_or(&0.0);
+    let s1 = *buffer.get(x1.max(0) as usize).unwrap_or(&0.0);
+    let s2 = *buffer.get(x2.max(0) as usize).unwrap_or(&0.0);
+    let s3 = *buffer.get(x3.max(0) as usize).unwrap_or(&0.0);
+
+    let c1 = 0.5 * (s2 - s0);
+    let c2 = s0 - 2.5 * s1 + 2.0 * s2 - 0.5 * s3;
+    let c3 = -0.5 * s0 + 1.5 * s1 - 1.5 * s2 + 0.5 * s3;
+
+    let frac2 = frac * frac;
+    let frac3 = frac2 * frac;
+
+    s1 + c1 * frac + c2 * frac2 + c3 * frac3
+}
+
+/// `None` once `cancel` is raised.
+fn resample_to_master(
+    input_samples: &[f32],       // interleaved
+    input_channels: u16,
+    input_sample_rate: u32,
+    output_sample_rate: u32,
+    cancel: &AtomicBool,
+) -> Option<Vec<f32>> {
+    // If sample rate matches, no need to resample.
+    if input_sample_rate == output_sample_rate {
+        return Some(input_samples.to_vec());
+    }
+
+    // 1) Deinterleave the input into channel-specific buffers.
+    //    E.g. if input_channels=2, samples are [L0, R0, L1, R1, L2, R2, ...]
+    let frames = input_samples.len() / input_channels as usize;
+    let mut channels_data = vec![Vec::with_capacity(frames); input_channels as usize];
+
+    for (i, &sample) in input_samples.iter().enumerate() {
+        let ch = i % input_channels as usize;
+        channels_data[ch].push(sample);
+    }
+
+    let ratio = output_sample_rate as f64 / input_sample_rate as f64;
+
+    // 2) For each channel, resample from input_sample_rate to output_sample_rate
+    let mut resampled_channels: Vec<Vec<f32>> = Vec::new();
+    for ch_buf in channels_data {
+        let signal_in = signal::from_iter(ch_buf.into_iter());
+
+        // swap in a Sinc interpolator
+        let interpolator = Linear::new(0.0, 0.0);
+        let mut sig = signal_in.from_hz_to_hz::<Linear<f32>>(
+            interpolator,
+            input_sample_rate as f64,
+            output_sample_rate as f64,
+        );
+
+        let estimated_len = (frames as f64 * ratio).ceil() as usize;
+        let mut out_buf = Vec::with_capacity(estimated_len);
+
+        for i in 0..estimated_len {
+            if i % loader::PROGRESS_INTERVAL == 0 && cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+            // Safely fetch a sample from `sig.next()`
+            let sample = sig.next();
+            out_buf.push(sample);
+        }
+
+        resampled_channels.push(out_buf);
+    }
+
+    // 3) Reinterleave the resampled channels back together
+    //    so that the final output is in the same interleaved format:
+    //    [C0_frame0, C1_frame0, C0_frame1, C1_frame1, ...]
+    let res_frames = resampled_channels[0].len(); // length of the first channel
+    let num_channels = resampled_channels.len();
+    let mut final_samples = Vec::with_capacity(res_frames * num_channels);
+
+    for i in 0..res_frames {
+        for ch in 0..num_channels {
+            let channel_data = &resampled_channels[ch];
+            // If this channel is shorter for some reason, use 0.0
+            let sample = channel_data.get(i).copied().unwrap_or(0.0);
+            final_samples.push(sample);
+        }
+    }
+
+    Some(final_samples)
+}
+
+// -------------------------------------
+// C API
+// -------------------------------------
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+#[allow(unused_imports)]
+use std::ptr;
+
+#[no_mangle]
+pub extern "C" fn create_synth(sample_rate: u32) -> *mut GranularSynth {
+    let synth = Box::new(GranularSynth::new(sample_rate));
+    Box::into_raw(synth)
+}
+
+#[no_mangle]
+pub extern "C" fn destroy_synth(ptr: *mut GranularSynth) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        let _ = Box::from_raw(ptr);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn load_audio_from_file(
+    synth_ptr: *mut GranularSynth,
+    file_path: *const c_char,
+    master_rate: u32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+
+    if file_path.is_null() {
+        return -1;
+    }
+    let c_str = unsafe {std::ffi::CStr::from_ptr(file_path)};
+    let path_str = match c_str.to_str() {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+
+    let result = 
+        synth.load_audio_from_file(
+            path_str.as_bytes().as_ptr(), path_str.len(), master_rate);
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn generate_grain_envelope(
+    synth_ptr: *mut GranularSynth,
+    size: usize,
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.generate_grain_envelope(size);
+}
+
+#[no_mangle]
+pub extern "C" fn start_scheduler(
+    synth_ptr: *mut GranularSynth
+){
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &mut *synth_ptr
+    };
+    synth.start_scheduler();
+}
+
+#[no_mangle]
+pub extern "C" fn stop_scheduler(
+    synth_ptr: *mut GranularSynth
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &mut *synth_ptr
+    };
+    synth.stop_scheduler();
+}
+
+#[no_mangle]
+pub extern "C" fn set_params(
+    synth_ptr: *mut GranularSynth,
+    start: f32,
+    duration: usize,
+    overlap: f32,
+    pitch: f32,
+){
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_params(start, duration, overlap, pitch);
+}
+
+#[no_mangle]
+pub extern "C" fn set_grain_start(
+    synth_ptr: *mut GranularSynth, 
+    start: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_grain_start(start);
+}
+
+#[no_mangle]
+pub extern "C" fn set_grain_duration(
+    synth_ptr: *mut GranularSynth, 
+    duration: usize
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_grain_duration(duration);
+}
+
+#[no_mangle]
+pub extern "C" fn set_grain_pitch(
+    synth_ptr: *mut GranularSynth, 
+    pitch: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_grain_pitch(pitch);
+}
+
+#[no_mangle]
+pub extern "C" fn set_overlap(
+    synth_ptr: *mut GranularSynth, 
+    overlap: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_grain_overlap(overlap);
+}
+
+
+#[no_mangle]
+pub extern "C" fn set_gain(
+    synth_ptr: *mut GranularSynth,
+    gain: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_gain(gain);
+}
+
+#[no_mangle]
+pub extern "C" fn set_pan(
+    synth_ptr: *mut GranularSynth,
+    pan: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_pan(pan);
+}
+
+#[no_mangle]
+pub extern "C" fn set_grain_amplitude(
+    synth_ptr: *mut GranularSynth,
+    amplitude: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_grain_amplitude(amplitude);
+}
+
+#[no_mangle]
+pub extern "C" fn set_grain_pan(
+    synth_ptr: *mut GranularSynth,
+    pan: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_grain_pan(pan);
+}
+
+#[no_mangle]
+pub extern "C" fn set_gain_compensation(
+    synth_ptr: *mut GranularSynth,
+    enabled: bool
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_gain_compensation(enabled);
+}
+
+/// `mode`: 0 = linear ramp, 1 = one-pole.
+#[no_mangle]
+pub extern "C" fn set_smoothing_mode(
+    synth_ptr: *mut GranularSynth,
+    mode: c_int
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_smoothing_mode(SmoothingMode::from_index(mode as u8));
+}
+
+#[no_mangle]
+pub extern "C" fn set_smoothing_time(
+    synth_ptr: *mut GranularSynth,
+    time_ms: f32
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.params.set_smoothing_time(time_ms);
+}
+
+/// `shape`: 0 sine, 1 triangle, 2 saw, 3 square, 4 sample & hold,
+/// 5 smooth random.
+#[no_mangle]
+pub extern "C" fn set_lfo(
+    synth_ptr: *mut GranularSynth,
+    index: usize,
+    shape: c_int,
+    rate_hz: f32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    let shape = match LfoShape::from_index(shape as u8) {
+        Some(shape) => shape,
+        None => return -1,
+    };
+    match synth.set_lfo(index, shape, rate_hz) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_envelope_follower(
+    synth_ptr: *mut GranularSynth,
+    index: usize,
+    attack_ms: f32,
+    release_ms: f32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match synth.set_envelope_follower(index, attack_ms, release_ms) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// `source_kind`: 0 LFO, 1 envelope follower.
+/// `target`: 0 start, 1 pitch, 2 duration, 3 density, 4 pan, 5 amplitude.
+/// Returns the route index, or -1 on error.
+#[no_mangle]
+pub extern "C" fn add_mod_route(
+    synth_ptr: *mut GranularSynth,
+    source_kind: c_int,
+    source_index: usize,
+    target: c_int,
+    depth: f32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    let source = match source_kind {
+        0 => ModSource::Lfo(source_index),
+        1 => ModSource::EnvelopeFollower(source_index),
+        _ => return -1,
+    };
+    let target = match ModTarget::from_index(target as u8) {
+        Some(target) => target,
+        None => return -1,
+    };
+    match synth.add_mod_route(source, target, depth) {
+        Ok(index) => index as c_int,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn remove_mod_route(
+    synth_ptr: *mut GranularSynth,
+    index: usize,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match synth.remove_mod_route(index) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn clear_mod_routes(synth_ptr: *mut GranularSynth) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.clear_mod_routes();
+}
+
+#[no_mangle]
+pub extern "C" fn set_adsr(
+    synth_ptr: *mut GranularSynth,
+    attack_ms: f32,
+    decay_ms: f32,
+    sustain: f32,
+    release_ms: f32,
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_adsr(attack_ms, decay_ms, sustain, release_ms);
+}
+
+/// `levels` and `times_ms` both hold `count` values. A negative
+/// `sustain_index` means no sustain point.
+#[no_mangle]
+pub extern "C" fn set_envelope_segments(
+    synth_ptr: *mut GranularSynth,
+    levels: *const f32,
+    times_ms: *const f32,
+    count: usize,
+    sustain_index: c_int,
+    release_ms: f32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    if levels.is_null() || times_ms.is_null() {
+        return -1;
+    }
+    let (levels, times_ms) = unsafe {
+        (
+            std::slice::from_raw_parts(levels, count),
+            std::slice::from_raw_parts(times_ms, count),
+        )
+    };
+    let segments: Vec<(f32, f32)> = levels.iter().copied().zip(times_ms.iter().copied()).collect();
+    let sustain = usize::try_from(sustain_index).ok();
+    match synth.set_envelope_segments(&segments, sustain, release_ms) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_cloud_envelope_enabled(
+    synth_ptr: *mut GranularSynth,
+    enabled: bool,
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_cloud_envelope_enabled(enabled);
+}
+
+#[no_mangle]
+pub extern "C" fn gate_on(synth_ptr: *mut GranularSynth) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.gate_on();
+}
+
+#[no_mangle]
+pub extern "C" fn gate_off(synth_ptr: *mut GranularSynth) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.gate_off();
+}
+
+#[no_mangle]
+pub extern "C" fn handle_midi_message(
+    synth_ptr: *mut GranularSynth,
+    data: *const u8,
+    length: usize,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    if data.is_null() {
+        return -1;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(data, length) };
+    if synth.handle_midi_message(bytes) { 0 } else { -1 }
+}
+
+#[no_mangle]
+pub extern "C" fn get_midi_input_list() -> *mut c_char {
+    let port_names: Vec<String> = midi::get_midi_input_ports()
+        .iter()
+        .map(|(i, name)| format!("{}: {}", i, name))
+        .collect();
+    let c_str = std::ffi::CString::new(port_names.join("\n")).unwrap();
+    c_str.into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn open_midi_input(
+    synth_ptr: *mut GranularSynth,
+    index: usize,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &mut *synth_ptr
+    };
+    match synth.open_midi_input(index) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Failed to open MIDI input: {}", e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn open_virtual_midi_input(
+    synth_ptr: *mut GranularSynth,
+    port_name: *const c_char,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &mut *synth_ptr
+    };
+    if port_name.is_null() {
+        return -1;
+    }
+    let c_str = unsafe { std::ffi::CStr::from_ptr(port_name) };
+    let name = match c_str.to_str() {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    match synth.open_virtual_midi_input(name) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Failed to create virtual MIDI input: {}", e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn close_midi_input(synth_ptr: *mut GranularSynth) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &mut *synth_ptr
+    };
+    synth.close_midi_input();
+}
+
+#[no_mangle]
+pub extern "C" fn set_polyphony(synth_ptr: *mut GranularSynth, polyphony: usize) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_polyphony(polyphony);
+}
+
+#[no_mangle]
+pub extern "C" fn set_root_note(synth_ptr: *mut GranularSynth, note: u8) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_root_note(note);
+}
+
+#[no_mangle]
+pub extern "C" fn set_midi_mode(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_midi_mode(enabled);
+}
+
+/// `min == max` selects the target's full range.
+fn mapping_range(target: MappingTarget, min: f32, max: f32) -> (f32, f32) {
+    if min == max { target.default_range() } else { (min, max) }
+}
+
+#[no_mangle]
+pub extern "C" fn start_midi_learn(
+    synth_ptr: *mut GranularSynth,
+    target: c_int,
+    min: f32,
+    max: f32,
+    curve: c_int,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    let target = match MappingTarget::from_index(target as u8) {
+        Some(target) => target,
+        None => return -1,
+    };
+    let (min, max) = mapping_range(target, min, max);
+    synth.start_midi_learn(target, min, max, MappingCurve::from_index(curve as u8));
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn cancel_midi_learn(synth_ptr: *mut GranularSynth) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.cancel_midi_learn();
+}
+
+#[no_mangle]
+pub extern "C" fn is_midi_learning(synth_ptr: *mut GranularSynth) -> bool {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.is_midi_learning()
+}
+
+/// `source_kind`: 0 = 7-bit CC, 1 = 14-bit CC (`number` is the MSB
+/// controller), 2 = NRPN (`number` is the parameter number).
+#[no_mangle]
+pub extern "C" fn add_cc_mapping(
+    synth_ptr: *mut GranularSynth,
+    source_kind: c_int,
+    channel: u8,
+    number: c_uint,
+    target: c_int,
+    min: f32,
+    max: f32,
+    curve: c_int,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    let channel = channel & 0x0F;
+    let source = match (source_kind, number) {
+        (0, 0..=127) => CcSource::Cc7 { channel, controller: number as u8 },
+        (1, 0..=31) => CcSource::Cc14 { channel, controller: number as u8 },
+        (2, 0..=16383) => CcSource::Nrpn { channel, parameter: number as u16 },
+        _ => return -1,
+    };
+    let target = match MappingTarget::from_index(target as u8) {
+        Some(target) => target,
+        None => return -1,
+    };
+    let (min, max) = mapping_range(target, min, max);
+    synth.add_cc_mapping(CcMapping {
+        source,
+        target,
+        min,
+        max,
+        curve: MappingCurve::from_index(curve as u8),
+    });
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn remove_cc_mapping(synth_ptr: *mut GranularSynth, target: c_int) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match MappingTarget::from_index(target as u8) {
+        Some(target) => {
+            synth.remove_cc_mapping(target);
+            0
+        }
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn clear_cc_mappings(synth_ptr: *mut GranularSynth) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.clear_cc_mappings();
+}
+
+#[no_mangle]
+pub extern "C" fn save_preset(
+    synth_ptr: *mut GranularSynth,
+    file_path: *const c_char,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    if file_path.is_null() {
+        return -1;
+    }
+    let c_str = unsafe { std::ffi::CStr::from_ptr(file_path) };
+    let path = match c_str.to_str() {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    match synth.save_preset(path) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Failed to save preset: {}", e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn load_preset(
+    synth_ptr: *mut GranularSynth,
+    file_path: *const c_char,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    if file_path.is_null() {
+        return -1;
+    }
+    let c_str = unsafe { std::ffi::CStr::from_ptr(file_path) };
+    let path = match c_str.to_str() {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    match synth.load_preset(path) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Failed to load preset: {}", e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn create_audio_engine(
+    synth_ptr: *mut GranularSynth,
+    sample_rate: u32,
+    channels: u16,
+    bit_depth: u16,
+    format: *const c_char,
+    device_index: usize,
+) -> *mut AudioEngine {
+    unsafe {
+        assert!(!synth_ptr.is_null());
+        let synth_ref = &*synth_ptr;
+        let arc_synth = Arc::new(synth_ref.clone_for_thread());
+
+        let mut format_str = "wav".to_string(); 
+        if !format.is_null() {
+            let cstr = std::ffi::CStr::from_ptr(format);
+            if let Ok(s) = cstr.to_str() {
+                format_str = s.to_string();
+            }
+        }
+
+        let host = cpal::default_host();
+        let devices = match host.output_devices() {
+            Ok(devices) => devices.collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+
+        let output_device = devices.get(device_index).cloned();
+        if output_device.is_none() {
+            eprintln!("Invalid device index: {}", device_index);
+            return std::ptr::null_mut();
+        }
+        
+        let user_settings = UserRecordingSettings {
+            sample_rate: Some(sample_rate),
+            channels: Some(channels),
+            bit_depth: Some(bit_depth),
+            format: Some(format_str),
+            format_specific: None,  // or fill in...
+        };
+        let engine = AudioEngine::new(arc_synth, user_settings, output_device);
+        Box::into_raw(Box::new(engine))
+    }
+}
+
+/// Listens on all interfaces. Port 0 picks a free port; returns the bound
+/// port, or `-1` on failure.
+#[no_mangle]
+pub extern "C" fn start_osc_server(engine_ptr: *mut AudioEngine, port: u16) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    match engine.start_osc_server(&format!("0.0.0.0:{}", port)) {
+        Ok(addr) => addr.port() as c_int,
+        Err(e) => {
+            eprintln!("Failed to start OSC server: {}", e);
+            -1
+        }
+    }
+}
 
-    let mut p = [0.0; 4];
+#[no_mangle]
+pub extern "C" fn stop_osc_server(engine_ptr: *mut AudioEngine) {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    engine.stop_osc_server();
+}
 
-    for j in -1..=2 {
-        let index = i + j;
-        if index >= 0 && index < n {
-            p[(j + 1) as usize] = buffer[index as usize];
-        } else {
-            p[(j + 1) as usize] = 0.0; // Out of bounds, use 0.0
+#[no_mangle]
+pub extern "C" fn get_output_peak(synth_ptr: *mut GranularSynth, channel: c_uint) -> f32 {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    let (left, right) = synth.output_peaks();
+    if channel == 0 { left } else { right }
+}
+
+#[no_mangle]
+pub extern "C" fn get_playhead(synth_ptr: *mut GranularSynth) -> f32 {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.playhead()
+}
+
+#[no_mangle]
+pub extern "C" fn set_grain_filter_enabled(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_grain_filter_enabled(enabled);
+}
+
+/// `topology`: 0 = biquad, 1 = state-variable. `mode`: 0 = low-pass,
+/// 1 = high-pass, 2 = band-pass, 3 = notch.
+#[no_mangle]
+pub extern "C" fn set_grain_filter_type(
+    synth_ptr: *mut GranularSynth,
+    topology: c_int,
+    mode: c_int,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match (
+        FilterTopology::from_index(topology as u8),
+        FilterMode::from_index(mode as u8),
+    ) {
+        (Some(topology), Some(mode)) => {
+            synth.set_grain_filter_type(topology, mode);
+            0
         }
+        _ => -1,
     }
+}
 
-    let a = (p[2] - p[0]) * 0.5;
-    let b = p[1] - p[0] - a;
-    let c = p[2] - p[1] - a;
-    let d = p[1];
+#[no_mangle]
+pub extern "C" fn set_grain_filter_cutoff_range(
+    synth_ptr: *mut GranularSynth,
+    min_hz: f32,
+    max_hz: f32,
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_grain_filter_cutoff_range(min_hz, max_hz);
+}
 
-    // Cubic polynomial: a * frac^3 + b * frac^2 + c * frac + d
-    let frac2 = frac * frac;
-    let frac3 = frac2 * frac;
+#[no_mangle]
+pub extern "C" fn set_grain_filter_resonance_range(
+    synth_ptr: *mut GranularSynth,
+    min_q: f32,
+    max_q: f32,
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_grain_filter_resonance_range(min_q, max_q);
+}
 
-    a * frac3 + b * frac2 + c * frac + d
+#[no_mangle]
+pub extern "C" fn set_oscillator_enabled(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_oscillator_enabled(enabled);
 }
 
-fn four_point_interpolation(buffer: &[f32], x: f32) -> f32 {
-    if buffer.is_empty() {
-        eprintln!("Buffer is empty [interpolation error]");
-        return 0.0;
+/// 0 = sine, 1 = saw, 2 = square, 3 = noise, 4 = pulse train, 5 = wavetable.
+#[no_mangle]
+pub extern "C" fn set_oscillator_waveform(synth_ptr: *mut GranularSynth, waveform: c_int) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match Waveform::from_index(waveform as u8) {
+        Some(waveform) => {
+            synth.set_oscillator_waveform(waveform);
+            0
+        }
+        None => -1,
     }
-    let i = x.floor() as isize;
-    let frac = x - (i as f32);
+}
 
-    let x0 = i-1;
-    let x1 = i;
-    let x2 = i+1;
-    let x3 = i+2;
+#[no_mangle]
+pub extern "C" fn set_oscillator_frequency(synth_ptr: *mut GranularSynth, frequency_hz: f32) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_oscillator_frequency(frequency_hz);
+}
 
-    let s0 = *buffer.get(x0.max(0) as usize).unwrap_or(&0.0);
-    let s1 = *buffer.get(x1.max(0) as usize).unwrap_or(&0.0);
-    let s2 = *buffer.get(x2.max(0) as usize).unwrap_or(&0.0);
-    let s3 = *buffer.get(x3.max(0) as usize).unwrap_or(&0.0);
+/// Copies `len` samples as the single-cycle wavetable.
+#[no_mangle]
+pub extern "C" fn set_wavetable(
+    synth_ptr: *mut GranularSynth,
+    table: *const f32,
+    len: usize,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    if table.is_null() {
+        return -1;
+    }
+    let table = unsafe { std::slice::from_raw_parts(table, len) };
+    match synth.set_wavetable(table.to_vec()) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
 
-    let c1 = 0.5 * (s2 - s0);
-    let c2 = s0 - 2.5 * s1 + 2.0 * s2 - 0.5 * s3;
-    let c3 = -0.5 * s0 + 1.5 * s1 - 1.5 * s2 + 0.5 * s3;
+#[no_mangle]
+pub extern "C" fn set_glisson_enabled(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_glisson_enabled(enabled);
+}
 
-    let frac2 = frac * frac;
-    let frac3 = frac2 * frac;
+#[no_mangle]
+pub extern "C" fn set_glisson_depth_range(
+    synth_ptr: *mut GranularSynth,
+    min_semitones: f32,
+    max_semitones: f32,
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_glisson_depth_range(min_semitones, max_semitones);
+}
 
-    s1 + c1 * frac + c2 * frac2 + c3 * frac3
+/// `direction`: 0 = up, 1 = down, 2 = random per grain. `curve`: 0 = linear,
+/// 1 = exponential.
+#[no_mangle]
+pub extern "C" fn set_glisson_shape(
+    synth_ptr: *mut GranularSynth,
+    direction: c_int,
+    curve: c_int,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match GlideDirection::from_index(direction as u8) {
+        Some(direction) => {
+            synth.set_glisson_direction(direction);
+            synth.set_glisson_curve(GlideCurve::from_index(curve as u8));
+            0
+        }
+        None => -1,
+    }
 }
 
-fn resample_to_master(
-    input_samples: &[f32],       // interleaved
-    input_channels: u16,
-    input_sample_rate: u32,
-    output_sample_rate: u32,
-) -> Vec<f32> {
-    // If sample rate matches, no need to resample.
-    if input_sample_rate == output_sample_rate {
-        return input_samples.to_vec();
+/// 0 = granular, 1 = spectral, 2 = PSOLA.
+#[no_mangle]
+pub extern "C" fn set_processing_mode(synth_ptr: *mut GranularSynth, mode: c_int) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match ProcessingMode::from_index(mode as u8) {
+        Some(mode) => {
+            synth.set_processing_mode(mode);
+            0
+        }
+        None => -1,
     }
+}
 
-    // 1) Deinterleave the input into channel-specific buffers.
-    //    E.g. if input_channels=2, samples are [L0, R0, L1, R1, L2, R2, ...]
-    let frames = input_samples.len() / input_channels as usize;
-    let mut channels_data = vec![Vec::with_capacity(frames); input_channels as usize];
+#[no_mangle]
+pub extern "C" fn set_spectral_enabled(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_spectral_enabled(enabled);
+}
 
-    for (i, &sample) in input_samples.iter().enumerate() {
-        let ch = i % input_channels as usize;
-        channels_data[ch].push(sample);
-    }
+#[no_mangle]
+pub extern "C" fn set_spectral_freeze(synth_ptr: *mut GranularSynth, frozen: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_spectral_freeze(frozen);
+}
 
-    let ratio = output_sample_rate as f64 / input_sample_rate as f64;
+#[no_mangle]
+pub extern "C" fn set_spectral_spread(synth_ptr: *mut GranularSynth, spread_ms: f32) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_spectral_spread(spread_ms);
+}
 
-    // 2) For each channel, resample from input_sample_rate to output_sample_rate
-    let mut resampled_channels: Vec<Vec<f32>> = Vec::new();
-    for ch_buf in channels_data {
-        let signal_in = signal::from_iter(ch_buf.into_iter());
+#[no_mangle]
+pub extern "C" fn set_spectral_phase_randomness(synth_ptr: *mut GranularSynth, amount: f32) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_spectral_phase_randomness(amount);
+}
 
-        // swap in a Sinc interpolator
-        let interpolator = Linear::new(0.0, 0.0);
-        let mut sig = signal_in.from_hz_to_hz::<Linear<f32>>(
-            interpolator,
-            input_sample_rate as f64,
-            output_sample_rate as f64,
-        );
+#[no_mangle]
+pub extern "C" fn set_live_input_enabled(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_live_input_enabled(enabled);
+}
+
+#[no_mangle]
+pub extern "C" fn set_live_delay_range(synth_ptr: *mut GranularSynth, min_ms: f32, max_ms: f32) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_live_delay_range(min_ms, max_ms);
+}
+
+#[no_mangle]
+pub extern "C" fn set_live_freeze(synth_ptr: *mut GranularSynth, frozen: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_live_freeze(frozen);
+}
 
-        let estimated_len = (frames as f64 * ratio).ceil() as usize;
-        let mut out_buf = Vec::with_capacity(estimated_len);
+#[no_mangle]
+pub extern "C" fn set_descriptor_selection_enabled(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_descriptor_selection_enabled(enabled);
+}
 
-        for _ in 0..estimated_len {
-            // Safely fetch a sample from `sig.next()`
-            let sample = sig.next();
-            out_buf.push(sample);
+/// Descriptor 0 = loudness, 1 = brightness, 2 = pitch, 3 = noisiness.
+#[no_mangle]
+pub extern "C" fn set_descriptor_target(
+    synth_ptr: *mut GranularSynth,
+    descriptor: c_int,
+    value: f32,
+    weight: f32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match Descriptor::from_index(descriptor as u8) {
+        Some(descriptor) => {
+            synth.set_descriptor_target(descriptor, value, weight);
+            0
         }
-
-        resampled_channels.push(out_buf);
+        None => -1,
     }
+}
 
-    // 3) Reinterleave the resampled channels back together
-    //    so that the final output is in the same interleaved format:
-    //    [C0_frame0, C1_frame0, C0_frame1, C1_frame1, ...]
-    let res_frames = resampled_channels[0].len(); // length of the first channel
-    let num_channels = resampled_channels.len();
-    let mut final_samples = Vec::with_capacity(res_frames * num_channels);
+#[no_mangle]
+pub extern "C" fn set_descriptor_candidates(synth_ptr: *mut GranularSynth, candidates: c_uint) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_descriptor_candidates(candidates as usize);
+}
 
-    for i in 0..res_frames {
-        for ch in 0..num_channels {
-            let channel_data = &resampled_channels[ch];
-            // If this channel is shorter for some reason, use 0.0
-            let sample = channel_data.get(i).copied().unwrap_or(0.0);
-            final_samples.push(sample);
+#[no_mangle]
+pub extern "C" fn set_source_region(synth_ptr: *mut GranularSynth, start: f32, end: f32) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_source_region(start, end);
+}
+
+/// 0 = clamp, 1 = wrap, 2 = ping-pong.
+#[no_mangle]
+pub extern "C" fn set_region_boundary(synth_ptr: *mut GranularSynth, mode: c_int) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match BoundaryMode::from_index(mode as u8) {
+        Some(mode) => {
+            synth.set_region_boundary(mode);
+            0
         }
+        None => -1,
+    }
+}
+
+/// Loads a WAV file into bank slot `slot` (1..8), resampled to
+/// `master_rate`.
+#[no_mangle]
+pub extern "C" fn load_bank_source(
+    synth_ptr: *mut GranularSynth,
+    slot: c_uint,
+    file_path: *const c_char,
+    master_rate: u32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    if file_path.is_null() {
+        return -1;
+    }
+    let c_str = unsafe { std::ffi::CStr::from_ptr(file_path) };
+    let path_str = match c_str.to_str() {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    match synth.load_bank_source(slot as usize, path_str, master_rate) {
+        Ok(_) => 0,
+        Err(_) => -1,
     }
+}
 
-    final_samples
+#[no_mangle]
+pub extern "C" fn clear_bank_source(synth_ptr: *mut GranularSynth, slot: c_uint) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match synth.clear_bank_source(slot as usize) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
 }
 
-// -------------------------------------
-// C API
-// -------------------------------------
-use std::ffi::CString;
-use std::os::raw::{c_char, c_int, c_uint};
-#[allow(unused_imports)]
-use std::ptr;
+#[no_mangle]
+pub extern "C" fn set_source_weight(synth_ptr: *mut GranularSynth, slot: c_uint, weight: f32) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match synth.set_source_weight(slot as usize, weight) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
 
 #[no_mangle]
-pub extern "C" fn create_synth(sample_rate: u32) -> *mut GranularSynth {
-    let synth = Box::new(GranularSynth::new(sample_rate));
-    Box::into_raw(synth)
+pub extern "C" fn set_source_crossfade_enabled(synth_ptr: *mut GranularSynth, enabled: bool) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_source_crossfade_enabled(enabled);
 }
 
 #[no_mangle]
-pub extern "C" fn destroy_synth(ptr: *mut GranularSynth) {
-    if ptr.is_null() {
-        return;
+pub extern "C" fn set_source_crossfade(
+    synth_ptr: *mut GranularSynth,
+    from: c_uint,
+    to: c_uint,
+    position: f32,
+    time_ms: f32,
+) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match synth.set_source_crossfade(from as usize, to as usize, position, time_ms) {
+        Ok(_) => 0,
+        Err(_) => -1,
     }
-    unsafe {
-        let _ = Box::from_raw(ptr);
+}
+
+/// Called with the progress of a load, 0..1.
+pub type LoadProgressCallback = extern "C" fn(progress: f32, user_data: *mut c_void);
+/// Called once when a load ends: 0 loaded, 1 cancelled, -1 failed.
+pub type LoadDoneCallback = extern "C" fn(status: c_int, user_data: *mut c_void);
+
+/// Caller's pointer handed back to the load callbacks; the caller keeps it
+/// valid until `on_done` has run.
+struct CallbackData(*mut c_void);
+unsafe impl Send for CallbackData {}
+
+impl CallbackData {
+    fn get(&self) -> *mut c_void {
+        self.0
     }
 }
 
+/// Loads a WAV file into bank slot `slot` (0 is the main source) in the
+/// background; the callbacks run on the loading thread and may be null.
 #[no_mangle]
-pub extern "C" fn load_audio_from_file(
+pub extern "C" fn load_audio_async(
     synth_ptr: *mut GranularSynth,
+    slot: c_uint,
     file_path: *const c_char,
     master_rate: u32,
+    on_progress: Option<LoadProgressCallback>,
+    on_done: Option<LoadDoneCallback>,
+    user_data: *mut c_void,
 ) -> c_int {
     let synth = unsafe {
         assert!(!synth_ptr.is_null());
         &*synth_ptr
     };
-
     if file_path.is_null() {
         return -1;
     }
-    let c_str = unsafe {std::ffi::CStr::from_ptr(file_path)};
+    let c_str = unsafe { std::ffi::CStr::from_ptr(file_path) };
     let path_str = match c_str.to_str() {
-        Ok(s) => s,
+        Ok(s) => s.to_string(),
         Err(_) => return -1,
     };
+    let progress_data = CallbackData(user_data);
+    let done_data = CallbackData(user_data);
+    let result = synth.load_source_async(
+        slot as usize,
+        path_str,
+        master_rate,
+        move |progress| {
+            if let Some(callback) = on_progress {
+                callback(progress, progress_data.get());
+            }
+        },
+        move |outcome| {
+            if let Some(callback) = on_done {
+                callback(outcome.status(), done_data.get());
+            }
+        },
+    );
+    match result {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cancel_audio_load(synth_ptr: *mut GranularSynth) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.cancel_loading();
+}
 
-    let result = 
-        synth.load_audio_from_file(
-            path_str.as_bytes().as_ptr(), path_str.len(), master_rate);
-    result
+#[no_mangle]
+pub extern "C" fn is_audio_loading(synth_ptr: *mut GranularSynth) -> bool {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.is_loading()
 }
 
+/// 0 = off (millisecond rate), 1 = internal tempo, 2 = MIDI clock.
 #[no_mangle]
-pub extern "C" fn generate_grain_envelope(
+pub extern "C" fn set_tempo_sync(synth_ptr: *mut GranularSynth, mode: c_int) -> c_int {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    match TempoSync::from_index(mode as u8) {
+        Some(sync) => {
+            synth.set_tempo_sync(sync);
+            0
+        }
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_bpm(synth_ptr: *mut GranularSynth, bpm: f32) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_bpm(bpm);
+}
+
+#[no_mangle]
+pub extern "C" fn get_bpm(synth_ptr: *mut GranularSynth) -> f32 {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.bpm()
+}
+
+/// `modifier`: 0 = straight, 1 = dotted, 2 = triplet.
+#[no_mangle]
+pub extern "C" fn set_note_division(
     synth_ptr: *mut GranularSynth,
-    size: usize,
+    denominator: c_uint,
+    modifier: c_int,
 ) {
     let synth = unsafe {
         assert!(!synth_ptr.is_null());
         &*synth_ptr
     };
-    synth.generate_grain_envelope(size);
+    synth.set_note_division(denominator, DivisionModifier::from_index(modifier as u8));
 }
 
 #[no_mangle]
-pub extern "C" fn start_scheduler(
-    synth_ptr: *mut GranularSynth
-){
+pub extern "C" fn set_swing(synth_ptr: *mut GranularSynth, swing: f32) {
     let synth = unsafe {
         assert!(!synth_ptr.is_null());
-        &mut *synth_ptr
+        &*synth_ptr
+    };
+    synth.set_swing(swing);
+}
+
+#[no_mangle]
+pub extern "C" fn set_euclidean_pattern(
+    synth_ptr: *mut GranularSynth,
+    steps: c_uint,
+    pulses: c_uint,
+    rotation: c_uint,
+) {
+    let synth = unsafe {
+        assert!(!synth_ptr.is_null());
+        &*synth_ptr
+    };
+    synth.set_euclidean_pattern(steps, pulses, rotation);
+}
+
+/// `effect`: 0 = delay, 1 = reverb, 2 = filter, 3 = EQ.
+#[no_mangle]
+pub extern "C" fn set_effect_bypass(
+    engine_ptr: *mut AudioEngine,
+    effect: c_int,
+    bypass: bool,
+) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
+    };
+    match Effect::from_index(effect as u8) {
+        Some(effect) => {
+            engine.set_effect_bypass(effect, bypass);
+            0
+        }
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_effect_mix(engine_ptr: *mut AudioEngine, effect: c_int, mix: f32) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
+    };
+    match Effect::from_index(effect as u8) {
+        Some(effect) => {
+            engine.set_effect_mix(effect, mix);
+            0
+        }
+        None => -1,
+    }
+}
+
+/// `order` points to four effect indices, each used once.
+#[no_mangle]
+pub extern "C" fn set_effect_order(engine_ptr: *mut AudioEngine, order: *const u8) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
+    };
+    if order.is_null() {
+        return -1;
+    }
+    let indices = unsafe { std::slice::from_raw_parts(order, FX_COUNT) };
+    let mut effects = [Effect::Delay; FX_COUNT];
+    for (effect, &index) in effects.iter_mut().zip(indices.iter()) {
+        match Effect::from_index(index) {
+            Some(e) => *effect = e,
+            None => return -1,
+        }
+    }
+    match engine.set_effect_order(effects) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_delay_effect(
+    engine_ptr: *mut AudioEngine,
+    time_ms: f32,
+    feedback: f32,
+    ping_pong: bool,
+) {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
+    };
+    engine.effects().set_delay(time_ms, feedback, ping_pong);
+}
+
+#[no_mangle]
+pub extern "C" fn set_reverb_effect(engine_ptr: *mut AudioEngine, room_size: f32, damping: f32) {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
+    };
+    engine.effects().set_reverb(room_size, damping);
+}
+
+/// `mode`: 0 = low-pass, 1 = high-pass, 2 = band-pass, 3 = notch.
+#[no_mangle]
+pub extern "C" fn set_filter_effect(
+    engine_ptr: *mut AudioEngine,
+    mode: c_int,
+    cutoff: f32,
+    resonance: f32,
+) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
+    };
+    match FilterMode::from_index(mode as u8) {
+        Some(mode) => {
+            engine.effects().set_filter(mode, cutoff, resonance);
+            0
+        }
+        None => -1,
+    }
+}
+
+/// `band`: 0 = low shelf, 1 = mid peak, 2 = high shelf.
+#[no_mangle]
+pub extern "C" fn set_eq_band(
+    engine_ptr: *mut AudioEngine,
+    band: c_uint,
+    freq: f32,
+    gain_db: f32,
+) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
+    };
+    match engine.effects().set_eq_band(band as usize, freq, gain_db) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_eq_mid_q(engine_ptr: *mut AudioEngine, q: f32) {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
     };
-    synth.start_scheduler();
+    engine.effects().set_eq_mid_q(q);
 }
 
 #[no_mangle]
-pub extern "C" fn stop_scheduler(
-    synth_ptr: *mut GranularSynth
-) {
-    let synth = unsafe {
-        assert!(!synth_ptr.is_null());
-        &mut *synth_ptr
+pub extern "C" fn set_master_gain(engine_ptr: *mut AudioEngine, gain: f32) {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
     };
-    synth.stop_scheduler();
+    engine.set_master_gain(gain);
 }
 
+/// Adds the synth as a new layer of the engine. The caller keeps using
+/// `synth_ptr` to load its source, set its parameters and start its
+/// scheduler. Returns the layer id, or -1.
 #[no_mangle]
-pub extern "C" fn set_params(
+pub extern "C" fn add_layer(
+    engine_ptr: *mut AudioEngine,
     synth_ptr: *mut GranularSynth,
-    start: f32,
-    duration: usize,
-    overlap: f32,
-    pitch: f32,
-){
+) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
     let synth = unsafe {
         assert!(!synth_ptr.is_null());
         &*synth_ptr
     };
-    synth.set_params(start, duration, overlap, pitch);
+    match engine.add_layer(Arc::new(synth.clone_for_thread())) {
+        Ok(id) => id as c_int,
+        Err(_) => -1,
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn set_grain_start(
-    synth_ptr: *mut GranularSynth, 
-    start: f32
-) {
-    let synth = unsafe {
-        assert!(!synth_ptr.is_null());
-        &*synth_ptr
+pub extern "C" fn remove_layer(engine_ptr: *mut AudioEngine, layer: c_uint) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
     };
-    let mut params = synth.params.lock().unwrap();
-    params.grain_start = 
-        start.clamp(0.0, 1.0) as f32 * params.specs.filesize as f32;
+    match engine.remove_layer(layer as usize) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn set_grain_duration(
-    synth_ptr: *mut GranularSynth, 
-    duration: usize
-) {
-    let synth = unsafe {
-        assert!(!synth_ptr.is_null());
-        &*synth_ptr
+pub extern "C" fn set_layer_mute(engine_ptr: *mut AudioEngine, layer: c_uint, mute: bool) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
     };
-    let mut params = synth.params.lock().unwrap();
-    params.grain_duration = duration;
+    match engine.set_layer_mute(layer as usize, mute) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn set_grain_pitch(
-    synth_ptr: *mut GranularSynth, 
-    pitch: f32
-) {
-    let synth = unsafe {
-        assert!(!synth_ptr.is_null());
-        &*synth_ptr
+pub extern "C" fn set_layer_solo(engine_ptr: *mut AudioEngine, layer: c_uint, solo: bool) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &*engine_ptr
     };
-    let mut params = synth.params.lock().unwrap();
-    params.grain_pitch = pitch.clamp(0.1, 2.0) as f32;
+    match engine.set_layer_solo(layer as usize, solo) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn set_overlap(
-    synth_ptr: *mut GranularSynth, 
-    overlap: f32
-) {
-    let synth = unsafe {
-        assert!(!synth_ptr.is_null());
-        &*synth_ptr
+pub extern "C" fn record_layer(
+    engine_ptr: *mut AudioEngine,
+    layer: c_uint,
+    output_path: *const c_char,
+) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    if output_path.is_null() {
+        return -1;
+    }
+    let c_str = unsafe { std::ffi::CStr::from_ptr(output_path) };
+    let path_str = match c_str.to_str() {
+        Ok(s) => s,
+        Err(_) => return -1,
     };
-    let mut params = synth.params.lock().unwrap();
-    params.grain_overlap = overlap.clamp(1.0, 2.0);
+    match engine.record_layer(layer as usize, path_str) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
 }
 
-
 #[no_mangle]
-pub extern "C" fn create_audio_engine(
-    synth_ptr: *mut GranularSynth,
-    sample_rate: u32,
-    channels: u16,
-    bit_depth: u16,
-    format: *const c_char,
-    device_index: usize,
-) -> *mut AudioEngine {
-    unsafe {
-        assert!(!synth_ptr.is_null());
-        let synth_ref = &*synth_ptr;
-        let arc_synth = Arc::new(synth_ref.clone_for_thread());
-
-        let mut format_str = "wav".to_string(); 
-        if !format.is_null() {
-            let cstr = std::ffi::CStr::from_ptr(format);
-            if let Ok(s) = cstr.to_str() {
-                format_str = s.to_string();
-            }
-        }
-
-        let host = cpal::default_host();
-        let devices = match host.output_devices() {
-            Ok(devices) => devices.collect::<Vec<_>>(),
-            Err(_) => Vec::new(),
-        };
-
-        let output_device = devices.get(device_index).cloned();
-        if output_device.is_none() {
-            eprintln!("Invalid device index: {}", device_index);
-            return std::ptr::null_mut();
-        }
-        
-        let user_settings = UserRecordingSettings {
-            sample_rate: Some(sample_rate),
-            channels: Some(channels),
-            bit_depth: Some(bit_depth),
-            format: Some(format_str),
-            format_specific: None,  // or fill in...
-        };
-        let engine = AudioEngine::new(arc_synth, user_settings, output_device);
-        Box::into_raw(Box::new(engine))
+pub extern "C" fn stop_layer_recording(engine_ptr: *mut AudioEngine, layer: c_uint) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    match engine.stop_layer_recording(layer as usize) {
+        Ok(_) => 0,
+        Err(_) => -1,
     }
 }
 
@@ -1236,6 +4318,140 @@ pub extern "C" fn destroy_audio_engine(engine_ptr: *mut AudioEngine) {
     }
 }
 
+#[no_mangle]
+pub extern "C" fn create_pitch_shifter(
+    sample_rate: u32,
+    channels: u16,
+    grain_ms: f32,
+) -> *mut PitchShifter {
+    let shifter = Box::new(PitchShifter::new(sample_rate, channels, grain_ms));
+    Box::into_raw(shifter)
+}
+
+#[no_mangle]
+pub extern "C" fn destroy_pitch_shifter(ptr: *mut PitchShifter) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        let _ = Box::from_raw(ptr);
+    }
+}
+
+/// `input` and `output` hold `frames` interleaved frames each and may not
+/// overlap.
+#[no_mangle]
+pub extern "C" fn pitch_shifter_process_block(
+    shifter_ptr: *mut PitchShifter,
+    input: *const f32,
+    output: *mut f32,
+    frames: usize,
+) -> c_int {
+    let shifter = unsafe {
+        assert!(!shifter_ptr.is_null());
+        &mut *shifter_ptr
+    };
+    if input.is_null() || output.is_null() {
+        return -1;
+    }
+    let len = frames * shifter.channels();
+    let input = unsafe { std::slice::from_raw_parts(input, len) };
+    let output = unsafe { std::slice::from_raw_parts_mut(output, len) };
+    match shifter.process_block(input, output) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pitch_shifter_latency(shifter_ptr: *mut PitchShifter) -> c_uint {
+    let shifter = unsafe {
+        assert!(!shifter_ptr.is_null());
+        &*shifter_ptr
+    };
+    shifter.latency() as c_uint
+}
+
+#[no_mangle]
+pub extern "C" fn pitch_shifter_set_pitch(shifter_ptr: *mut PitchShifter, pitch: f32) {
+    let shifter = unsafe {
+        assert!(!shifter_ptr.is_null());
+        &mut *shifter_ptr
+    };
+    shifter.set_pitch(pitch);
+}
+
+#[no_mangle]
+pub extern "C" fn pitch_shifter_set_feedback(shifter_ptr: *mut PitchShifter, feedback: f32) {
+    let shifter = unsafe {
+        assert!(!shifter_ptr.is_null());
+        &mut *shifter_ptr
+    };
+    shifter.set_feedback(feedback);
+}
+
+#[no_mangle]
+pub extern "C" fn pitch_shifter_set_freeze(shifter_ptr: *mut PitchShifter, frozen: bool) {
+    let shifter = unsafe {
+        assert!(!shifter_ptr.is_null());
+        &mut *shifter_ptr
+    };
+    shifter.set_frozen(frozen);
+}
+
+#[no_mangle]
+pub extern "C" fn pitch_shifter_reset(shifter_ptr: *mut PitchShifter) {
+    let shifter = unsafe {
+        assert!(!shifter_ptr.is_null());
+        &mut *shifter_ptr
+    };
+    shifter.reset();
+}
+
+/// Stretches the WAV at `input_path` by `stretch` (output over input
+/// length) and shifts its pitch by `pitch`, writing `output_path`.
+#[no_mangle]
+pub extern "C" fn time_stretch_file(
+    input_path: *const c_char,
+    output_path: *const c_char,
+    stretch: f32,
+    pitch: f32,
+) -> c_int {
+    time_stretch_file_with_curve(input_path, output_path, &0.0, &stretch, 1, pitch)
+}
+
+/// Like `time_stretch_file` with a stretch curve: `count` breakpoints of
+/// input position (0..1, ascending) and stretch factor.
+#[no_mangle]
+pub extern "C" fn time_stretch_file_with_curve(
+    input_path: *const c_char,
+    output_path: *const c_char,
+    positions: *const f32,
+    factors: *const f32,
+    count: usize,
+    pitch: f32,
+) -> c_int {
+    if input_path.is_null() || output_path.is_null() || positions.is_null() || factors.is_null()
+        || count == 0
+    {
+        return -1;
+    }
+    let input_str = unsafe { std::ffi::CStr::from_ptr(input_path) };
+    let output_str = unsafe { std::ffi::CStr::from_ptr(output_path) };
+    let (input_str, output_str) = match (input_str.to_str(), output_str.to_str()) {
+        (Ok(input), Ok(output)) => (input, output),
+        _ => return -1,
+    };
+    let positions = unsafe { std::slice::from_raw_parts(positions, count) };
+    let factors = unsafe { std::slice::from_raw_parts(factors, count) };
+    let mut settings = StretchSettings::new(1.0, pitch);
+    settings.stretch = positions.iter().copied().zip(factors.iter().copied()).collect();
+    match stretch::time_stretch_file(input_str, output_str, &settings) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn get_output_device_list() -> *mut c_char {
     let host = cpal::default_host();
@@ -1405,6 +4621,71 @@ pub extern "C" fn get_output_devices(
     DeviceList { devices: ptr, count }
 }
 
+#[no_mangle]
+pub extern "C" fn get_input_devices(
+    engine_ptr: *mut AudioEngine,
+    ) -> DeviceList {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    let mut device_infos: Vec<DeviceInfo> = engine
+        .get_input_devices()
+        .iter()
+        .map(|(idx, name)| {
+            let c_string = CString::new(name.clone())
+                .unwrap_or_else(|_| CString::new("Unknown").unwrap());
+            DeviceInfo {
+                index: *idx,
+                name: c_string.into_raw(),
+            }
+        }).collect();
+    let ptr = device_infos.as_mut_ptr();
+    let count = device_infos.len();
+    std::mem::forget(device_infos);
+
+    DeviceList { devices: ptr, count }
+}
+
+#[no_mangle]
+pub extern "C" fn set_input_device(
+    engine_ptr: *mut AudioEngine,
+    index: usize,
+) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    match engine.set_input_device_by_index(index) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn start_live_input(engine_ptr: *mut AudioEngine) -> c_int {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    match engine.start_live_input() {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn stop_live_input(engine_ptr: *mut AudioEngine) {
+    let engine = unsafe {
+        assert!(!engine_ptr.is_null());
+        &mut *engine_ptr
+    };
+    engine.stop_live_input();
+}
+
 #[no_mangle]
 pub extern "C" fn free_device_list(device_list: DeviceList) {
     if device_list.devices.is_null() {
@@ -1571,8 +4852,7 @@ pub extern "C" fn get_sample_rate(synth_ptr: *mut GranularSynth) -> c_int {
         assert!(!synth_ptr.is_null());
         &mut *synth_ptr
     };
-    let params = synth.params.lock().unwrap();
-    params.specs.sample_rate as c_int
+    synth.params.specs().sample_rate as c_int
 }
 
 #[no_mangle]
@@ -1581,6 +4861,5 @@ pub extern "C" fn get_total_channels(synth_ptr: *mut GranularSynth) -> c_int {
         assert!(!synth_ptr.is_null());
         &mut *synth_ptr
     };
-    let params = synth.params.lock().unwrap();
-    params.specs.channels as c_int
+    synth.params.specs().channels as c_int
 }
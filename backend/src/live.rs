@@ -0,0 +1,176 @@
+use crate::layers::MAX_BLOCK_FRAMES;
+use crate::{interpolate, AtomicF32, Interpolation};
+use arc_swap::{ArcSwapOption, Guard};
+use rand::Rng;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+// -------------------------------------
+// LIVE INPUT
+// -------------------------------------
+/// Seconds of input the circular buffer keeps.
+pub const LIVE_BUFFER_SECONDS: f32 = 12.0;
+/// Longest delay a live grain may start at, in milliseconds.
+pub const MAX_LIVE_DELAY_MS: f32 = 10_000.0;
+// Points read around a live grain's position, enough for every
+// interpolation: two before, three after
+const INTERPOLATION_POINTS: usize = 6;
+
+/// Circular buffer of the live input, mixed to mono. The input callback is
+/// the only writer; grains read it from the output callback. Samples are
+/// atomics so neither side ever locks.
+pub struct LiveBuffer {
+    samples: Box<[AtomicF32]>,
+    // Frames written since the buffer was created
+    written: AtomicUsize,
+}
+
+impl LiveBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: (0..capacity.max(1)).map(|_| AtomicF32::new(0.0)).collect(),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Appends one frame of interleaved input as its channel average.
+    pub fn write_frame(&self, frame: &[f32]) {
+        if frame.is_empty() {
+            return;
+        }
+        let mono = frame.iter().sum::<f32>() / frame.len() as f32;
+        let written = self.written.load(Ordering::Relaxed);
+        self.samples[written % self.capacity()].store(mono);
+        self.written.store(written + 1, Ordering::Release);
+    }
+
+    /// Position (in buffer samples) `delay` samples behind the write head.
+    pub fn position_behind(&self, delay: f32) -> f32 {
+        let capacity = self.capacity();
+        let head = (self.written.load(Ordering::Acquire) % capacity) as f32;
+        (head - delay).rem_euclid(capacity as f32)
+    }
+
+    /// Interpolated sample at position `x`, wrapping around the buffer.
+    pub fn read(&self, interpolation: Interpolation, x: f32) -> f32 {
+        let capacity = self.capacity() as isize;
+        let index = x.floor() as isize;
+        let mut points = [0.0f32; INTERPOLATION_POINTS];
+        for (k, point) in points.iter_mut().enumerate() {
+            let i = (index + k as isize - 2).rem_euclid(capacity);
+            *point = self.samples[i as usize].load();
+        }
+        interpolate(interpolation, &points, 2.0 + (x - index as f32))
+    }
+}
+
+/// Live input settings of a synth and its buffer, which is created the
+/// first time an input stream is started and recreated when one starts at
+/// another sample rate, so it always holds `LIVE_BUFFER_SECONDS`.
+pub struct LiveInput {
+    enabled: AtomicBool,
+    frozen: AtomicBool,
+    delay_min_ms: AtomicF32,
+    delay_max_ms: AtomicF32,
+    buffer: ArcSwapOption<LiveBuffer>,
+}
+
+impl LiveInput {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            frozen: AtomicBool::new(false),
+            delay_min_ms: AtomicF32::new(100.0),
+            delay_max_ms: AtomicF32::new(1000.0),
+            buffer: ArcSwapOption::empty(),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// While frozen the input is not written, so grains keep playing what
+    /// the buffer holds.
+    pub fn set_frozen(&self, frozen: bool) {
+        self.frozen.store(frozen, Ordering::Relaxed);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Relaxed)
+    }
+
+    /// In milliseconds, 0..`MAX_LIVE_DELAY_MS`.
+    pub fn set_delay_range(&self, min_ms: f32, max_ms: f32) {
+        let (min_ms, max_ms) = (
+            min_ms.clamp(0.0, MAX_LIVE_DELAY_MS),
+            max_ms.clamp(0.0, MAX_LIVE_DELAY_MS),
+        );
+        self.delay_min_ms.store(min_ms.min(max_ms));
+        self.delay_max_ms.store(min_ms.max(max_ms));
+    }
+
+    /// The buffer for `sample_rate`, replacing one sized for another rate.
+    /// Grains still reading the old buffer go silent; it is freed off the
+    /// audio thread.
+    pub fn buffer_for(&self, sample_rate: u32) -> Arc<LiveBuffer> {
+        let capacity = ((LIVE_BUFFER_SECONDS * sample_rate as f32) as usize).max(1);
+        if let Some(buffer) = self.buffer.load_full().filter(|b| b.capacity() == capacity) {
+            return buffer;
+        }
+        let buffer = Arc::new(LiveBuffer::new(capacity));
+        if let Some(previous) = self.buffer.swap(Some(Arc::clone(&buffer))) {
+            crate::loader::retire(previous);
+        }
+        buffer
+    }
+
+    /// The buffer as it is now, borrowed without locking; hold it no longer
+    /// than a block.
+    pub fn buffer(&self) -> Guard<Option<Arc<LiveBuffer>>> {
+        self.buffer.load()
+    }
+
+    /// Start of the next live grain, `length` samples long at
+    /// `playback_rate`: a random delay from the range behind the write
+    /// head. `None` when live input is off or was never started.
+    pub fn grain_start(&self, length: usize, playback_rate: f32, sample_rate: u32) -> Option<f32> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let buffer = self.buffer();
+        let buffer = buffer.as_deref()?;
+        let (min, max) = (self.delay_min_ms.load(), self.delay_max_ms.load());
+        let delay_ms = min + (max - min) * rand::thread_rng().gen_range(0.0..=1.0);
+        let delay = delay_ms / 1000.0 * sample_rate as f32;
+        // The grain must stay behind the write head, which it closes in on
+        // faster than real time (or at all, when frozen), and ahead of the
+        // writes that overwrite the oldest samples during its lifetime
+        let (length, margin) = (length as f32, 2.0 * MAX_BLOCK_FRAMES as f32);
+        let (reach, lifetime) = if self.is_frozen() {
+            (length * playback_rate, 0.0)
+        } else {
+            (length * (playback_rate - 1.0), length)
+        };
+        let shortest = reach.max(0.0) + margin;
+        let longest = (buffer.capacity() as f32 - lifetime - margin).max(shortest);
+        let delay = delay.clamp(shortest, longest);
+        Some(buffer.position_behind(delay))
+    }
+}
+
+impl Default for LiveInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
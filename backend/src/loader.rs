@@ -0,0 +1,227 @@
+use crate::bank::SourceBank;
+use crate::resample_to_master;
+use crossbeam_channel::{Receiver, Sender};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// -------------------------------------
+// ASYNC LOADING
+// -------------------------------------
+// Samples decoded between progress reports and cancellation checks
+pub(crate) const PROGRESS_INTERVAL: usize = 1 << 16;
+// Share of the progress taken by decoding, then by resampling; the rest is
+// the swap and the analyses of the main source
+const DECODE_SHARE: f32 = 0.8;
+const RESAMPLE_SHARE: f32 = 0.1;
+// Grains may still be on their way to the audio thread right after a swap,
+// so the retired buffer is kept at least this long
+const RETIRE_SETTLE: Duration = Duration::from_millis(200);
+// ...and at most this long, should the audio thread stop reporting
+const RETIRE_TIMEOUT: Duration = Duration::from_secs(60);
+const RETIRE_POLL: Duration = Duration::from_millis(10);
+
+/// How a background load ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadOutcome {
+    Loaded,
+    Cancelled,
+    Failed(String),
+}
+
+impl LoadOutcome {
+    /// 0 loaded, 1 cancelled, -1 failed.
+    pub fn status(&self) -> i32 {
+        match self {
+            LoadOutcome::Loaded => 0,
+            LoadOutcome::Cancelled => 1,
+            LoadOutcome::Failed(_) => -1,
+        }
+    }
+}
+
+/// Reads a WAV file and resamples it to `master_rate`; returns the samples
+/// (interleaved as in the file) and the channel count, or `None` once
+/// `cancel` is raised. `progress` gets 0..`DECODE_SHARE + RESAMPLE_SHARE`.
+pub(crate) fn decode_file(
+    path: &str,
+    master_rate: u32,
+    cancel: &AtomicBool,
+    progress: &mut dyn FnMut(f32),
+) -> Result<Option<(Vec<f32>, u16)>, String> {
+    let mut reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+    let spec = reader.spec();
+    let input_sample_rate = spec.sample_rate; // e.g. 48000
+    let input_channels = spec.channels;
+
+    let total = (reader.len() as usize).max(1);
+    let mut float_samples = Vec::with_capacity(total);
+    for (i, sample) in reader.samples::<i16>().enumerate() {
+        if let Ok(sample_i16) = sample {
+            float_samples.push(sample_i16 as f32 / 32768.0);
+        }
+        if i % PROGRESS_INTERVAL == 0 {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            progress(DECODE_SHARE * i as f32 / total as f32);
+        }
+    }
+    progress(DECODE_SHARE);
+
+    let final_samples = match resample_to_master(
+        &float_samples,
+        input_channels,
+        input_sample_rate,
+        master_rate,
+        cancel,
+    ) {
+        Some(samples) => samples,
+        None => return Ok(None),
+    };
+    progress(DECODE_SHARE + RESAMPLE_SHARE);
+    Ok(Some((final_samples, input_channels)))
+}
+
+/// The background load of a synth, if any. One runs at a time; starting
+/// another cancels it.
+pub struct Loader {
+    job: Mutex<Option<(Arc<AtomicBool>, JoinHandle<()>)>>,
+    // Held from the last cancellation check to the end of a swap, so a
+    // cancelled load can never swap in after the one that replaced it
+    swap: Mutex<()>,
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        Self {
+            job: Mutex::new(None),
+            swap: Mutex::new(()),
+        }
+    }
+
+    /// Keeps track of a load thread and its cancel flag.
+    pub fn start(&self, cancel: Arc<AtomicBool>, handle: JoinHandle<()>) {
+        *self.job.lock().unwrap() = Some((cancel, handle));
+    }
+
+    /// Whether a load is still decoding or swapping.
+    pub fn is_loading(&self) -> bool {
+        self.job
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(_, handle)| !handle.is_finished())
+    }
+
+    /// Raises the cancel flag of the running load and returns at once; the
+    /// thread is detached and stops at its next check, before the swap.
+    pub fn cancel(&self) {
+        let job = self.job.lock().unwrap().take();
+        if let Some((cancel, _handle)) = job {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn swap_lock(&self) -> MutexGuard<'_, ()> {
+        self.swap.lock().unwrap()
+    }
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// -------------------------------------
+// RETIRE THREAD
+// -------------------------------------
+/// Something the retire thread holds on to until it can be let go.
+trait Retiring: Send {
+    /// Whether it can be dropped now.
+    fn is_done(&mut self) -> bool;
+}
+
+/// A snapshot swapped out of an `ArcSwap`, done once nothing else holds it.
+impl<T: Send + Sync> Retiring for Arc<T> {
+    fn is_done(&mut self) -> bool {
+        Arc::strong_count(self) == 1
+    }
+}
+
+/// The buffers a bank slot retired up to a generation, done once no grain
+/// reads a retired buffer of the slot.
+struct RetiredBuffers {
+    bank: Arc<SourceBank>,
+    slot: usize,
+    generation: u32,
+    swapped: Instant,
+}
+
+impl Retiring for RetiredBuffers {
+    fn is_done(&mut self) -> bool {
+        let waited = self.swapped.elapsed();
+        if waited < RETIRE_SETTLE
+            || (waited < RETIRE_TIMEOUT && self.bank.is_retired_in_use(self.slot))
+        {
+            return false;
+        }
+        self.bank.release_retired(self.slot, self.generation);
+        true
+    }
+}
+
+fn retire_thread(receiver: Receiver<Box<dyn Retiring>>) {
+    let mut pending: Vec<Box<dyn Retiring>> = Vec::new();
+    loop {
+        // Sleep on the channel while there is nothing to watch
+        if pending.is_empty() {
+            match receiver.recv() {
+                Ok(item) => pending.push(item),
+                Err(_) => return,
+            }
+        } else {
+            thread::sleep(RETIRE_POLL);
+        }
+        pending.extend(receiver.try_iter());
+        pending.retain_mut(|item| !item.is_done());
+    }
+}
+
+// One thread per process, started by the first item
+fn send_to_retire_thread(item: Box<dyn Retiring>) {
+    static SENDER: OnceLock<Sender<Box<dyn Retiring>>> = OnceLock::new();
+    let sender = SENDER.get_or_init(|| {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        thread::spawn(move || retire_thread(receiver));
+        sender
+    });
+    let _ = sender.send(item);
+}
+
+/// Drops a snapshot swapped out of an `ArcSwap` once nothing else holds it.
+/// The audio thread borrows snapshots for a block at a time and must not be
+/// the one to free them, and the caller doesn't wait for it: a snapshot
+/// still in use goes to the retire thread.
+pub(crate) fn retire<T: Send + Sync + 'static>(snapshot: Arc<T>) {
+    if Arc::strong_count(&snapshot) > 1 {
+        send_to_retire_thread(Box::new(snapshot));
+    }
+}
+
+/// Frees the buffers `slot` retired up to becoming `generation`, on the
+/// retire thread once no grain reads them.
+pub(crate) fn release_when_unused(bank: Arc<SourceBank>, slot: usize, generation: u32) {
+    send_to_retire_thread(Box::new(RetiredBuffers {
+        bank,
+        slot,
+        generation,
+        swapped: Instant::now(),
+    }));
+}
@@ -0,0 +1,299 @@
+use crate::AtomicF32;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+    Mutex,
+};
+
+// -------------------------------------
+// MIDI MESSAGES
+// -------------------------------------
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiEvent {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    // System real-time messages, for clock sync
+    Clock,
+    Start,
+    Continue,
+    Stop,
+}
+
+/// Parses one raw MIDI message. A note-on with velocity 0 is a note-off.
+/// Anything we don't use yet returns `None`.
+pub fn parse_midi_message(bytes: &[u8]) -> Option<MidiEvent> {
+    let status = *bytes.first()?;
+    match status {
+        0xF8 => return Some(MidiEvent::Clock),
+        0xFA => return Some(MidiEvent::Start),
+        0xFB => return Some(MidiEvent::Continue),
+        0xFC => return Some(MidiEvent::Stop),
+        _ => {}
+    }
+    let channel = status & 0x0F;
+    match (status & 0xF0, bytes.get(1), bytes.get(2)) {
+        (0x90, Some(&note), Some(&velocity)) if velocity > 0 => {
+            Some(MidiEvent::NoteOn { channel, note, velocity })
+        }
+        (0x90, Some(&note), Some(_)) | (0x80, Some(&note), Some(_)) => {
+            Some(MidiEvent::NoteOff { channel, note })
+        }
+        (0xB0, Some(&controller), Some(&value)) => {
+            Some(MidiEvent::ControlChange { channel, controller, value })
+        }
+        _ => None,
+    }
+}
+
+// -------------------------------------
+// NOTE VOICES
+// -------------------------------------
+pub const MAX_POLYPHONY: usize = 16;
+
+/// One playable cloud. Written by the MIDI side, read by the scheduler and
+/// the audio callback. A voice sounds while `triggers != retired`; the audio
+/// callback sets `retired` once the voice's release has finished.
+pub struct NoteVoice {
+    note: AtomicU8,
+    velocity: AtomicF32,
+    gate: AtomicBool,
+    triggers: AtomicU32,
+    retired: AtomicU32,
+}
+
+impl NoteVoice {
+    fn new() -> Self {
+        Self {
+            note: AtomicU8::new(0),
+            velocity: AtomicF32::new(0.0),
+            gate: AtomicBool::new(false),
+            triggers: AtomicU32::new(0),
+            retired: AtomicU32::new(0),
+        }
+    }
+
+    pub fn note(&self) -> u8 {
+        self.note.load(Ordering::Relaxed)
+    }
+
+    /// Velocity mapped to 0..1.
+    pub fn velocity(&self) -> f32 {
+        self.velocity.load()
+    }
+
+    pub fn gate(&self) -> bool {
+        self.gate.load(Ordering::Relaxed)
+    }
+
+    pub fn triggers(&self) -> u32 {
+        self.triggers.load(Ordering::Acquire)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.triggers() != self.retired.load(Ordering::Acquire)
+    }
+
+    /// Called by the audio callback when the envelope started by trigger
+    /// number `trigger` has fully released.
+    pub fn retire(&self, trigger: u32) {
+        self.retired.store(trigger, Ordering::Release);
+    }
+}
+
+/// Voice allocation bookkeeping; only touched from the MIDI/control side.
+struct Allocator {
+    // Allocation order of each voice, for stealing the oldest
+    ages: [u64; MAX_POLYPHONY],
+    clock: u64,
+}
+
+/// Note-to-voice handling: each note-on starts a cloud transposed from
+/// `root_note`, each note-off releases it.
+pub struct MidiState {
+    voices: [NoteVoice; MAX_POLYPHONY],
+    polyphony: AtomicUsize,
+    root_note: AtomicU8,
+    // In MIDI mode only note voices play; the free-running cloud is silent
+    midi_mode: AtomicBool,
+    allocator: Mutex<Allocator>,
+}
+
+impl MidiState {
+    pub fn new() -> Self {
+        Self {
+            voices: std::array::from_fn(|_| NoteVoice::new()),
+            polyphony: AtomicUsize::new(8),
+            root_note: AtomicU8::new(60),
+            midi_mode: AtomicBool::new(false),
+            allocator: Mutex::new(Allocator {
+                ages: [0; MAX_POLYPHONY],
+                clock: 0,
+            }),
+        }
+    }
+
+    pub fn voices(&self) -> &[NoteVoice] {
+        &self.voices
+    }
+
+    pub fn polyphony(&self) -> usize {
+        self.polyphony.load(Ordering::Relaxed)
+    }
+
+    /// Voices above the new limit are released.
+    pub fn set_polyphony(&self, polyphony: usize) {
+        let polyphony = polyphony.clamp(1, MAX_POLYPHONY);
+        self.polyphony.store(polyphony, Ordering::Relaxed);
+        for voice in self.voices[polyphony..].iter() {
+            voice.gate.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn root_note(&self) -> u8 {
+        self.root_note.load(Ordering::Relaxed)
+    }
+
+    pub fn set_root_note(&self, note: u8) {
+        self.root_note.store(note.min(127), Ordering::Relaxed);
+    }
+
+    pub fn is_midi_mode(&self) -> bool {
+        self.midi_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn set_midi_mode(&self, enabled: bool) {
+        self.midi_mode.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Pitch multiplier of `note` relative to the root key.
+    pub fn transpose(&self, note: u8) -> f32 {
+        2.0_f32.powf((note as f32 - self.root_note() as f32) / 12.0)
+    }
+
+    pub fn note_on(&self, note: u8, velocity: u8) {
+        let mut allocator = self.allocator.lock().unwrap();
+        let polyphony = self.polyphony();
+        let voices = &self.voices[..polyphony];
+        // Same note still held: retrigger it. Otherwise a free voice, or
+        // steal the oldest one.
+        let index = voices
+            .iter()
+            .position(|v| v.is_active() && v.gate() && v.note() == note)
+            .or_else(|| voices.iter().position(|v| !v.is_active()))
+            .unwrap_or_else(|| {
+                (0..polyphony)
+                    .min_by_key(|&i| allocator.ages[i])
+                    .unwrap_or(0)
+            });
+
+        allocator.clock += 1;
+        allocator.ages[index] = allocator.clock;
+
+        let voice = &self.voices[index];
+        voice.note.store(note, Ordering::Relaxed);
+        voice.velocity.store(velocity.min(127) as f32 / 127.0);
+        voice.gate.store(true, Ordering::Relaxed);
+        voice.triggers.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn note_off(&self, note: u8) {
+        let _allocator = self.allocator.lock().unwrap();
+        for voice in self.voices.iter() {
+            if voice.gate() && voice.note() == note {
+                voice.gate.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn all_notes_off(&self) {
+        for voice in self.voices.iter() {
+            voice.gate.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Handles a parsed event; returns `false` for events this does not use.
+    pub fn handle_event(&self, event: MidiEvent) -> bool {
+        match event {
+            MidiEvent::NoteOn { note, velocity, .. } => self.note_on(note, velocity),
+            MidiEvent::NoteOff { note, .. } => self.note_off(note),
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Default for MidiState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// -------------------------------------
+// MIDI INPUT PORTS
+// -------------------------------------
+const CLIENT_NAME: &str = "GranularSynth";
+
+pub fn get_midi_input_ports() -> Vec<(usize, String)> {
+    let input = match MidiInput::new(CLIENT_NAME) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Failed to open MIDI input: {}", e);
+            return Vec::new();
+        }
+    };
+    input
+        .ports()
+        .iter()
+        .enumerate()
+        .map(|(index, port)| {
+            let name = input.port_name(port).unwrap_or("Unknown".to_string());
+            (index, name)
+        })
+        .collect()
+}
+
+/// Connects to hardware/software MIDI port `index`. Incoming messages are
+/// passed to `handler` on midir's thread for as long as the connection lives.
+pub fn connect_midi_input<F>(index: usize, mut handler: F) -> Result<MidiInputConnection<()>, String>
+where
+    F: FnMut(&[u8]) + Send + 'static,
+{
+    let mut input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    // Keep clock/start/stop messages
+    input.ignore(Ignore::None);
+    let ports = input.ports();
+    let port = ports.get(index).ok_or("Invalid MIDI port index")?;
+    input
+        .connect(port, "granular-in", move |_, message, _| handler(message), ())
+        .map_err(|e| e.to_string())
+}
+
+/// Creates a virtual input port other applications can connect to.
+#[cfg(unix)]
+pub fn create_virtual_midi_input<F>(
+    port_name: &str,
+    mut handler: F,
+) -> Result<MidiInputConnection<()>, String>
+where
+    F: FnMut(&[u8]) + Send + 'static,
+{
+    use midir::os::unix::VirtualInput;
+    let mut input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    input.ignore(Ignore::None);
+    input
+        .create_virtual(port_name, move |_, message, _| handler(message), ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+pub fn create_virtual_midi_input<F>(
+    _port_name: &str,
+    _handler: F,
+) -> Result<MidiInputConnection<()>, String>
+where
+    F: FnMut(&[u8]) + Send + 'static,
+{
+    Err("Virtual MIDI ports are not supported on this platform".to_string())
+}
+
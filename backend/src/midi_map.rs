@@ -0,0 +1,315 @@
+use crate::SharedParams;
+use serde::{Deserialize, Serialize};
+
+// -------------------------------------
+// MIDI CC MAPPING
+// -------------------------------------
+const NRPN_PARAM_MSB: u8 = 99;
+const NRPN_PARAM_LSB: u8 = 98;
+const RPN_PARAM_MSB: u8 = 101;
+const RPN_PARAM_LSB: u8 = 100;
+// Parameter number 127/127 deselects (the "null" RPN/NRPN)
+const NULL_PARAMETER: u16 = 0x3FFF;
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+
+/// Where a mapped value comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CcSource {
+    /// Plain 7-bit controller
+    Cc7 { channel: u8, controller: u8 },
+    /// 14-bit controller pair: `controller` (0..31) is the MSB,
+    /// `controller + 32` the LSB
+    Cc14 { channel: u8, controller: u8 },
+    /// Non-registered parameter number, 14-bit data entry
+    Nrpn { channel: u8, parameter: u16 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MappingTarget {
+    GrainStart,
+    GrainDuration,
+    GrainPitch,
+    Overlap,
+    Gain,
+    Pan,
+}
+
+impl MappingTarget {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(MappingTarget::GrainStart),
+            1 => Some(MappingTarget::GrainDuration),
+            2 => Some(MappingTarget::GrainPitch),
+            3 => Some(MappingTarget::Overlap),
+            4 => Some(MappingTarget::Gain),
+            5 => Some(MappingTarget::Pan),
+            _ => None,
+        }
+    }
+
+    /// Full range of the parameter, used when no range is given.
+    pub fn default_range(self) -> (f32, f32) {
+        match self {
+            MappingTarget::GrainStart => (0.0, 1.0),
+            MappingTarget::GrainDuration => (10.0, 1000.0),
+            MappingTarget::GrainPitch => (0.5, 2.0),
+            MappingTarget::Overlap => (1.0, 2.0),
+            MappingTarget::Gain => (0.0, 1.0),
+            MappingTarget::Pan => (-1.0, 1.0),
+        }
+    }
+
+    pub fn apply(self, params: &SharedParams, value: f32) {
+        match self {
+            MappingTarget::GrainStart => params.set_grain_start(value),
+            MappingTarget::GrainDuration => params.set_grain_duration(value.max(1.0) as usize),
+            MappingTarget::GrainPitch => params.set_grain_pitch(value),
+            MappingTarget::Overlap => params.set_grain_overlap(value),
+            MappingTarget::Gain => params.set_gain(value),
+            MappingTarget::Pan => params.set_pan(value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MappingCurve {
+    Linear,
+    /// Equal ratios per step (geometric) when min and max share a sign,
+    /// a squared response otherwise
+    Exponential,
+}
+
+impl MappingCurve {
+    pub fn from_index(index: u8) -> Self {
+        match index {
+            1 => MappingCurve::Exponential,
+            _ => MappingCurve::Linear,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CcMapping {
+    pub source: CcSource,
+    pub target: MappingTarget,
+    pub min: f32,
+    pub max: f32,
+    pub curve: MappingCurve,
+}
+
+impl CcMapping {
+    /// Maps a normalized controller position (0..1) into the target range.
+    pub fn scale(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self.curve {
+            MappingCurve::Linear => self.min + (self.max - self.min) * x,
+            MappingCurve::Exponential if self.min * self.max > 0.0 => {
+                self.min * (self.max / self.min).powf(x)
+            }
+            MappingCurve::Exponential => self.min + (self.max - self.min) * x * x,
+        }
+    }
+}
+
+/// A learn request waiting for the next controller to move.
+#[derive(Clone, Copy)]
+struct PendingLearn {
+    target: MappingTarget,
+    min: f32,
+    max: f32,
+    curve: MappingCurve,
+}
+
+/// Which kind of parameter number the data entry controllers address.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum ParameterSelect {
+    #[default]
+    None,
+    Rpn,
+    Nrpn,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ChannelState {
+    // Last MSB of every 14-bit controller pair
+    cc_msb: [u8; 32],
+    selected: ParameterSelect,
+    nrpn_param_msb: Option<u8>,
+    nrpn_param_lsb: Option<u8>,
+    data_msb: u8,
+}
+
+impl ChannelState {
+    /// The NRPN data entry goes to, if an NRPN is the active selection.
+    fn nrpn_parameter(&self) -> Option<u16> {
+        if self.selected != ParameterSelect::Nrpn {
+            return None;
+        }
+        let parameter = ((self.nrpn_param_msb? as u16) << 7) | self.nrpn_param_lsb? as u16;
+        (parameter != NULL_PARAMETER).then_some(parameter)
+    }
+}
+
+/// The mapping table plus the running state needed to decode 14-bit
+/// controllers and NRPNs. Lives behind a lock on the MIDI side only.
+pub struct CcMapper {
+    mappings: Vec<CcMapping>,
+    learn: Option<PendingLearn>,
+    // Index of a just-learned 7-bit mapping that becomes 14-bit if its LSB
+    // partner arrives next
+    learned_msb: Option<usize>,
+    channels: [ChannelState; 16],
+}
+
+impl CcMapper {
+    pub fn new() -> Self {
+        Self {
+            mappings: Vec::new(),
+            learn: None,
+            learned_msb: None,
+            channels: [ChannelState::default(); 16],
+        }
+    }
+
+    pub fn mappings(&self) -> &[CcMapping] {
+        &self.mappings
+    }
+
+    /// Adds a mapping, replacing any other mapping of the same target.
+    pub fn set_mapping(&mut self, mapping: CcMapping) {
+        self.mappings.retain(|m| m.target != mapping.target);
+        self.mappings.push(mapping);
+    }
+
+    pub fn set_mappings(&mut self, mappings: Vec<CcMapping>) {
+        self.mappings = mappings;
+        self.learned_msb = None;
+    }
+
+    pub fn remove_mapping(&mut self, target: MappingTarget) {
+        self.mappings.retain(|m| m.target != target);
+        self.learned_msb = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.mappings.clear();
+        self.learned_msb = None;
+    }
+
+    /// The next controller that moves gets mapped to `target`.
+    pub fn start_learn(&mut self, target: MappingTarget, min: f32, max: f32, curve: MappingCurve) {
+        self.learn = Some(PendingLearn { target, min, max, curve });
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learn = None;
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learn.is_some()
+    }
+
+    /// Handles one control change message.
+    pub fn handle_cc(&mut self, channel: u8, controller: u8, value: u8, params: &SharedParams) {
+        let channel = channel & 0x0F;
+        let state = &mut self.channels[channel as usize];
+        match controller {
+            NRPN_PARAM_MSB => {
+                state.nrpn_param_msb = Some(value);
+                state.selected = ParameterSelect::Nrpn;
+            }
+            NRPN_PARAM_LSB => {
+                state.nrpn_param_lsb = Some(value);
+                state.selected = ParameterSelect::Nrpn;
+            }
+            // RPNs (pitch-bend range, tuning) aren't mapped, but selecting
+            // one takes data entry away from the last NRPN
+            RPN_PARAM_MSB | RPN_PARAM_LSB => state.selected = ParameterSelect::Rpn,
+            // Once a parameter number has been selected, data entry only
+            // belongs to an NRPN; for an RPN or a null parameter it's dropped
+            DATA_ENTRY_MSB | DATA_ENTRY_LSB if state.selected != ParameterSelect::None => {
+                let Some(parameter) = state.nrpn_parameter() else {
+                    return;
+                };
+                let data = if controller == DATA_ENTRY_MSB {
+                    state.data_msb = value;
+                    (value as u16) << 7
+                } else {
+                    ((state.data_msb as u16) << 7) | value as u16
+                };
+                let source = CcSource::Nrpn { channel, parameter };
+                if let Some(learn) = self.learn.take() {
+                    self.learn_source(source, learn);
+                }
+                self.apply(source, data as f32 / 16383.0, params);
+            }
+            _ => self.handle_plain_cc(channel, controller, value, params),
+        }
+    }
+
+    fn handle_plain_cc(&mut self, channel: u8, controller: u8, value: u8, params: &SharedParams) {
+        let state = &mut self.channels[channel as usize];
+        // 14-bit pairs: MSB on 0..31, LSB on 32..63
+        let (cc14_value, cc14_controller) = match controller {
+            0..=31 => {
+                state.cc_msb[controller as usize] = value;
+                ((value as u16) << 7, controller)
+            }
+            32..=63 => {
+                let msb = state.cc_msb[(controller - 32) as usize];
+                (((msb as u16) << 7) | value as u16, controller - 32)
+            }
+            _ => (0, 0),
+        };
+
+        // A learned MSB followed by its LSB means the controller is 14-bit
+        if let Some(index) = self.learned_msb.take() {
+            if let Some(mapping) = self.mappings.get_mut(index) {
+                if (32..=63).contains(&controller)
+                    && mapping.source == (CcSource::Cc7 { channel, controller: controller - 32 })
+                {
+                    mapping.source = CcSource::Cc14 { channel, controller: controller - 32 };
+                }
+            }
+        }
+
+        if let Some(learn) = self.learn.take() {
+            self.learn_source(CcSource::Cc7 { channel, controller }, learn);
+            if controller < 32 {
+                self.learned_msb = Some(self.mappings.len() - 1);
+            }
+        }
+
+        self.apply(CcSource::Cc7 { channel, controller }, value as f32 / 127.0, params);
+        if controller < 64 {
+            self.apply(
+                CcSource::Cc14 { channel, controller: cc14_controller },
+                cc14_value as f32 / 16383.0,
+                params,
+            );
+        }
+    }
+
+    fn learn_source(&mut self, source: CcSource, learn: PendingLearn) {
+        self.set_mapping(CcMapping {
+            source,
+            target: learn.target,
+            min: learn.min,
+            max: learn.max,
+            curve: learn.curve,
+        });
+    }
+
+    fn apply(&self, source: CcSource, x: f32, params: &SharedParams) {
+        for mapping in self.mappings.iter().filter(|m| m.source == source) {
+            mapping.target.apply(params, mapping.scale(x));
+        }
+    }
+}
+
+impl Default for CcMapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{AtomicF32, GrainParams};
+use rand::Rng;
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+// -------------------------------------
+// MODULATION SOURCES
+// -------------------------------------
+pub const MAX_LFOS: usize = 4;
+pub const MAX_ENVELOPE_FOLLOWERS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleAndHold,
+    SmoothRandom,
+}
+
+impl LfoShape {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(LfoShape::Sine),
+            1 => Some(LfoShape::Triangle),
+            2 => Some(LfoShape::Saw),
+            3 => Some(LfoShape::Square),
+            4 => Some(LfoShape::SampleAndHold),
+            5 => Some(LfoShape::SmoothRandom),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub rate_hz: f32,
+}
+
+impl Default for LfoSettings {
+    fn default() -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            rate_hz: 1.0,
+        }
+    }
+}
+
+/// Running state of one LFO. Outputs are bipolar, -1..1.
+#[derive(Clone, Copy, Default)]
+pub struct Lfo {
+    phase: f32,
+    held: f32,
+    random_from: f32,
+    random_to: f32,
+}
+
+impl Lfo {
+    /// Moves the LFO `dt` seconds forward and returns its new value.
+    pub fn advance(&mut self, settings: &LfoSettings, dt: f32) -> f32 {
+        self.phase += settings.rate_hz.max(0.0) * dt;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            let mut rng = rand::thread_rng();
+            self.held = rng.gen_range(-1.0..=1.0);
+            self.random_from = self.random_to;
+            self.random_to = rng.gen_range(-1.0..=1.0);
+        }
+        let p = self.phase;
+        match settings.shape {
+            LfoShape::Sine => (2.0 * PI * p).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (p - 0.5).abs(),
+            LfoShape::Saw => 2.0 * p - 1.0,
+            LfoShape::Square => if p < 0.5 { 1.0 } else { -1.0 },
+            LfoShape::SampleAndHold => self.held,
+            LfoShape::SmoothRandom => {
+                // Cosine glide from the previous random point to the next one
+                let t = 0.5 - 0.5 * (PI * p).cos();
+                self.random_from + (self.random_to - self.random_from) * t
+            }
+        }
+    }
+}
+
+/// Attack/release settings of an envelope follower plus the level the audio
+/// callback publishes for the scheduler. Levels are unipolar, 0..1.
+pub struct EnvelopeFollowerSlot {
+    attack_ms: AtomicF32,
+    release_ms: AtomicF32,
+    level: AtomicF32,
+}
+
+impl EnvelopeFollowerSlot {
+    fn new() -> Self {
+        Self {
+            attack_ms: AtomicF32::new(10.0),
+            release_ms: AtomicF32::new(200.0),
+            level: AtomicF32::new(0.0),
+        }
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level.load()
+    }
+}
+
+/// Follows the output of the synth. Owned by the audio callback.
+#[derive(Clone, Copy, Default)]
+pub struct EnvelopeFollower {
+    level: f32,
+}
+
+impl EnvelopeFollower {
+    pub fn process(&mut self, sample: f32, attack_coeff: f32, release_coeff: f32) -> f32 {
+        let x = sample.abs();
+        let coeff = if x > self.level { attack_coeff } else { release_coeff };
+        self.level = coeff * self.level + (1.0 - coeff) * x;
+        self.level
+    }
+}
+
+fn follower_coeff(time_ms: f32, sample_rate: u32) -> f32 {
+    let samples = time_ms.max(0.0) / 1000.0 * sample_rate as f32;
+    if samples < 1.0 {
+        0.0
+    } else {
+        (-1.0 / samples).exp()
+    }
+}
+
+// -------------------------------------
+// MODULATION MATRIX
+// -------------------------------------
+#[derive(Clone, Copy, PartialEq)]
+pub enum ModSource {
+    Lfo(usize),
+    EnvelopeFollower(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ModTarget {
+    /// depth in fractions of the source file
+    Start,
+    /// depth in semitones
+    Pitch,
+    /// depth as a fraction of the grain duration
+    Duration,
+    /// depth as a fraction of the grain rate (overlap)
+    Density,
+    /// depth in pan units (-1..1)
+    Pan,
+    /// depth as a fraction of the grain amplitude
+    Amplitude,
+}
+
+impl ModTarget {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(ModTarget::Start),
+            1 => Some(ModTarget::Pitch),
+            2 => Some(ModTarget::Duration),
+            3 => Some(ModTarget::Density),
+            4 => Some(ModTarget::Pan),
+            5 => Some(ModTarget::Amplitude),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ModRoute {
+    pub source: ModSource,
+    pub target: ModTarget,
+    pub depth: f32,
+}
+
+#[derive(Clone, Default)]
+pub struct ModConfig {
+    pub lfos: [LfoSettings; MAX_LFOS],
+    pub routes: Vec<ModRoute>,
+}
+
+/// Modulation settings shared between the control side and the scheduler.
+/// The scheduler keeps its own copy and only re-reads the config when
+/// `version` changes.
+pub struct Modulation {
+    config: Mutex<ModConfig>,
+    version: AtomicUsize,
+    followers: [EnvelopeFollowerSlot; MAX_ENVELOPE_FOLLOWERS],
+}
+
+impl Modulation {
+    pub fn new() -> Self {
+        Self {
+            config: Mutex::new(ModConfig::default()),
+            version: AtomicUsize::new(0),
+            followers: std::array::from_fn(|_| EnvelopeFollowerSlot::new()),
+        }
+    }
+
+    fn edit<R>(&self, f: impl FnOnce(&mut ModConfig) -> R) -> R {
+        let mut config = self.config.lock().unwrap();
+        let result = f(&mut config);
+        self.version.fetch_add(1, Ordering::Release);
+        result
+    }
+
+    pub fn set_lfo(&self, index: usize, shape: LfoShape, rate_hz: f32) -> Result<(), String> {
+        if index >= MAX_LFOS {
+            return Err(format!("Invalid LFO index: {}", index));
+        }
+        self.edit(|config| {
+            config.lfos[index] = LfoSettings { shape, rate_hz: rate_hz.max(0.0) };
+        });
+        Ok(())
+    }
+
+    pub fn set_envelope_follower(
+        &self,
+        index: usize,
+        attack_ms: f32,
+        release_ms: f32,
+    ) -> Result<(), String> {
+        let slot = self.followers
+            .get(index)
+            .ok_or(format!("Invalid envelope follower index: {}", index))?;
+        slot.attack_ms.store(attack_ms.max(0.0));
+        slot.release_ms.store(release_ms.max(0.0));
+        Ok(())
+    }
+
+    /// Returns the index of the new route.
+    pub fn add_route(&self, route: ModRoute) -> Result<usize, String> {
+        match route.source {
+            ModSource::Lfo(i) if i >= MAX_LFOS => {
+                return Err(format!("Invalid LFO index: {}", i));
+            }
+            ModSource::EnvelopeFollower(i) if i >= MAX_ENVELOPE_FOLLOWERS => {
+                return Err(format!("Invalid envelope follower index: {}", i));
+            }
+            _ => {}
+        }
+        Ok(self.edit(|config| {
+            config.routes.push(route);
+            config.routes.len() - 1
+        }))
+    }
+
+    pub fn remove_route(&self, index: usize) -> Result<(), String> {
+        self.edit(|config| {
+            if index < config.routes.len() {
+                config.routes.remove(index);
+                Ok(())
+            } else {
+                Err(format!("Invalid modulation route index: {}", index))
+            }
+        })
+    }
+
+    pub fn clear_routes(&self) {
+        self.edit(|config| config.routes.clear());
+    }
+
+    pub fn follower_level(&self, index: usize) -> f32 {
+        self.followers.get(index).map_or(0.0, |f| f.level())
+    }
+}
+
+impl Default for Modulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Envelope followers as run by the audio callback on the output mix.
+pub struct FollowerRunner {
+    followers: [EnvelopeFollower; MAX_ENVELOPE_FOLLOWERS],
+    coeffs: [(f32, f32); MAX_ENVELOPE_FOLLOWERS],
+}
+
+impl FollowerRunner {
+    pub fn new() -> Self {
+        Self {
+            followers: [EnvelopeFollower::default(); MAX_ENVELOPE_FOLLOWERS],
+            coeffs: [(0.0, 0.0); MAX_ENVELOPE_FOLLOWERS],
+        }
+    }
+
+    /// Call once per block before `process`.
+    pub fn prepare(&mut self, modulation: &Modulation, sample_rate: u32) {
+        for (coeffs, slot) in self.coeffs.iter_mut().zip(modulation.followers.iter()) {
+            *coeffs = (
+                follower_coeff(slot.attack_ms.load(), sample_rate),
+                follower_coeff(slot.release_ms.load(), sample_rate),
+            );
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) {
+        for (follower, &(attack, release)) in self.followers.iter_mut().zip(self.coeffs.iter()) {
+            follower.process(sample, attack, release);
+        }
+    }
+
+    /// Call once per block after `process`.
+    pub fn publish(&self, modulation: &Modulation) {
+        for (follower, slot) in self.followers.iter().zip(modulation.followers.iter()) {
+            slot.level.store(follower.level);
+        }
+    }
+}
+
+impl Default for FollowerRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Scheduler-side evaluation of the matrix, once per grain onset.
+pub struct ModState {
+    config: ModConfig,
+    version: usize,
+    lfos: [Lfo; MAX_LFOS],
+}
+
+impl ModState {
+    pub fn new() -> Self {
+        Self {
+            config: ModConfig::default(),
+            // Forces a read of the shared config on the first grain
+            version: usize::MAX,
+            lfos: [Lfo::default(); MAX_LFOS],
+        }
+    }
+
+    /// Advances the LFOs by `dt` seconds and applies every route to `params`.
+    pub fn apply(&mut self, modulation: &Modulation, dt: f32, params: &mut GrainParams) {
+        let version = modulation.version.load(Ordering::Acquire);
+        if version != self.version {
+            self.config.clone_from(&modulation.config.lock().unwrap());
+            self.version = version;
+        }
+
+        let mut lfo_values = [0.0; MAX_LFOS];
+        for ((value, lfo), settings) in lfo_values
+            .iter_mut()
+            .zip(self.lfos.iter_mut())
+            .zip(self.config.lfos.iter())
+        {
+            *value = lfo.advance(settings, dt);
+        }
+
+        // Sum every route per target before applying
+        let (mut start, mut pitch, mut duration, mut density, mut pan, mut amplitude) =
+            (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+        for route in self.config.routes.iter() {
+            let value = match route.source {
+                ModSource::Lfo(i) => lfo_values[i],
+                ModSource::EnvelopeFollower(i) => modulation.follower_level(i),
+            } * route.depth;
+            match route.target {
+                ModTarget::Start => start += value,
+                ModTarget::Pitch => pitch += value,
+                ModTarget::Duration => duration += value,
+                ModTarget::Density => density += value,
+                ModTarget::Pan => pan += value,
+                ModTarget::Amplitude => amplitude += value,
+            }
+        }
+
+        let filesize = params.specs.filesize as f32;
+        params.grain_start = (params.grain_start + start * filesize).clamp(0.0, filesize);
+        params.grain_pitch *= 2.0_f32.powf(pitch / 12.0);
+        params.grain_duration =
+            ((params.grain_duration as f32 * (1.0 + duration)).max(1.0)) as usize;
+        params.grain_overlap = (params.grain_overlap * (1.0 + density)).max(0.1);
+        params.grain_pan = (params.grain_pan + pan).clamp(-1.0, 1.0);
+        params.grain_amplitude = (params.grain_amplitude * (1.0 + amplitude)).max(0.0);
+    }
+}
+
+impl Default for ModState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
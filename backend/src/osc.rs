@@ -0,0 +1,375 @@
+use crate::{GranularSynth, Recorder};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// -------------------------------------
+// OSC MESSAGES
+// -------------------------------------
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(v) => Some(v as f32),
+            OscArg::Float(v) => Some(v),
+            OscArg::Bool(v) => Some(if v { 1.0 } else { 0.0 }),
+            OscArg::String(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            OscArg::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> Self {
+        Self {
+            address: address.to_string(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_padded_str(&mut bytes, &self.address);
+        let mut tags = String::from(",");
+        for arg in self.args.iter() {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::String(_) => 's',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+            });
+        }
+        write_padded_str(&mut bytes, &tags);
+        for arg in self.args.iter() {
+            match arg {
+                OscArg::Int(v) => bytes.extend_from_slice(&v.to_be_bytes()),
+                OscArg::Float(v) => bytes.extend_from_slice(&v.to_be_bytes()),
+                OscArg::String(s) => write_padded_str(&mut bytes, s),
+                OscArg::Bool(_) => {}
+            }
+        }
+        bytes
+    }
+}
+
+fn write_padded_str(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend_from_slice(s.as_bytes());
+    // At least one terminating null, padded to a multiple of four
+    let padding = 4 - s.len() % 4;
+    bytes.extend(std::iter::repeat_n(0, padding));
+}
+
+/// Reads a null-terminated, four-byte padded string at `*pos`.
+fn read_padded_str(bytes: &[u8], pos: &mut usize) -> Option<String> {
+    let rest = bytes.get(*pos..)?;
+    let end = rest.iter().position(|&b| b == 0)?;
+    let s = std::str::from_utf8(&rest[..end]).ok()?.to_string();
+    *pos += (end / 4 + 1) * 4;
+    Some(s)
+}
+
+fn read_4(bytes: &[u8], pos: &mut usize) -> Option<[u8; 4]> {
+    let word = bytes.get(*pos..*pos + 4)?.try_into().ok()?;
+    *pos += 4;
+    Some(word)
+}
+
+fn decode_message(bytes: &[u8]) -> Option<OscMessage> {
+    let mut pos = 0;
+    let address = read_padded_str(bytes, &mut pos)?;
+    if !address.starts_with('/') {
+        return None;
+    }
+    // Very old senders omit the type tags entirely
+    if pos >= bytes.len() {
+        return Some(OscMessage { address, args: Vec::new() });
+    }
+    let tags = read_padded_str(bytes, &mut pos)?;
+    let mut args = Vec::new();
+    for tag in tags.strip_prefix(',')?.chars() {
+        let arg = match tag {
+            'i' => OscArg::Int(i32::from_be_bytes(read_4(bytes, &mut pos)?)),
+            'f' => OscArg::Float(f32::from_be_bytes(read_4(bytes, &mut pos)?)),
+            's' | 'S' => OscArg::String(read_padded_str(bytes, &mut pos)?),
+            'd' => {
+                let hi = read_4(bytes, &mut pos)?;
+                let lo = read_4(bytes, &mut pos)?;
+                let mut word = [0u8; 8];
+                word[..4].copy_from_slice(&hi);
+                word[4..].copy_from_slice(&lo);
+                OscArg::Float(f64::from_be_bytes(word) as f32)
+            }
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            _ => return None,
+        };
+        args.push(arg);
+    }
+    Some(OscMessage { address, args })
+}
+
+/// Decodes a packet into its messages. Bundles are flattened and their time
+/// tags ignored: everything is applied as soon as it arrives.
+pub fn decode_packet(bytes: &[u8]) -> Vec<OscMessage> {
+    let mut messages = Vec::new();
+    decode_into(bytes, &mut messages);
+    messages
+}
+
+fn decode_into(bytes: &[u8], messages: &mut Vec<OscMessage>) {
+    const BUNDLE: &[u8] = b"#bundle\0";
+    if !bytes.starts_with(BUNDLE) {
+        messages.extend(decode_message(bytes));
+        return;
+    }
+    // Skip the header and the time tag
+    let mut pos = BUNDLE.len() + 8;
+    while let Some(size) = read_4(bytes, &mut pos) {
+        let size = i32::from_be_bytes(size).max(0) as usize;
+        match bytes.get(pos..pos + size) {
+            Some(element) => decode_into(element, messages),
+            None => break,
+        }
+        pos += size;
+    }
+}
+
+// -------------------------------------
+// OSC SERVER
+// -------------------------------------
+pub const OSC_PREFIX: &str = "/granular";
+/// How often meter and playhead values go out to subscribers.
+const METER_INTERVAL: Duration = Duration::from_millis(50);
+
+/// UDP server on its own thread. Messages under `/granular` set grain
+/// parameters, start/stop the scheduler and recording and load sources.
+/// A parameter address sent without arguments is answered with its value.
+/// Clients that send `/granular/subscribe` receive `/granular/meter` and
+/// `/granular/playhead` at a fixed rate.
+pub struct OscServer {
+    local_addr: SocketAddr,
+    should_stop: Arc<AtomicBool>,
+    thread_handle: Option<JoinHandle<()>>,
+}
+
+impl OscServer {
+    /// Binds `bind_addr` (e.g. `"127.0.0.1:9000"`, port 0 picks a free one)
+    /// and starts serving.
+    pub fn start(
+        bind_addr: &str,
+        synth: Arc<GranularSynth>,
+        recorder: Recorder,
+    ) -> Result<Self, String> {
+        let socket = UdpSocket::bind(bind_addr).map_err(|e| e.to_string())?;
+        // Wake up regularly to send meters and notice `stop`
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .map_err(|e| e.to_string())?;
+        let local_addr = socket.local_addr().map_err(|e| e.to_string())?;
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::clone(&should_stop);
+        let thread_handle = thread::spawn(move || {
+            let mut session = OscSession {
+                socket,
+                synth,
+                recorder,
+                subscribers: Vec::new(),
+            };
+            session.run(&stop_flag);
+        });
+        Ok(Self {
+            local_addr,
+            should_stop,
+            thread_handle: Some(thread_handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stop(&mut self) {
+        self.should_stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.thread_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct OscSession {
+    socket: UdpSocket,
+    synth: Arc<GranularSynth>,
+    recorder: Recorder,
+    subscribers: Vec<SocketAddr>,
+}
+
+impl OscSession {
+    fn run(&mut self, should_stop: &AtomicBool) {
+        let mut buffer = [0u8; 65536];
+        let mut next_meter = Instant::now();
+        while !should_stop.load(Ordering::SeqCst) {
+            if let Ok((size, sender)) = self.socket.recv_from(&mut buffer) {
+                for message in decode_packet(&buffer[..size]) {
+                    if let Err(e) = self.handle(&message, sender) {
+                        eprintln!("OSC {}: {}", message.address, e);
+                    }
+                }
+            }
+            if !self.subscribers.is_empty() && Instant::now() >= next_meter {
+                next_meter = Instant::now() + METER_INTERVAL;
+                for message in [self.meter_message(), self.playhead_message()] {
+                    let bytes = message.encode();
+                    for subscriber in self.subscribers.iter() {
+                        let _ = self.socket.send_to(&bytes, subscriber);
+                    }
+                }
+            }
+        }
+    }
+
+    fn reply(&self, to: SocketAddr, message: OscMessage) {
+        let _ = self.socket.send_to(&message.encode(), to);
+    }
+
+    fn meter_message(&self) -> OscMessage {
+        let (left, right) = self.synth.output_peaks();
+        OscMessage::new(
+            &format!("{}/meter", OSC_PREFIX),
+            vec![OscArg::Float(left), OscArg::Float(right)],
+        )
+    }
+
+    fn playhead_message(&self) -> OscMessage {
+        OscMessage::new(
+            &format!("{}/playhead", OSC_PREFIX),
+            vec![OscArg::Float(self.synth.playhead())],
+        )
+    }
+
+    fn handle(&mut self, message: &OscMessage, sender: SocketAddr) -> Result<(), String> {
+        let path = match message.address.strip_prefix(OSC_PREFIX) {
+            Some(path) => path,
+            None => return Err("Unknown address".to_string()),
+        };
+        let float_arg = message.args.first().and_then(OscArg::as_f32);
+        let string_arg = message.args.first().and_then(OscArg::as_str);
+        let params = &self.synth.params;
+
+        // Grain parameters: set with an argument, query without
+        let current = match path {
+            "/start" => Some(params.normalized_grain_start()),
+            "/duration" => Some(params.snapshot().grain_duration as f32),
+            "/overlap" => Some(params.snapshot().grain_overlap),
+            "/pitch" => Some(params.snapshot().grain_pitch),
+            "/amplitude" => Some(params.grain_amplitude()),
+            "/grain_pan" => Some(params.grain_pan()),
+            "/gain" => Some(params.gain()),
+            "/pan" => Some(params.pan()),
+            "/bpm" => Some(self.synth.bpm()),
+            "/sample_rate" => Some(params.specs().sample_rate as f32),
+            "/channels" => Some(params.specs().channels as f32),
+            "/filesize" => Some(params.specs().filesize as f32),
+            _ => None,
+        };
+        if let Some(current) = current {
+            match float_arg {
+                Some(value) => match path {
+                    "/start" => params.set_grain_start(value),
+                    "/duration" => params.set_grain_duration(value.max(1.0) as usize),
+                    "/overlap" => params.set_grain_overlap(value),
+                    "/pitch" => params.set_grain_pitch(value),
+                    "/amplitude" => params.set_grain_amplitude(value),
+                    "/grain_pan" => params.set_grain_pan(value),
+                    "/gain" => params.set_gain(value),
+                    "/pan" => params.set_pan(value),
+                    "/bpm" => self.synth.set_bpm(value),
+                    _ => return Err("Read-only parameter".to_string()),
+                },
+                None => self.reply(
+                    sender,
+                    OscMessage::new(&message.address, vec![OscArg::Float(current)]),
+                ),
+            }
+            return Ok(());
+        }
+
+        match path {
+            "/scheduler/start" => self.synth.start_scheduler(),
+            "/scheduler/stop" => self.synth.stop_scheduler(),
+            "/gate" => match float_arg {
+                Some(v) if v > 0.0 => self.synth.gate_on(),
+                _ => self.synth.gate_off(),
+            },
+            "/source/load" => {
+                let path = string_arg.ok_or("Expected a file path")?;
+                let master_rate = params.specs().sample_rate;
+                if self.synth.load_audio_from_file(path.as_ptr(), path.len(), master_rate) != 0 {
+                    return Err(format!("Failed to load {}", path));
+                }
+            }
+            "/record/start" => {
+                let path = string_arg.ok_or("Expected a file path")?;
+                self.recorder.start(path)?;
+            }
+            "/record/stop" => self.recorder.stop()?,
+            "/record" => self.reply(
+                sender,
+                OscMessage::new(
+                    &message.address,
+                    vec![OscArg::Int(self.recorder.is_recording() as i32)],
+                ),
+            ),
+            "/meter" => self.reply(sender, self.meter_message()),
+            "/playhead" => self.reply(sender, self.playhead_message()),
+            // Optional argument: the port to send to, if not the sender's
+            "/subscribe" => {
+                let mut target = sender;
+                if let Some(port) = float_arg {
+                    target.set_port(port as u16);
+                }
+                if !self.subscribers.contains(&target) {
+                    self.subscribers.push(target);
+                }
+            }
+            "/unsubscribe" => {
+                let port = float_arg.map_or(sender.port(), |p| p as u16);
+                self.subscribers
+                    .retain(|s| !(s.ip() == sender.ip() && s.port() == port));
+            }
+            _ => return Err("Unknown address".to_string()),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use crate::AtomicF32;
+use rand::Rng;
+use std::{
+    f32::consts::PI,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+// -------------------------------------
+// SYNTHETIC GRAIN SOURCES
+// -------------------------------------
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Waveform {
+    Sine,
+    /// Band-limited (PolyBLEP) sawtooth
+    Saw,
+    /// Band-limited (PolyBLEP) square
+    Square,
+    /// White noise
+    Noise,
+    /// Band-limited impulse train (BLIT): every harmonic up to Nyquist at
+    /// equal level, the classic pulsar waveform
+    PulseTrain,
+    /// One cycle of the user wavetable
+    Wavetable,
+}
+
+impl Waveform {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(Waveform::Sine),
+            1 => Some(Waveform::Saw),
+            2 => Some(Waveform::Square),
+            3 => Some(Waveform::Noise),
+            4 => Some(Waveform::PulseTrain),
+            5 => Some(Waveform::Wavetable),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            Waveform::Sine => 0,
+            Waveform::Saw => 1,
+            Waveform::Square => 2,
+            Waveform::Noise => 3,
+            Waveform::PulseTrain => 4,
+            Waveform::Wavetable => 5,
+        }
+    }
+}
+
+/// Residual that takes the aliasing step out of a naive saw or square;
+/// `t` is the phase (0..1) and `dt` the phase increment per sample.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = t / dt;
+        2.0 * x - x * x - 1.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Oscillator with its own phase, carried inside a `Grain` in place of the
+/// source file. Set up by the scheduler, only run by the audio thread.
+#[derive(Clone, Copy)]
+pub struct GrainOscillator {
+    waveform: Waveform,
+    // Phase in cycles (0..1) and its increment per sample
+    phase: f32,
+    increment: f32,
+    noise_state: u32,
+}
+
+impl GrainOscillator {
+    pub fn new(waveform: Waveform, frequency: f32, sample_rate: u32) -> Self {
+        let increment = (frequency / sample_rate as f32).clamp(1e-6, 0.5);
+        Self {
+            waveform,
+            phase: 0.0,
+            increment,
+            noise_state: rand::thread_rng().gen_range(1..u32::MAX),
+        }
+    }
+
+    /// `rate` scales the frequency for this sample (1.0 without a glide).
+    pub fn next_sample(&mut self, wavetable: &[f32], rate: f32) -> f32 {
+        let (t, dt) = (self.phase, (self.increment * rate).clamp(1e-6, 0.5));
+        let sample = match self.waveform {
+            Waveform::Sine => (2.0 * PI * t).sin(),
+            Waveform::Saw => 2.0 * t - 1.0 - poly_blep(t, dt),
+            Waveform::Square => {
+                let naive = if t < 0.5 { 1.0 } else { -1.0 };
+                naive + poly_blep(t, dt) - poly_blep((t + 0.5) % 1.0, dt)
+            }
+            Waveform::Noise => {
+                // xorshift32
+                let mut x = self.noise_state;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.noise_state = x;
+                x as f32 / u32::MAX as f32 * 2.0 - 1.0
+            }
+            Waveform::PulseTrain => {
+                // Dirichlet kernel with an odd number of harmonics, all below
+                // Nyquist; peaks at 1 once per cycle
+                let harmonics = 2.0 * (0.5 / dt).floor() + 1.0;
+                let denominator = harmonics * (PI * t).sin();
+                if denominator.abs() < 1e-6 {
+                    1.0
+                } else {
+                    (harmonics * PI * t).sin() / denominator
+                }
+            }
+            Waveform::Wavetable => {
+                if wavetable.is_empty() {
+                    0.0
+                } else {
+                    let x = t * wavetable.len() as f32;
+                    let index0 = x as usize % wavetable.len();
+                    let index1 = (index0 + 1) % wavetable.len();
+                    let frac = x.fract();
+                    wavetable[index0] + (wavetable[index1] - wavetable[index0]) * frac
+                }
+            }
+        };
+        self.phase = (self.phase + dt) % 1.0;
+        sample
+    }
+}
+
+/// Chooses between the loaded file and an oscillator for new grains. The
+/// grain frequency is `frequency` times the grain's playback rate, so pitch,
+/// its random spread and MIDI notes all apply.
+pub struct OscillatorSettings {
+    enabled: AtomicBool,
+    waveform: AtomicU8,
+    frequency: AtomicF32,
+}
+
+impl OscillatorSettings {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            waveform: AtomicU8::new(Waveform::Sine.index()),
+            frequency: AtomicF32::new(440.0),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn waveform(&self) -> Waveform {
+        Waveform::from_index(self.waveform.load(Ordering::Relaxed)).unwrap_or(Waveform::Sine)
+    }
+
+    pub fn set_waveform(&self, waveform: Waveform) {
+        self.waveform.store(waveform.index(), Ordering::Relaxed);
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency.load()
+    }
+
+    /// In Hz, at a playback rate of 1.
+    pub fn set_frequency(&self, frequency: f32) {
+        self.frequency.store(frequency.clamp(1.0, 20000.0));
+    }
+
+    /// The oscillator for the next grain, or `None` to read the file.
+    pub fn grain_oscillator(&self, playback_rate: f32, sample_rate: u32) -> Option<GrainOscillator> {
+        if !self.is_enabled() {
+            return None;
+        }
+        Some(GrainOscillator::new(
+            self.waveform(),
+            self.frequency() * playback_rate,
+            sample_rate,
+        ))
+    }
+}
+
+impl Default for OscillatorSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
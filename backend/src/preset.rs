@@ -0,0 +1,42 @@
+use crate::midi_map::CcMapping;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// -------------------------------------
+// PRESETS
+// -------------------------------------
+/// Everything a performer sets up by hand, stored as JSON. The grain start
+/// is normalized so a preset carries over to a different source file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Preset {
+    pub grain_start: f32,
+    pub grain_duration: usize,
+    pub grain_overlap: f32,
+    pub grain_pitch: f32,
+    pub gain: f32,
+    pub pan: f32,
+    #[serde(default = "unity")]
+    pub grain_amplitude: f32,
+    #[serde(default)]
+    pub grain_pan: f32,
+    #[serde(default)]
+    pub gain_compensation: bool,
+    #[serde(default)]
+    pub midi_mappings: Vec<CcMapping>,
+}
+
+fn unity() -> f32 {
+    1.0
+}
+
+impl Preset {
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(path, json).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&json).map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,278 @@
+use realfft::RealFftPlanner;
+use std::{
+    f32::consts::PI,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// -------------------------------------
+// PSOLA
+// -------------------------------------
+/// Lowest and highest fundamental the pitch detector looks for.
+pub const MIN_FREQUENCY: f32 = 60.0;
+pub const MAX_FREQUENCY: f32 = 1000.0;
+/// Grains a `PsolaVoice` can play at once; at most 2 x pitch overlap.
+pub const MAX_PSOLA_GRAINS: usize = 8;
+// YIN threshold on the cumulative mean normalized difference
+const YIN_THRESHOLD: f32 = 0.15;
+// Spacing of the marks in unvoiced parts, in seconds
+const UNVOICED_PERIOD: f32 = 0.01;
+
+/// Pitch marks of the source: one per fundamental period, on the largest
+/// peak of the period, with the local period length in samples.
+pub struct PitchMarks {
+    marks: Vec<usize>,
+    periods: Vec<f32>,
+}
+
+impl PitchMarks {
+    pub fn empty() -> Self {
+        Self {
+            marks: Vec::new(),
+            periods: Vec::new(),
+        }
+    }
+
+    /// Detects the period with YIN every hop, then walks the source period
+    /// by period placing a mark on each waveform peak. `None` once `cancel`
+    /// is raised.
+    pub fn analyze(source: &[f32], sample_rate: u32, cancel: &AtomicBool) -> Option<Self> {
+        if source.is_empty() {
+            return Some(Self::empty());
+        }
+        let min_lag = (sample_rate as f32 / MAX_FREQUENCY) as usize;
+        let max_lag = (sample_rate as f32 / MIN_FREQUENCY) as usize;
+        let hop = max_lag / 2;
+        let frame_periods = detect_periods(source, min_lag, max_lag, hop, cancel)?;
+        let unvoiced = UNVOICED_PERIOD * sample_rate as f32;
+
+        let mut marks = Vec::new();
+        let mut periods = Vec::new();
+        let mut position = 0usize;
+        while position < source.len() {
+            let frame = (position / hop).min(frame_periods.len() - 1);
+            let (mark, period) = match frame_periods[frame] {
+                Some(period) => {
+                    // Snap to the largest peak within a quarter period
+                    let reach = (period / 4.0) as usize;
+                    let from = position.saturating_sub(reach);
+                    let to = (position + reach + 1).min(source.len());
+                    let peak = (from..to)
+                        .max_by(|&a, &b| source[a].abs().total_cmp(&source[b].abs()))
+                        .unwrap_or(position);
+                    (peak.max(marks.last().map_or(0, |&m| m + min_lag / 2)), period)
+                }
+                None => (position, unvoiced),
+            };
+            marks.push(mark);
+            periods.push(period);
+            position = mark + period.max(1.0) as usize;
+        }
+        Some(Self { marks, periods })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+
+    /// The mark closest to sample `position` and its period.
+    pub fn nearest(&self, position: f32) -> Option<(usize, f32)> {
+        if self.marks.is_empty() {
+            return None;
+        }
+        let position = position.max(0.0) as usize;
+        let index = match self.marks.binary_search(&position) {
+            Ok(index) => index,
+            Err(0) => 0,
+            Err(index) if index >= self.marks.len() => self.marks.len() - 1,
+            Err(index) => {
+                if position - self.marks[index - 1] <= self.marks[index] - position {
+                    index - 1
+                } else {
+                    index
+                }
+            }
+        };
+        Some((self.marks[index], self.periods[index]))
+    }
+}
+
+/// Period (in samples) of every analysis frame, `None` where unvoiced. YIN
+/// with the autocorrelation computed by FFT. `None` once `cancel` is raised.
+pub(crate) fn detect_periods(
+    source: &[f32],
+    min_lag: usize,
+    max_lag: usize,
+    hop: usize,
+    cancel: &AtomicBool,
+) -> Option<Vec<Option<f32>>> {
+    let window = max_lag;
+    let size = (window + max_lag).next_power_of_two();
+    let mut planner = RealFftPlanner::<f32>::new();
+    let forward = planner.plan_fft_forward(size);
+    let inverse = planner.plan_fft_inverse(size);
+    let mut head = forward.make_input_vec();
+    let mut segment = forward.make_input_vec();
+    let mut head_spectrum = forward.make_output_vec();
+    let mut segment_spectrum = forward.make_output_vec();
+    let mut correlation = inverse.make_output_vec();
+    let mut difference = vec![0.0f32; max_lag + 1];
+
+    let frame_count = source.len().div_ceil(hop);
+    let mut periods = Vec::with_capacity(frame_count);
+    for frame in 0..frame_count {
+        if cancel.load(Ordering::Relaxed) {
+            return None;
+        }
+        let start = frame * hop;
+        for (i, (h, s)) in head.iter_mut().zip(segment.iter_mut()).enumerate() {
+            let sample = source.get(start + i).copied().unwrap_or(0.0);
+            *s = if i < window + max_lag { sample } else { 0.0 };
+            *h = if i < window { sample } else { 0.0 };
+        }
+        let _ = forward.process(&mut head, &mut head_spectrum);
+        let _ = forward.process(&mut segment, &mut segment_spectrum);
+        // r(lag) = sum head[j] * segment[j + lag]
+        for (h, s) in head_spectrum.iter_mut().zip(&segment_spectrum) {
+            *h = h.conj() * s;
+        }
+        head_spectrum[0].im = 0.0;
+        if let Some(last) = head_spectrum.last_mut() {
+            last.im = 0.0;
+        }
+        let _ = inverse.process(&mut head_spectrum, &mut correlation);
+        let scale = 1.0 / size as f32;
+
+        // d(lag) = e(head) + e(segment shifted by lag) - 2 r(lag)
+        let sample_at = |i: usize| source.get(start + i).copied().unwrap_or(0.0);
+        let head_energy: f32 = (0..window).map(|i| sample_at(i) * sample_at(i)).sum();
+        if head_energy < 1e-6 * window as f32 {
+            periods.push(None);
+            continue;
+        }
+        let mut shifted_energy = head_energy;
+        difference[0] = 0.0;
+        for lag in 1..=max_lag {
+            shifted_energy += sample_at(lag + window - 1).powi(2) - sample_at(lag - 1).powi(2);
+            difference[lag] = head_energy + shifted_energy - 2.0 * correlation[lag] * scale;
+        }
+        periods.push(yin_period(&difference, min_lag, max_lag));
+    }
+    Some(periods)
+}
+
+/// First dip of the cumulative mean normalized difference below the
+/// threshold, refined to its local minimum with parabolic interpolation.
+fn yin_period(difference: &[f32], min_lag: usize, max_lag: usize) -> Option<f32> {
+    let mut running_sum = 0.0f32;
+    let mut normalized = |lag: usize, d: f32| {
+        running_sum += d;
+        if running_sum > 0.0 {
+            d * lag as f32 / running_sum
+        } else {
+            1.0
+        }
+    };
+    let mut cmnd = [0.0f32; 3];
+    let mut previous = 1.0f32;
+    let mut found = None;
+    for (lag, &d) in difference.iter().enumerate().take(max_lag + 1).skip(1) {
+        let value = normalized(lag, d);
+        if lag >= min_lag && found.is_none() && value < YIN_THRESHOLD {
+            found = Some(lag);
+        }
+        if let Some(candidate) = found {
+            // Follow the dip down to its minimum
+            if lag > candidate && value > previous {
+                let best = lag - 1;
+                cmnd[2] = value;
+                let (a, b, c) = (cmnd[0], cmnd[1], cmnd[2]);
+                let denominator = a - 2.0 * b + c;
+                let shift = if denominator.abs() > 1e-9 {
+                    (0.5 * (a - c) / denominator).clamp(-0.5, 0.5)
+                } else {
+                    0.0
+                };
+                return Some(best as f32 + shift);
+            }
+        }
+        cmnd[0] = cmnd[1];
+        cmnd[1] = value;
+        previous = value;
+    }
+    found.map(|lag| lag as f32)
+}
+
+/// Per-block controls of a `PsolaVoice`.
+#[derive(Clone, Copy)]
+pub struct PsolaControl {
+    /// Grain start in source samples
+    pub position: f32,
+    /// Ratio of the output to the source fundamental
+    pub pitch: f32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct PsolaGrain {
+    start: usize,
+    length: usize,
+    position: usize,
+}
+
+/// Pitch-synchronous overlap-add owned by the audio thread. Grains are two
+/// source periods long, centred on a pitch mark and played at their
+/// original speed, so formants stay put; only their spacing (period /
+/// pitch) sets the new fundamental.
+pub struct PsolaVoice {
+    grains: [Option<PsolaGrain>; MAX_PSOLA_GRAINS],
+    // Samples until the next grain starts
+    countdown: f32,
+}
+
+impl PsolaVoice {
+    pub fn new() -> Self {
+        Self {
+            grains: [None; MAX_PSOLA_GRAINS],
+            countdown: 0.0,
+        }
+    }
+
+    pub fn next_sample(&mut self, source: &[f32], marks: &PitchMarks, control: &PsolaControl) -> f32 {
+        self.countdown -= 1.0;
+        if self.countdown <= 0.0 {
+            match marks.nearest(control.position) {
+                Some((mark, period)) => {
+                    let half = period.round().max(1.0) as usize;
+                    if let Some(slot) = self.grains.iter_mut().find(|g| g.is_none()) {
+                        *slot = Some(PsolaGrain {
+                            start: mark.saturating_sub(half),
+                            length: 2 * half,
+                            position: 0,
+                        });
+                    }
+                    self.countdown += period / control.pitch.max(0.1);
+                }
+                None => self.countdown = 0.0,
+            }
+        }
+
+        let mut sum = 0.0;
+        for slot in self.grains.iter_mut() {
+            if let Some(grain) = slot {
+                let t = grain.position as f32 / grain.length as f32;
+                let window = 0.5 - 0.5 * (2.0 * PI * t).cos();
+                sum += source.get(grain.start + grain.position).copied().unwrap_or(0.0) * window;
+                grain.position += 1;
+                if grain.position >= grain.length {
+                    *slot = None;
+                }
+            }
+        }
+        sum
+    }
+}
+
+impl Default for PsolaVoice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
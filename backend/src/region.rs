@@ -0,0 +1,152 @@
+use crate::AtomicF32;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// -------------------------------------
+// SOURCE REGION
+// -------------------------------------
+/// Shortest region, as a share of the file.
+pub const MIN_REGION_WIDTH: f32 = 0.001;
+
+/// What grain reads do at the edges of the region.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BoundaryMode {
+    /// Grains are placed (and shortened if need be) to fit inside the
+    /// region; reads past it hold the edge sample
+    Clamp,
+    /// Reads past the end continue from the start
+    Wrap,
+    /// Reads bounce between the edges
+    PingPong,
+}
+
+impl BoundaryMode {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(BoundaryMode::Clamp),
+            1 => Some(BoundaryMode::Wrap),
+            2 => Some(BoundaryMode::PingPong),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            BoundaryMode::Clamp => 0,
+            BoundaryMode::Wrap => 1,
+            BoundaryMode::PingPong => 2,
+        }
+    }
+}
+
+/// The region in source samples, resolved for one grain.
+#[derive(Clone, Copy, Debug)]
+pub struct SourceBounds {
+    pub start: f32,
+    pub end: f32,
+    pub mode: BoundaryMode,
+}
+
+impl SourceBounds {
+    pub fn length(&self) -> f32 {
+        (self.end - self.start).max(1.0)
+    }
+
+    /// Source position of `normalized` (0..1) across the region.
+    pub fn map(&self, normalized: f32) -> f32 {
+        self.start + normalized.clamp(0.0, 1.0) * self.length()
+    }
+
+    /// Source position actually read for `x`, brought inside the region;
+    /// held at the edges when clamping.
+    pub fn fold(&self, x: f32) -> f32 {
+        let length = self.length();
+        match self.mode {
+            BoundaryMode::Clamp => x.clamp(self.start, (self.end - 1.0).max(self.start)),
+            BoundaryMode::Wrap => self.start + (x - self.start).rem_euclid(length),
+            BoundaryMode::PingPong => {
+                let t = (x - self.start).rem_euclid(2.0 * length);
+                self.start + if t < length { t } else { 2.0 * length - t }
+            }
+        }
+    }
+
+    /// Length of a grain of `length` samples reading `reach` source samples.
+    /// When clamping, a grain reaching further than the region is shortened
+    /// so all of it fits.
+    pub fn grain_length(&self, length: usize, reach: f32) -> usize {
+        match self.mode {
+            BoundaryMode::Clamp if reach > self.length() => {
+                ((length as f32 * self.length() / reach) as usize).max(1)
+            }
+            _ => length,
+        }
+    }
+
+    /// Start of a grain reading `reach` source samples from `start`. When
+    /// clamping the grain is moved so all of it fits.
+    pub fn grain_start(&self, start: f32, reach: f32) -> f32 {
+        match self.mode {
+            BoundaryMode::Clamp => start.clamp(self.start, (self.end - reach).max(self.start)),
+            _ => self.fold(start),
+        }
+    }
+}
+
+/// Part of the source the grains read from, normalized 0..1 over the file.
+/// The grain start spans the region rather than the file.
+pub struct SourceRegion {
+    start: AtomicF32,
+    end: AtomicF32,
+    mode: AtomicU8,
+}
+
+impl SourceRegion {
+    pub fn new() -> Self {
+        Self {
+            start: AtomicF32::new(0.0),
+            end: AtomicF32::new(1.0),
+            mode: AtomicU8::new(BoundaryMode::Clamp.index()),
+        }
+    }
+
+    /// Both ends 0..1, in either order, at least `MIN_REGION_WIDTH` apart.
+    pub fn set_region(&self, start: f32, end: f32) {
+        let (start, end) = (start.clamp(0.0, 1.0), end.clamp(0.0, 1.0));
+        let start = start.min(end).min(1.0 - MIN_REGION_WIDTH);
+        let end = start.max(end).max(start + MIN_REGION_WIDTH);
+        self.start.store(start);
+        self.end.store(end);
+    }
+
+    pub fn region(&self) -> (f32, f32) {
+        (self.start.load(), self.end.load())
+    }
+
+    pub fn set_boundary_mode(&self, mode: BoundaryMode) {
+        self.mode.store(mode.index(), Ordering::Relaxed);
+    }
+
+    pub fn boundary_mode(&self) -> BoundaryMode {
+        BoundaryMode::from_index(self.mode.load(Ordering::Relaxed)).unwrap_or(BoundaryMode::Clamp)
+    }
+
+    /// The region over a source of `filesize` samples; `None` when nothing
+    /// is loaded.
+    pub fn bounds(&self, filesize: usize) -> Option<SourceBounds> {
+        if filesize == 0 {
+            return None;
+        }
+        let (start, end) = self.region();
+        Some(SourceBounds {
+            start: start * filesize as f32,
+            end: end * filesize as f32,
+            mode: self.boundary_mode(),
+        })
+    }
+}
+
+impl Default for SourceRegion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
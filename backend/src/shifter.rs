@@ -0,0 +1,220 @@
+use std::f32::consts::PI;
+
+// -------------------------------------
+// PITCH SHIFTER
+// -------------------------------------
+/// Grain length limits of a `PitchShifter`, in milliseconds.
+pub const MIN_SHIFTER_GRAIN_MS: f32 = 5.0;
+pub const MAX_SHIFTER_GRAIN_MS: f32 = 500.0;
+// Grains reading one channel's delay line, half a grain apart
+const SHIFTER_GRAINS: usize = 2;
+// Samples kept clear of the write head for interpolation
+const MARGIN: f32 = 2.0;
+
+/// Granular pitch shifter for an incoming signal, run block by block by
+/// the caller with no device or thread of its own. Each channel is written
+/// into a delay line that two Hann-windowed grains read at `pitch` times
+/// real time; every grain restarts around the same delay, so the output
+/// lags the input by a fixed `latency` (one grain). Feedback sends the
+/// output back into the delay line, and freeze stops writing it so the
+/// grains loop what it holds.
+pub struct PitchShifter {
+    channels: usize,
+    grain_length: f32,
+    latency: usize,
+    // One delay line per channel, `mask + 1` samples each
+    lines: Vec<Vec<f32>>,
+    mask: usize,
+    write_position: usize,
+    // Per grain: phase (0..1) and current delay in samples
+    phases: [f32; SHIFTER_GRAINS],
+    delays: [f32; SHIFTER_GRAINS],
+    last_output: Vec<f32>,
+    pitch: f32,
+    feedback: f32,
+    frozen: bool,
+}
+
+impl PitchShifter {
+    /// `grain_ms` is clamped to `MIN_SHIFTER_GRAIN_MS..=MAX_SHIFTER_GRAIN_MS`
+    /// and sets the latency.
+    pub fn new(sample_rate: u32, channels: u16, grain_ms: f32) -> Self {
+        let channels = channels.max(1) as usize;
+        let grain_ms = grain_ms.clamp(MIN_SHIFTER_GRAIN_MS, MAX_SHIFTER_GRAIN_MS);
+        let grain_length = (grain_ms / 1000.0 * sample_rate as f32).max(4.0);
+        // At pitch 2 a frozen grain reads two grain lengths of buffer around
+        // the centre delay, so one grain length of delay always suffices
+        let latency = (grain_length + MARGIN).ceil() as usize;
+        let size = (latency + grain_length.ceil() as usize + 8).next_power_of_two();
+        let mut shifter = Self {
+            channels,
+            grain_length,
+            latency,
+            lines: vec![vec![0.0; size]; channels],
+            mask: size - 1,
+            write_position: 0,
+            phases: [0.0; SHIFTER_GRAINS],
+            delays: [latency as f32; SHIFTER_GRAINS],
+            last_output: vec![0.0; channels],
+            pitch: 1.0,
+            feedback: 0.0,
+            frozen: false,
+        };
+        for grain in 0..SHIFTER_GRAINS {
+            shifter.phases[grain] = grain as f32 / SHIFTER_GRAINS as f32;
+            shifter.delays[grain] = shifter.delay_at(shifter.phases[grain]);
+        }
+        shifter
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Samples (frames) the output lags the input; constant for the life of
+    /// the shifter.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Ratio of output to input pitch, 0.1..2.
+    pub fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = pitch.clamp(0.1, 2.0);
+    }
+
+    /// Share of the output fed back into the delay line, 0..0.95.
+    pub fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = feedback.clamp(0.0, 0.95);
+    }
+
+    /// Stops writing the input so the grains keep looping the held signal.
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    /// Empties the delay lines.
+    pub fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.fill(0.0);
+        }
+        self.last_output.fill(0.0);
+    }
+
+    // How much the delay of a grain grows per sample: the write head moves
+    // by one (none when frozen) while the grain reads `pitch` samples
+    fn drift(&self) -> f32 {
+        let head = if self.frozen { 0.0 } else { 1.0 };
+        head - self.pitch
+    }
+
+    // Delay of a grain at `phase`, centred on the latency at mid-grain
+    fn delay_at(&self, phase: f32) -> f32 {
+        self.latency as f32 + self.drift() * self.grain_length * (phase - 0.5)
+    }
+
+    /// Shifts one block of interleaved audio. `input` and `output` must
+    /// hold the same number of whole frames.
+    pub fn process_block(&mut self, input: &[f32], output: &mut [f32]) -> Result<(), String> {
+        if input.len() != output.len() || !input.len().is_multiple_of(self.channels) {
+            return Err("Input and output must hold the same whole frames".to_string());
+        }
+        let size = (self.mask + 1) as f32;
+        let phase_step = 1.0 / self.grain_length;
+        for (in_frame, out_frame) in input
+            .chunks(self.channels)
+            .zip(output.chunks_mut(self.channels))
+        {
+            if !self.frozen {
+                for (ch, &sample) in in_frame.iter().enumerate() {
+                    self.lines[ch][self.write_position] = sample + self.feedback * self.last_output[ch];
+                }
+            }
+
+            out_frame.fill(0.0);
+            let drift = self.drift();
+            for grain in 0..SHIFTER_GRAINS {
+                let window = 0.5 - 0.5 * (2.0 * PI * self.phases[grain]).cos();
+                let delay = self.delays[grain].clamp(MARGIN, size - MARGIN);
+                let position = self.write_position as f32 - delay;
+                let index = position.floor();
+                let frac = position - index;
+                let i0 = (index as isize).rem_euclid(size as isize) as usize;
+                let i1 = (i0 + 1) & self.mask;
+                for (ch, out) in out_frame.iter_mut().enumerate() {
+                    let line = &self.lines[ch];
+                    *out += (line[i0] + (line[i1] - line[i0]) * frac) * window;
+                }
+
+                self.phases[grain] += phase_step;
+                self.delays[grain] += drift;
+                if self.phases[grain] >= 1.0 {
+                    self.phases[grain] -= 1.0;
+                    self.delays[grain] = self.delay_at(self.phases[grain]);
+                }
+            }
+            self.last_output.copy_from_slice(out_frame);
+
+            if !self.frozen {
+                self.write_position = (self.write_position + 1) & self.mask;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    // Positive-going zero crossings per second after the first `skip` samples
+    fn frequency(signal: &[f32], skip: usize) -> f32 {
+        let crossings = signal[skip..]
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        crossings as f32 * SAMPLE_RATE as f32 / (signal.len() - skip) as f32
+    }
+
+    #[test]
+    fn unity_pitch_delays_by_latency() {
+        let mut shifter = PitchShifter::new(SAMPLE_RATE, 1, 20.0);
+        let latency = shifter.latency();
+        let mut input = vec![0.0; latency * 3];
+        input[0] = 1.0;
+        let mut output = vec![0.0; input.len()];
+        shifter.process_block(&input, &mut output).unwrap();
+
+        for (i, &sample) in output.iter().enumerate() {
+            let expected = if i == latency { 1.0 } else { 0.0 };
+            assert!((sample - expected).abs() < 1e-4, "sample {i} is {sample}");
+        }
+    }
+
+    #[test]
+    fn octave_up_doubles_frequency() {
+        let mut shifter = PitchShifter::new(SAMPLE_RATE, 1, 50.0);
+        shifter.set_pitch(2.0);
+        let input: Vec<f32> = (0..SAMPLE_RATE as usize)
+            .map(|i| (2.0 * PI * 220.0 * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect();
+        let mut output = vec![0.0; input.len()];
+        shifter.process_block(&input, &mut output).unwrap();
+
+        let skip = shifter.latency() * 2;
+        let ratio = frequency(&output, skip) / frequency(&input, skip);
+        assert!((ratio - 2.0).abs() < 0.05, "frequency ratio {ratio}");
+    }
+
+    #[test]
+    fn rejects_mismatched_blocks() {
+        let mut shifter = PitchShifter::new(SAMPLE_RATE, 2, 20.0);
+        let mut output = vec![0.0; 8];
+        assert!(shifter.process_block(&[0.0; 6], &mut output).is_err());
+        let mut output = vec![0.0; 7];
+        assert!(shifter.process_block(&[0.0; 7], &mut output).is_err());
+        let mut output = vec![0.0; 8];
+        assert!(shifter.process_block(&[0.0; 8], &mut output).is_ok());
+    }
+}
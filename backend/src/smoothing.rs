@@ -0,0 +1,195 @@
+use crate::{GrainParams, SharedParams};
+
+// -------------------------------------
+// PARAMETER SMOOTHING
+// -------------------------------------
+#[derive(Clone, Copy, PartialEq)]
+pub enum SmoothingMode {
+    Linear,
+    OnePole,
+}
+
+impl SmoothingMode {
+    pub fn from_index(index: u8) -> Self {
+        match index {
+            1 => SmoothingMode::OnePole,
+            _ => SmoothingMode::Linear,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            SmoothingMode::Linear => 0,
+            SmoothingMode::OnePole => 1,
+        }
+    }
+}
+
+/// A value that glides towards its target instead of jumping.
+/// `Linear` reaches the target in exactly the ramp time, `OnePole` treats the
+/// ramp time as a time constant (63% of the way after one ramp time).
+///
+/// Layer gain and pan, the master gain and the effect wet shares tick per
+/// sample; the master filter cutoff, resonance and EQ gains are smoothed by
+/// `EffectChain`, and the grain controls by `GrainSmoothers`. All share the
+/// synth's mode and ramp time.
+pub struct SmoothedParam {
+    current: f32,
+    target: f32,
+    mode: SmoothingMode,
+    ramp_samples: f32,
+    // Linear state
+    step: f32,
+    remaining: usize,
+    // One-pole state
+    coeff: f32,
+}
+
+impl SmoothedParam {
+    pub fn new(value: f32) -> Self {
+        Self {
+            current: value,
+            target: value,
+            mode: SmoothingMode::Linear,
+            ramp_samples: 0.0,
+            step: 0.0,
+            remaining: 0,
+            coeff: 0.0,
+        }
+    }
+
+    pub fn configure(&mut self, mode: SmoothingMode, time_ms: f32, sample_rate: u32) {
+        let ramp_samples = (time_ms.max(0.0) / 1000.0) * sample_rate as f32;
+        if mode == self.mode && ramp_samples == self.ramp_samples {
+            return;
+        }
+        self.mode = mode;
+        self.ramp_samples = ramp_samples;
+        self.coeff = if ramp_samples > 0.0 {
+            (-1.0 / ramp_samples).exp()
+        } else {
+            0.0
+        };
+        // Restart the ramp from where we are with the new settings
+        let target = self.target;
+        self.target = self.current;
+        self.set_target(target);
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        if self.ramp_samples < 1.0 {
+            self.reset(target);
+            return;
+        }
+        self.remaining = self.ramp_samples as usize;
+        self.step = (self.target - self.current) / self.remaining as f32;
+    }
+
+    /// Jumps straight to `value` with no ramp.
+    pub fn reset(&mut self, value: f32) {
+        self.current = value;
+        self.target = value;
+        self.remaining = 0;
+    }
+
+    pub fn value(&self) -> f32 {
+        self.current
+    }
+
+    pub fn is_smoothing(&self) -> bool {
+        self.current != self.target
+    }
+
+    /// Per-sample tick, for controls applied in the audio callback.
+    pub fn tick(&mut self) -> f32 {
+        if !self.is_smoothing() {
+            return self.current;
+        }
+        match self.mode {
+            SmoothingMode::Linear => {
+                self.current += self.step;
+                self.remaining = self.remaining.saturating_sub(1);
+                if self.remaining == 0 {
+                    self.current = self.target;
+                }
+            }
+            SmoothingMode::OnePole => {
+                self.current = self.target + (self.current - self.target) * self.coeff;
+                self.snap_if_close();
+            }
+        }
+        self.current
+    }
+
+    /// Jumps `samples` ticks ahead at once, for controls that are only read
+    /// at grain onsets.
+    pub fn advance(&mut self, samples: usize) -> f32 {
+        if !self.is_smoothing() {
+            return self.current;
+        }
+        match self.mode {
+            SmoothingMode::Linear => {
+                if samples >= self.remaining {
+                    self.reset(self.target);
+                } else {
+                    self.current += self.step * samples as f32;
+                    self.remaining -= samples;
+                }
+            }
+            SmoothingMode::OnePole => {
+                self.current = self.target
+                    + (self.current - self.target) * self.coeff.powi(samples as i32);
+                self.snap_if_close();
+            }
+        }
+        self.current
+    }
+
+    fn snap_if_close(&mut self) {
+        if (self.current - self.target).abs() <= f32::EPSILON * self.target.abs().max(1.0) {
+            self.current = self.target;
+        }
+    }
+}
+
+/// Smoothers for the per-grain controls, owned by the scheduler thread and
+/// advanced by the number of samples between two grain onsets.
+pub struct GrainSmoothers {
+    start: SmoothedParam,
+    pitch: SmoothedParam,
+    overlap: SmoothedParam,
+}
+
+impl GrainSmoothers {
+    pub fn new(params: &GrainParams) -> Self {
+        Self {
+            start: SmoothedParam::new(params.grain_start),
+            pitch: SmoothedParam::new(params.grain_pitch),
+            overlap: SmoothedParam::new(params.grain_overlap),
+        }
+    }
+
+    /// Reads the latest targets and returns the smoothed parameters for the
+    /// grain about to start.
+    pub fn update(&mut self, shared: &SharedParams, elapsed_samples: usize) -> GrainParams {
+        let mut params = shared.snapshot();
+        let (mode, time_ms) = shared.smoothing();
+        let sample_rate = params.specs.sample_rate;
+        for smoother in [&mut self.start, &mut self.pitch, &mut self.overlap] {
+            smoother.configure(mode, time_ms, sample_rate);
+            smoother.advance(elapsed_samples);
+        }
+        self.start.set_target(params.grain_start);
+        self.pitch.set_target(params.grain_pitch);
+        self.overlap.set_target(params.grain_overlap);
+
+        params.grain_start = self.start.value();
+        params.grain_pitch = self.pitch.value();
+        params.grain_overlap = self.overlap.value();
+        params
+    }
+}
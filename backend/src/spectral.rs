@@ -0,0 +1,271 @@
+use crate::region::SourceBounds;
+use crate::AtomicF32;
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner};
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// -------------------------------------
+// SPECTRAL GRANULATION
+// -------------------------------------
+pub const FFT_SIZE: usize = 2048;
+/// Analysis and resynthesis hop, 75% overlap.
+pub const HOP: usize = FFT_SIZE / 4;
+pub const BINS: usize = FFT_SIZE / 2 + 1;
+// Hann analysis x Hann synthesis windows sum to 1.5 at 75% overlap, and the
+// inverse FFT is unnormalized
+const OLA_SCALE: f32 = 1.0 / (FFT_SIZE as f32 * 1.5);
+
+fn hann_window() -> Vec<f32> {
+    (0..FFT_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+        .collect()
+}
+
+/// Magnitude spectra of the source, one frame every `HOP` samples. Phases
+/// are not kept: resynthesis builds its own.
+pub struct SpectralFrames {
+    magnitudes: Vec<f32>,
+    frame_count: usize,
+}
+
+impl SpectralFrames {
+    pub fn empty() -> Self {
+        Self {
+            magnitudes: Vec::new(),
+            frame_count: 0,
+        }
+    }
+
+    /// Short-time Fourier transform of `source` with a Hann window; `None`
+    /// once `cancel` is raised.
+    pub fn analyze(source: &[f32], cancel: &AtomicBool) -> Option<Self> {
+        if source.is_empty() {
+            return Some(Self::empty());
+        }
+        let forward = RealFftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+        let window = hann_window();
+        let mut input = forward.make_input_vec();
+        let mut spectrum = forward.make_output_vec();
+        let mut scratch = forward.make_scratch_vec();
+        let frame_count = source.len().div_ceil(HOP);
+        let mut magnitudes = Vec::with_capacity(frame_count * BINS);
+        for frame in 0..frame_count {
+            if cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+            let start = frame * HOP;
+            for (i, sample) in input.iter_mut().enumerate() {
+                *sample = source.get(start + i).copied().unwrap_or(0.0) * window[i];
+            }
+            let _ = forward.process_with_scratch(&mut input, &mut spectrum, &mut scratch);
+            magnitudes.extend(spectrum.iter().map(|bin| bin.norm()));
+        }
+        Some(Self {
+            magnitudes,
+            frame_count,
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    pub fn frame(&self, index: usize) -> &[f32] {
+        &self.magnitudes[index * BINS..(index + 1) * BINS]
+    }
+
+    /// Frame starting nearest to sample `position` of the source.
+    pub fn frame_at(&self, position: f32) -> usize {
+        let index = (position.max(0.0) / HOP as f32).round() as usize;
+        index.min(self.frame_count.saturating_sub(1))
+    }
+}
+
+/// User settings of the spectral mode. `spread_ms` is how far around the
+/// grain start each spectral grain may be picked from (shuffling), and
+/// `phase_randomness` (0..1) smears the resynthesis.
+pub struct SpectralSettings {
+    frozen: AtomicBool,
+    spread_ms: AtomicF32,
+    phase_randomness: AtomicF32,
+}
+
+impl SpectralSettings {
+    pub fn new() -> Self {
+        Self {
+            frozen: AtomicBool::new(false),
+            spread_ms: AtomicF32::new(0.0),
+            phase_randomness: AtomicF32::new(0.0),
+        }
+    }
+
+    /// Holds the current spectral grain until unfrozen.
+    pub fn set_frozen(&self, frozen: bool) {
+        self.frozen.store(frozen, Ordering::Relaxed);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Relaxed)
+    }
+
+    pub fn spread_ms(&self) -> f32 {
+        self.spread_ms.load()
+    }
+
+    pub fn set_spread_ms(&self, spread_ms: f32) {
+        self.spread_ms.store(spread_ms.clamp(0.0, 10_000.0));
+    }
+
+    pub fn phase_randomness(&self) -> f32 {
+        self.phase_randomness.load()
+    }
+
+    pub fn set_phase_randomness(&self, amount: f32) {
+        self.phase_randomness.store(amount.clamp(0.0, 1.0));
+    }
+}
+
+impl Default for SpectralSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Per-block controls of a `SpectralVoice`, read once from the shared
+/// settings and parameters.
+#[derive(Clone, Copy)]
+pub struct SpectralControl {
+    /// Grain start in source samples
+    pub position: f32,
+    pub spread_samples: f32,
+    /// Bin scaling factor, the grain pitch
+    pub pitch: f32,
+    pub frozen: bool,
+    pub phase_randomness: f32,
+    /// Source region the spread stays in
+    pub bounds: Option<SourceBounds>,
+}
+
+/// Resynthesis state owned by the audio thread. Every `HOP` samples it
+/// picks a spectral grain (an analysis frame), scales its bins by the pitch,
+/// gives every bin a running phase and overlap-adds the inverse FFT.
+/// Everything is allocated in `new`.
+pub struct SpectralVoice {
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    // The current spectral grain, unscaled, kept while frozen
+    grain: Vec<f32>,
+    has_grain: bool,
+    phases: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    frame: Vec<f32>,
+    scratch: Vec<Complex<f32>>,
+    // Overlap-add accumulator; the first `HOP` samples are ready to play
+    output: Vec<f32>,
+    read_position: usize,
+    // xorshift32, so the audio thread needs no thread-local generator
+    noise_state: u32,
+}
+
+impl SpectralVoice {
+    pub fn new() -> Self {
+        let inverse = RealFftPlanner::<f32>::new().plan_fft_inverse(FFT_SIZE);
+        let spectrum = inverse.make_input_vec();
+        let frame = inverse.make_output_vec();
+        let scratch = inverse.make_scratch_vec();
+        Self {
+            inverse,
+            window: hann_window(),
+            grain: vec![0.0; BINS],
+            has_grain: false,
+            phases: vec![0.0; BINS],
+            spectrum,
+            frame,
+            scratch,
+            output: vec![0.0; FFT_SIZE],
+            read_position: HOP,
+            noise_state: 0x9E37_79B9,
+        }
+    }
+
+    /// Uniform in -1..1.
+    fn random(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        x as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    pub fn next_sample(&mut self, frames: &SpectralFrames, control: &SpectralControl) -> f32 {
+        if self.read_position >= HOP {
+            self.synthesize_frame(frames, control);
+            self.read_position = 0;
+        }
+        let sample = self.output[self.read_position];
+        self.read_position += 1;
+        sample
+    }
+
+    fn synthesize_frame(&mut self, frames: &SpectralFrames, control: &SpectralControl) {
+        self.output.copy_within(HOP.., 0);
+        self.output[FFT_SIZE - HOP..].fill(0.0);
+
+        if !(control.frozen && self.has_grain) {
+            if frames.frame_count() == 0 {
+                self.has_grain = false;
+                return;
+            }
+            let offset = control.spread_samples * self.random();
+            let position = control.position + offset;
+            let position = control.bounds.map_or(position, |b| b.fold(position));
+            let index = frames.frame_at(position);
+            self.grain.copy_from_slice(frames.frame(index));
+            self.has_grain = true;
+        }
+
+        // Bin scaling: bin k moves to k * pitch
+        for bin in self.spectrum.iter_mut() {
+            *bin = Complex::new(0.0, 0.0);
+        }
+        for (k, &magnitude) in self.grain.iter().enumerate() {
+            let target = (k as f32 * control.pitch).round() as usize;
+            if target < BINS {
+                self.spectrum[target].re += magnitude;
+            }
+        }
+        // Each bin advances by its centre frequency over one hop, plus an
+        // optional random jitter
+        for k in 0..BINS {
+            let jitter = if control.phase_randomness > 0.0 {
+                control.phase_randomness * PI * self.random()
+            } else {
+                0.0
+            };
+            let advance = 2.0 * PI * (k * HOP) as f32 / FFT_SIZE as f32;
+            self.phases[k] = (self.phases[k] + advance + jitter) % (2.0 * PI);
+            self.spectrum[k] = Complex::from_polar(self.spectrum[k].re, self.phases[k]);
+        }
+        // DC and Nyquist must be real for the inverse real FFT
+        self.spectrum[0].im = 0.0;
+        self.spectrum[BINS - 1].im = 0.0;
+        let _ = self
+            .inverse
+            .process_with_scratch(&mut self.spectrum, &mut self.frame, &mut self.scratch);
+        for ((out, sample), window) in self.output.iter_mut().zip(&self.frame).zip(&self.window) {
+            *out += sample * window * OLA_SCALE;
+        }
+    }
+}
+
+impl Default for SpectralVoice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,379 @@
+use crate::{AudioEngine, GrainParams, GrainVoice, Specs};
+use realfft::RealFftPlanner;
+use std::{f32::consts::PI, fs::File, io::BufWriter, sync::Arc};
+
+// -------------------------------------
+// OFFLINE TIME-STRETCH
+// -------------------------------------
+/// Limits of the stretch factor (output length over input length).
+pub const MIN_STRETCH: f32 = 0.1;
+pub const MAX_STRETCH: f32 = 100.0;
+
+/// Settings of `time_stretch_file`. `stretch` holds breakpoints (position
+/// 0..1 in the input, stretch factor) interpolated linearly; a single point
+/// stretches the whole file evenly.
+#[derive(Clone, Debug)]
+pub struct StretchSettings {
+    pub stretch: Vec<(f32, f32)>,
+    /// Playback rate of the grains: 2.0 is an octave up at any stretch
+    pub pitch: f32,
+    /// Grain length in milliseconds; grains overlap by half
+    pub grain_ms: f32,
+    /// How far (ms) a grain may move from its nominal position to line up
+    /// with the previous one
+    pub tolerance_ms: f32,
+    /// 16, 24 or 32; `None` keeps the input's depth when possible
+    pub bit_depth: Option<u16>,
+}
+
+impl StretchSettings {
+    pub fn new(stretch: f32, pitch: f32) -> Self {
+        Self {
+            stretch: vec![(0.0, stretch)],
+            pitch,
+            grain_ms: 80.0,
+            tolerance_ms: 10.0,
+            bit_depth: None,
+        }
+    }
+
+    /// Stretch factor at `position` (0..1) of the input.
+    pub fn stretch_at(&self, position: f32) -> f32 {
+        let factor = match self.stretch.iter().position(|&(x, _)| x > position) {
+            _ if self.stretch.is_empty() => 1.0,
+            Some(0) => self.stretch[0].1,
+            None => self.stretch[self.stretch.len() - 1].1,
+            Some(i) => {
+                let ((x0, y0), (x1, y1)) = (self.stretch[i - 1], self.stretch[i]);
+                y0 + (y1 - y0) * (position - x0) / (x1 - x0).max(1e-6)
+            }
+        };
+        factor.clamp(MIN_STRETCH, MAX_STRETCH)
+    }
+}
+
+impl Default for StretchSettings {
+    fn default() -> Self {
+        Self::new(1.0, 1.0)
+    }
+}
+
+/// Stretches `input_path` into `output_path` (WAV) without changing its
+/// pitch, or shifts its pitch by `settings.pitch`. Returns the number of
+/// frames written.
+pub fn time_stretch_file(
+    input_path: &str,
+    output_path: &str,
+    settings: &StretchSettings,
+) -> Result<usize, String> {
+    let (channels, spec) = read_channels(input_path)?;
+    let output = time_stretch(&channels, spec.sample_rate, settings);
+
+    let bit_depth = settings.bit_depth.unwrap_or(match spec.bits_per_sample {
+        24 | 32 => spec.bits_per_sample,
+        _ => 16,
+    });
+    let output_spec = hound::WavSpec {
+        channels: spec.channels,
+        sample_rate: spec.sample_rate,
+        bits_per_sample: bit_depth,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let file = File::create(output_path).map_err(|e| e.to_string())?;
+    let mut wav_writer =
+        hound::WavWriter::new(BufWriter::new(file), output_spec).map_err(|e| e.to_string())?;
+    let frames = output.first().map_or(0, |channel| channel.len());
+    let interleaved: Vec<f32> = (0..frames)
+        .flat_map(|i| output.iter().map(move |channel| channel[i]))
+        .collect();
+    AudioEngine::write_wav_samples(&mut wav_writer, &interleaved, bit_depth)?;
+    wav_writer.finalize().map_err(|e| e.to_string())?;
+    Ok(frames)
+}
+
+/// Reads a WAV file into one buffer per channel.
+fn read_channels(path: &str) -> Result<(Vec<Vec<f32>>, hound::WavSpec), String> {
+    let mut reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().filter_map(|s| s.ok()).collect(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .filter_map(|s| s.ok())
+                .map(|s| s as f32 * scale)
+                .collect()
+        }
+    };
+    let count = spec.channels.max(1) as usize;
+    let channels = (0..count)
+        .map(|ch| samples.iter().skip(ch).step_by(count).copied().collect())
+        .collect();
+    Ok((channels, spec))
+}
+
+/// Linear read of `signal` at fractional position `x`, 0 outside.
+fn read_at(signal: &[f32], x: f32) -> f32 {
+    if x < 0.0 {
+        return 0.0;
+    }
+    let index = x as usize;
+    let frac = x - index as f32;
+    let s0 = signal.get(index).copied().unwrap_or(0.0);
+    let s1 = signal.get(index + 1).copied().unwrap_or(0.0);
+    s0 + (s1 - s0) * frac
+}
+
+/// WSOLA time-stretch of every channel, in memory. Grains are Hann-windowed
+/// `GrainVoice` grains read at `pitch`, laid down every half grain. Each
+/// one starts near its nominal input position, at the offset where it best
+/// continues the previous grain (cross-correlation on the channel mix), so
+/// overlapping grains add in phase.
+pub fn time_stretch(
+    channels: &[Vec<f32>],
+    sample_rate: u32,
+    settings: &StretchSettings,
+) -> Vec<Vec<f32>> {
+    let input_len = channels.first().map_or(0, |channel| channel.len());
+    if input_len == 0 {
+        return vec![Vec::new(); channels.len()];
+    }
+    let pitch = settings.pitch.clamp(0.1, 4.0);
+    let grain_length =
+        ((settings.grain_ms.max(5.0) / 1000.0 * sample_rate as f32) as usize).max(8) & !1;
+    let hop = grain_length / 2;
+    let tolerance = (settings.tolerance_ms.max(0.0) / 1000.0 * sample_rate as f32) as usize;
+
+    // The grain envelope, as `generate_grain_envelope` makes it
+    let envelope: Vec<f32> = (0..grain_length)
+        .map(|i| 0.5 + 0.5 * ((i as f32 / grain_length as f32 * 2.0 - 1.0) * PI).cos())
+        .collect();
+    let voice = GrainVoice::new(0.0, 1.0, 1.0);
+    let grain_params = GrainParams {
+        grain_start: 0.0,
+        grain_duration: 0,
+        grain_overlap: 2.0,
+        grain_pitch: pitch,
+        grain_amplitude: 1.0,
+        grain_pan: 0.0,
+        specs: Specs {
+            sample_rate,
+            channels: channels.len() as u16,
+            filesize: input_len,
+        },
+    };
+
+    let mono: Vec<f32> = (0..input_len)
+        .map(|i| channels.iter().map(|channel| channel[i]).sum::<f32>() / channels.len() as f32)
+        .collect();
+    let mut aligner = Aligner::new(hop, tolerance);
+
+    let mut output = vec![Vec::new(); channels.len()];
+    let mut nominal = 0.0f32;
+    let mut previous: Option<f32> = None;
+    let mut write_position = 0usize;
+    while nominal < input_len as f32 {
+        let start = match previous {
+            Some(previous) => {
+                let continuation = previous + hop as f32 * pitch;
+                aligner.best_start(&mono, continuation, nominal, pitch)
+            }
+            None => nominal,
+        };
+        let mut grain = voice.grain(&grain_params);
+        grain.source_start = start;
+        grain.length = grain_length;
+        for (channel, out) in channels.iter().zip(output.iter_mut()) {
+            out.resize(write_position + grain_length, 0.0);
+            for i in 0..grain_length {
+                out[write_position + i] += grain.sample_at(i, channel, None, &envelope);
+            }
+        }
+        previous = Some(start);
+        write_position += hop;
+        nominal += hop as f32 / settings.stretch_at(nominal / input_len as f32);
+    }
+    output
+}
+
+/// Finds grain offsets by FFT cross-correlation; buffers are reused from
+/// grain to grain.
+struct Aligner {
+    overlap: usize,
+    tolerance: usize,
+    forward: Arc<dyn realfft::RealToComplex<f32>>,
+    inverse: Arc<dyn realfft::ComplexToReal<f32>>,
+    template: Vec<f32>,
+    region: Vec<f32>,
+    region_copy: Vec<f32>,
+    template_spectrum: Vec<realfft::num_complex::Complex<f32>>,
+    region_spectrum: Vec<realfft::num_complex::Complex<f32>>,
+    correlation: Vec<f32>,
+}
+
+impl Aligner {
+    fn new(overlap: usize, tolerance: usize) -> Self {
+        let size = (overlap + 2 * tolerance + 1).next_power_of_two();
+        let mut planner = RealFftPlanner::<f32>::new();
+        let forward = planner.plan_fft_forward(size);
+        let inverse = planner.plan_fft_inverse(size);
+        Self {
+            overlap,
+            tolerance,
+            template: forward.make_input_vec(),
+            region: forward.make_input_vec(),
+            region_copy: forward.make_input_vec(),
+            template_spectrum: forward.make_output_vec(),
+            region_spectrum: forward.make_output_vec(),
+            correlation: inverse.make_output_vec(),
+            forward,
+            inverse,
+        }
+    }
+
+    /// Start within `tolerance` of `nominal` whose first `overlap` samples
+    /// (read at `pitch`) best match the signal read from `continuation`.
+    fn best_start(&mut self, signal: &[f32], continuation: f32, nominal: f32, pitch: f32) -> f32 {
+        if self.tolerance == 0 {
+            return nominal;
+        }
+        let (overlap, span) = (self.overlap, self.overlap + 2 * self.tolerance);
+        let region_start = nominal - self.tolerance as f32 * pitch;
+        self.template.fill(0.0);
+        self.region.fill(0.0);
+        for (i, t) in self.template.iter_mut().take(overlap).enumerate() {
+            *t = read_at(signal, continuation + i as f32 * pitch);
+        }
+        for (i, r) in self.region.iter_mut().take(span).enumerate() {
+            *r = read_at(signal, region_start + i as f32 * pitch);
+        }
+        self.region_copy.copy_from_slice(&self.region);
+        let _ = self.forward.process(&mut self.template, &mut self.template_spectrum);
+        let _ = self.forward.process(&mut self.region_copy, &mut self.region_spectrum);
+        // corr(shift) = sum template[i] * region[i + shift]
+        for (t, r) in self.template_spectrum.iter_mut().zip(&self.region_spectrum) {
+            *t = t.conj() * r;
+        }
+        self.template_spectrum[0].im = 0.0;
+        if let Some(last) = self.template_spectrum.last_mut() {
+            last.im = 0.0;
+        }
+        let _ = self.inverse.process(&mut self.template_spectrum, &mut self.correlation);
+
+        // Normalize by the energy of each candidate so loud passages do not
+        // win by level alone
+        let mut energy: f32 = self.region[..overlap].iter().map(|s| s * s).sum();
+        let (mut best_shift, mut best_score) = (self.tolerance, f32::MIN);
+        for shift in 0..=2 * self.tolerance {
+            if shift > 0 {
+                let (out, new) = (self.region[shift - 1], self.region[shift + overlap - 1]);
+                energy = (energy + new * new - out * out).max(0.0);
+            }
+            let score = self.correlation[shift] / (energy + 1e-9).sqrt();
+            if score > best_score && region_start + shift as f32 * pitch >= 0.0 {
+                best_score = score;
+                best_shift = shift;
+            }
+        }
+        (region_start + best_shift as f32 * pitch).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn sine(freq: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| 0.5 * (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    // Positive-going zero crossings per second, away from the edges
+    fn frequency(signal: &[f32]) -> f32 {
+        let middle = &signal[signal.len() / 4..signal.len() * 3 / 4];
+        let crossings = middle
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        crossings as f32 * SAMPLE_RATE as f32 / middle.len() as f32
+    }
+
+    #[test]
+    fn stretch_breakpoints() {
+        let curve = StretchSettings {
+            stretch: vec![(0.0, 1.0), (0.5, 3.0), (1.0, 1.0)],
+            ..StretchSettings::default()
+        };
+        let empty = StretchSettings {
+            stretch: Vec::new(),
+            ..StretchSettings::default()
+        };
+        // (settings, position, expected factor)
+        let cases = [
+            (StretchSettings::new(2.0, 1.0), 0.0, 2.0),
+            (StretchSettings::new(2.0, 1.0), 0.7, 2.0),
+            (curve.clone(), 0.0, 1.0),
+            (curve.clone(), 0.25, 2.0),
+            (curve.clone(), 0.5, 3.0),
+            (curve.clone(), 0.75, 2.0),
+            (curve, 1.0, 1.0),
+            (empty, 0.5, 1.0),
+            (StretchSettings::new(0.01, 1.0), 0.5, MIN_STRETCH),
+            (StretchSettings::new(500.0, 1.0), 0.5, MAX_STRETCH),
+        ];
+        for (settings, position, expected) in cases {
+            let factor = settings.stretch_at(position);
+            assert!(
+                (factor - expected).abs() < 1e-5,
+                "{:?} at {position}: {factor}",
+                settings.stretch
+            );
+        }
+    }
+
+    #[test]
+    fn output_length_follows_stretch() {
+        let input = vec![sine(220.0, SAMPLE_RATE as usize); 2];
+        let grain_length = (0.08 * SAMPLE_RATE as f32) as usize;
+        for stretch in [0.5, 1.0, 2.0, 4.0] {
+            let output = time_stretch(&input, SAMPLE_RATE, &StretchSettings::new(stretch, 1.0));
+            assert_eq!(output.len(), 2);
+            assert_eq!(output[0].len(), output[1].len());
+            let expected = input[0].len() as f32 * stretch;
+            assert!(
+                (output[0].len() as f32 - expected).abs() <= grain_length as f32,
+                "stretch {stretch}: {} frames, expected {expected}",
+                output[0].len()
+            );
+        }
+        // A curve averaging 2 over the input
+        let curve = StretchSettings {
+            stretch: vec![(0.0, 1.0), (1.0, 3.0)],
+            ..StretchSettings::default()
+        };
+        let output = time_stretch(&input, SAMPLE_RATE, &curve);
+        let expected = input[0].len() as f32 * 2.0;
+        assert!((output[0].len() as f32 - expected).abs() <= (grain_length * 2) as f32);
+        assert!(time_stretch(&[Vec::new()], SAMPLE_RATE, &curve)[0].is_empty());
+    }
+
+    #[test]
+    fn pitch_is_independent_of_stretch() {
+        let input = [sine(220.0, SAMPLE_RATE as usize)];
+        let reference = frequency(&input[0]);
+        // (stretch, pitch, expected frequency ratio)
+        let cases = [(2.0, 1.0, 1.0), (0.5, 1.0, 1.0), (1.0, 2.0, 2.0), (3.0, 0.5, 0.5)];
+        for (stretch, pitch, ratio) in cases {
+            let output = time_stretch(&input, SAMPLE_RATE, &StretchSettings::new(stretch, pitch));
+            let measured = frequency(&output[0]) / reference;
+            assert!(
+                (measured - ratio).abs() < 0.03 * ratio,
+                "stretch {stretch} pitch {pitch}: ratio {measured}"
+            );
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use crate::AtomicF32;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+// -------------------------------------
+// TEMPO AND TRANSPORT
+// -------------------------------------
+/// MIDI clock resolution, pulses per quarter note.
+pub const CLOCK_PPQN: f64 = 24.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TempoSync {
+    /// Grain rate follows `grain_duration / grain_overlap`, as before
+    Off,
+    /// Note divisions at the internal BPM
+    Internal,
+    /// Note divisions locked to incoming MIDI clock
+    MidiClock,
+}
+
+impl TempoSync {
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(TempoSync::Off),
+            1 => Some(TempoSync::Internal),
+            2 => Some(TempoSync::MidiClock),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            TempoSync::Off => 0,
+            TempoSync::Internal => 1,
+            TempoSync::MidiClock => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DivisionModifier {
+    Straight,
+    Dotted,
+    Triplet,
+}
+
+impl DivisionModifier {
+    pub fn from_index(index: u8) -> Self {
+        match index {
+            1 => DivisionModifier::Dotted,
+            2 => DivisionModifier::Triplet,
+            _ => DivisionModifier::Straight,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            DivisionModifier::Straight => 0,
+            DivisionModifier::Dotted => 1,
+            DivisionModifier::Triplet => 2,
+        }
+    }
+}
+
+/// Length of one step in quarter notes: 1/`denominator` of a whole note,
+/// times 1.5 when dotted or 2/3 for triplets.
+pub fn division_beats(denominator: u32, modifier: DivisionModifier) -> f64 {
+    let beats = 4.0 / denominator.max(1) as f64;
+    match modifier {
+        DivisionModifier::Straight => beats,
+        DivisionModifier::Dotted => beats * 1.5,
+        DivisionModifier::Triplet => beats * 2.0 / 3.0,
+    }
+}
+
+/// Whether `step` of a Euclidean pattern (`pulses` hits spread as evenly as
+/// possible over `steps`, shifted by `rotation`) is a hit. Zero steps means
+/// no pattern: every step plays.
+pub fn euclidean_hit(step: u64, steps: u32, pulses: u32, rotation: u32) -> bool {
+    if steps == 0 {
+        return true;
+    }
+    let steps = steps as u64;
+    let pulses = pulses.min(steps as u32) as u64;
+    let i = (step + rotation as u64) % steps;
+    (i * pulses) % steps < pulses
+}
+
+/// State of the incoming MIDI clock; only touched by the MIDI thread.
+struct ClockState {
+    last_tick: Option<Instant>,
+    // Smoothed time between ticks, in seconds
+    tick_seconds: f64,
+}
+
+/// Tempo settings plus the MIDI clock position, shared between the control
+/// side, the MIDI input thread and the scheduler.
+pub struct Transport {
+    sync: AtomicU8,
+    bpm: AtomicF32,
+    division: AtomicU32,
+    modifier: AtomicU8,
+    swing: AtomicF32,
+    euclid_steps: AtomicU32,
+    euclid_pulses: AtomicU32,
+    euclid_rotation: AtomicU32,
+    // MIDI clock: running flag, ticks since the last start, tempo estimate
+    running: AtomicBool,
+    ticks: AtomicU32,
+    // Bumped on every MIDI start so the scheduler restarts its pattern
+    starts: AtomicU32,
+    clock_bpm: AtomicF32,
+    clock: Mutex<ClockState>,
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Self {
+            sync: AtomicU8::new(TempoSync::Off.index()),
+            bpm: AtomicF32::new(120.0),
+            division: AtomicU32::new(16),
+            modifier: AtomicU8::new(DivisionModifier::Straight.index()),
+            swing: AtomicF32::new(0.0),
+            euclid_steps: AtomicU32::new(0),
+            euclid_pulses: AtomicU32::new(0),
+            euclid_rotation: AtomicU32::new(0),
+            running: AtomicBool::new(false),
+            ticks: AtomicU32::new(0),
+            starts: AtomicU32::new(0),
+            clock_bpm: AtomicF32::new(120.0),
+            clock: Mutex::new(ClockState {
+                last_tick: None,
+                tick_seconds: 60.0 / (120.0 * CLOCK_PPQN),
+            }),
+        }
+    }
+
+    pub fn sync(&self) -> TempoSync {
+        TempoSync::from_index(self.sync.load(Ordering::Relaxed)).unwrap_or(TempoSync::Off)
+    }
+
+    pub fn set_sync(&self, sync: TempoSync) {
+        self.sync.store(sync.index(), Ordering::Relaxed);
+    }
+
+    /// The tempo in use: the internal BPM, or the one measured from MIDI
+    /// clock when slaved.
+    pub fn bpm(&self) -> f32 {
+        match self.sync() {
+            TempoSync::MidiClock => self.clock_bpm.load(),
+            _ => self.bpm.load(),
+        }
+    }
+
+    pub fn set_bpm(&self, bpm: f32) {
+        self.bpm.store(bpm.clamp(20.0, 300.0));
+    }
+
+    pub fn division(&self) -> (u32, DivisionModifier) {
+        (
+            self.division.load(Ordering::Relaxed),
+            DivisionModifier::from_index(self.modifier.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// `denominator` is the note value: 4 for quarter notes, 16 for
+    /// sixteenths, and so on.
+    pub fn set_division(&self, denominator: u32, modifier: DivisionModifier) {
+        self.division.store(denominator.clamp(1, 128), Ordering::Relaxed);
+        self.modifier.store(modifier.index(), Ordering::Relaxed);
+    }
+
+    pub fn step_beats(&self) -> f64 {
+        let (denominator, modifier) = self.division();
+        division_beats(denominator, modifier)
+    }
+
+    /// Length of one step in milliseconds at the current tempo.
+    pub fn step_ms(&self) -> f32 {
+        (self.step_beats() * 60_000.0 / self.bpm() as f64) as f32
+    }
+
+    pub fn swing(&self) -> f32 {
+        self.swing.load()
+    }
+
+    /// 0 is straight; every second step is pushed back by `swing` times half
+    /// a step (2/3 gives a triplet shuffle).
+    pub fn set_swing(&self, swing: f32) {
+        self.swing.store(swing.clamp(0.0, 1.0));
+    }
+
+    /// `steps == 0` turns the pattern off.
+    pub fn set_euclidean(&self, steps: u32, pulses: u32, rotation: u32) {
+        self.euclid_steps.store(steps, Ordering::Relaxed);
+        self.euclid_pulses.store(pulses.min(steps), Ordering::Relaxed);
+        self.euclid_rotation.store(rotation, Ordering::Relaxed);
+    }
+
+    /// Share of steps the Euclidean pattern plays, 1.0 without a pattern.
+    pub fn hit_ratio(&self) -> f32 {
+        let steps = self.euclid_steps.load(Ordering::Relaxed);
+        if steps == 0 {
+            return 1.0;
+        }
+        self.euclid_pulses.load(Ordering::Relaxed) as f32 / steps as f32
+    }
+
+    pub fn is_hit(&self, step: u64) -> bool {
+        euclidean_hit(
+            step,
+            self.euclid_steps.load(Ordering::Relaxed),
+            self.euclid_pulses.load(Ordering::Relaxed),
+            self.euclid_rotation.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    pub fn starts(&self) -> u32 {
+        self.starts.load(Ordering::Acquire)
+    }
+
+    // ----------------------
+    // MIDI CLOCK
+    // ----------------------
+    pub fn clock_tick(&self) {
+        let now = Instant::now();
+        let mut clock = self.clock.lock().unwrap();
+        if let Some(last) = clock.last_tick {
+            let dt = (now - last).as_secs_f64();
+            // Gaps longer than a 10 BPM tick are a paused clock, not a tempo
+            if dt < 60.0 / (10.0 * CLOCK_PPQN) {
+                clock.tick_seconds += 0.1 * (dt - clock.tick_seconds);
+                self.clock_bpm
+                    .store((60.0 / (clock.tick_seconds * CLOCK_PPQN)) as f32);
+            }
+        }
+        clock.last_tick = Some(now);
+        if self.is_running() {
+            self.ticks.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    pub fn clock_start(&self) {
+        self.ticks.store(0, Ordering::Release);
+        self.starts.fetch_add(1, Ordering::Release);
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    pub fn clock_continue(&self) {
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    pub fn clock_stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    /// Position in quarter notes since the last MIDI start.
+    pub fn clock_position(&self) -> f64 {
+        self.ticks.load(Ordering::Acquire) as f64 / CLOCK_PPQN
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Scheduler-side step sequencer. Turns the transport position into step
+/// onsets, applying swing and the Euclidean pattern.
+pub struct StepClock {
+    // Position in quarter notes
+    position: f64,
+    step: u64,
+    last_time: Instant,
+    starts: u32,
+}
+
+impl StepClock {
+    pub fn new(transport: &Transport) -> Self {
+        Self {
+            position: 0.0,
+            step: 0,
+            last_time: Instant::now(),
+            starts: transport.starts(),
+        }
+    }
+
+    /// Restarts the pattern from step 0 at the current time.
+    pub fn reset(&mut self) {
+        self.position = 0.0;
+        self.step = 0;
+        self.last_time = Instant::now();
+    }
+
+    /// Call regularly. Returns `Some(hit)` when a step starts, where `hit`
+    /// tells whether the pattern plays on it.
+    pub fn poll(&mut self, transport: &Transport, now: Instant) -> Option<bool> {
+        let elapsed = (now - self.last_time).as_secs_f64();
+        self.last_time = now;
+        match transport.sync() {
+            TempoSync::MidiClock => {
+                let starts = transport.starts();
+                if starts != self.starts {
+                    self.starts = starts;
+                    self.step = 0;
+                }
+                if !transport.is_running() {
+                    return None;
+                }
+                self.position = transport.clock_position();
+            }
+            _ => self.position += elapsed * transport.bpm() as f64 / 60.0,
+        }
+
+        let step_beats = transport.step_beats();
+        // After a tempo or division change, pick up from the current step
+        // instead of firing every missed one.
+        let current = (self.position / step_beats).floor() as u64;
+        if self.step > current + 1 || self.step + 1 < current {
+            self.step = current;
+        }
+
+        let swing = if self.step % 2 == 1 {
+            transport.swing() as f64 * step_beats * 0.5
+        } else {
+            0.0
+        };
+        if self.position < self.step as f64 * step_beats + swing {
+            return None;
+        }
+        let hit = transport.is_hit(self.step);
+        self.step += 1;
+        Some(hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn division_lengths() {
+        let cases = [
+            (4, DivisionModifier::Straight, 1.0),
+            (16, DivisionModifier::Straight, 0.25),
+            (8, DivisionModifier::Dotted, 0.75),
+            (8, DivisionModifier::Triplet, 1.0 / 3.0),
+            (1, DivisionModifier::Straight, 4.0),
+            (0, DivisionModifier::Straight, 4.0),
+        ];
+        for (denominator, modifier, beats) in cases {
+            let result = division_beats(denominator, modifier);
+            assert!((result - beats).abs() < 1e-9, "1/{denominator} {modifier:?} is {result}");
+        }
+    }
+
+    #[test]
+    fn euclidean_patterns() {
+        // (steps, pulses, rotation, hits over one cycle)
+        let cases: [(u32, u32, u32, &[u64]); 6] = [
+            (8, 3, 0, &[0, 3, 6]),
+            (8, 3, 1, &[2, 5, 7]),
+            (16, 4, 0, &[0, 4, 8, 12]),
+            (5, 2, 0, &[0, 3]),
+            (4, 0, 0, &[]),
+            (4, 9, 0, &[0, 1, 2, 3]),
+        ];
+        for (steps, pulses, rotation, hits) in cases {
+            let pattern: Vec<u64> = (0..steps as u64)
+                .filter(|&step| euclidean_hit(step, steps, pulses, rotation))
+                .collect();
+            assert_eq!(pattern, hits, "E({pulses}, {steps}) rotated by {rotation}");
+            // The pattern repeats every cycle
+            for step in 0..steps as u64 {
+                assert_eq!(
+                    euclidean_hit(step, steps, pulses, rotation),
+                    euclidean_hit(step + steps as u64, steps, pulses, rotation)
+                );
+            }
+        }
+        assert!((0..8).all(|step| euclidean_hit(step, 0, 3, 0)));
+    }
+
+    // Polls every 5 ms for `seconds` and returns the onset times and hits
+    fn run(transport: &Transport, seconds: f64) -> Vec<(f64, bool)> {
+        let mut clock = StepClock::new(transport);
+        let start = clock.last_time;
+        let mut onsets = Vec::new();
+        for tick in 0..=(seconds * 200.0) as u64 {
+            let time = tick as f64 * 0.005;
+            if let Some(hit) = clock.poll(transport, start + Duration::from_secs_f64(time)) {
+                onsets.push((time, hit));
+            }
+        }
+        onsets
+    }
+
+    #[test]
+    fn step_clock_follows_tempo_and_pattern() {
+        let transport = Transport::new();
+        transport.set_sync(TempoSync::Internal);
+        transport.set_bpm(120.0);
+        transport.set_division(4, DivisionModifier::Straight);
+        transport.set_euclidean(8, 3, 0);
+
+        let onsets = run(&transport, 3.9);
+        assert_eq!(onsets.len(), 8);
+        for (step, &(time, hit)) in onsets.iter().enumerate() {
+            assert!((time - step as f64 * 0.5).abs() < 0.011, "step {step} at {time}");
+            assert_eq!(hit, [0, 3, 6].contains(&step), "step {step}");
+        }
+    }
+
+    #[test]
+    fn step_clock_swings_odd_steps() {
+        let transport = Transport::new();
+        transport.set_sync(TempoSync::Internal);
+        transport.set_bpm(120.0);
+        transport.set_division(4, DivisionModifier::Straight);
+        transport.set_swing(0.5);
+
+        let onsets = run(&transport, 1.9);
+        let times: Vec<f64> = onsets.iter().map(|&(time, _)| time).collect();
+        for (time, expected) in times.iter().zip([0.0, 0.625, 1.0, 1.625]) {
+            assert!((time - expected).abs() < 0.011, "{times:?}");
+        }
+        assert_eq!(times.len(), 4);
+    }
+
+    #[test]
+    fn step_clock_follows_midi_clock() {
+        let transport = Transport::new();
+        transport.set_sync(TempoSync::MidiClock);
+        transport.set_division(16, DivisionModifier::Straight);
+        let mut clock = StepClock::new(&transport);
+
+        // Stopped: ticks do not advance the pattern
+        transport.clock_tick();
+        assert_eq!(clock.poll(&transport, Instant::now()), None);
+
+        transport.clock_start();
+        assert_eq!(clock.poll(&transport, Instant::now()), Some(true));
+        let mut steps = 0;
+        for _ in 0..CLOCK_PPQN as usize * 2 {
+            transport.clock_tick();
+            if clock.poll(&transport, Instant::now()).is_some() {
+                steps += 1;
+            }
+        }
+        // Two quarter notes of sixteenths, the first already played
+        assert_eq!(steps, 8);
+    }
+}